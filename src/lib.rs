@@ -1,9 +1,16 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use image::imageops::FilterType;
-use image::{DynamicImage, ImageFormat};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
-use std::io::Cursor;
+use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::WalkDir;
 
 /// Supported compression output formats
@@ -16,6 +23,8 @@ pub enum OutputFormat {
 }
 
 impl OutputFormat {
+    pub const ALL: [Self; 4] = [Self::Jpeg, Self::Png, Self::WebP, Self::Avif];
+
     pub fn from_extension(extension: &str) -> Result<Self> {
         match normalize_extension(extension)?.as_str() {
             "jpg" | "jpeg" => Ok(Self::Jpeg),
@@ -27,13 +36,504 @@ impl OutputFormat {
     }
 }
 
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+const DEFAULT_WEBP_QUALITY: u8 = 85;
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+const DEFAULT_AVIF_SPEED: u8 = 4;
+
+impl OutputFormat {
+    fn from_image_format(format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::Jpeg => Some(Self::Jpeg),
+            ImageFormat::Png => Some(Self::Png),
+            ImageFormat::WebP => Some(Self::WebP),
+            ImageFormat::Avif => Some(Self::Avif),
+            _ => None,
+        }
+    }
+
+    /// Preferred file extension, the inverse of [`OutputFormat::from_extension`]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    /// Quality the encoder uses when `CompressOptions::quality` is unset;
+    /// `None` for PNG, which is lossless
+    pub fn default_quality(self) -> Option<u8> {
+        match self {
+            Self::Jpeg => Some(DEFAULT_JPEG_QUALITY),
+            Self::Png => None,
+            Self::WebP => Some(DEFAULT_WEBP_QUALITY),
+            Self::Avif => Some(DEFAULT_AVIF_QUALITY),
+        }
+    }
+
+    /// IANA media type, e.g. for a `Content-Type` header
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        })
+    }
+}
+
+/// Formats this build can read and write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupportedFormats {
+    /// Decodable input formats, by primary file extension
+    pub input: Vec<&'static str>,
+    pub output: Vec<OutputFormat>,
+}
+
+/// Query the formats compiled into this build at runtime
+pub fn supported_formats() -> SupportedFormats {
+    SupportedFormats {
+        input: ImageFormat::all()
+            .filter(ImageFormat::reading_enabled)
+            .filter_map(|format| format.extensions_str().first().copied())
+            .collect(),
+        output: OutputFormat::ALL.to_vec(),
+    }
+}
+
+/// JSON Schema (draft 2020-12) of every [`CompressOptions`] field, with the
+/// ranges and choices [`CompressOptionsBuilder::build`] accepts. Values use the
+/// CLI spellings: kebab-case enum names, `#RRGGBB` colors, objects for resize,
+/// crop, trim and watermark.
+pub fn compress_options_schema() -> Value {
+    let color = json!({ "type": "string", "pattern": "^#[0-9A-Fa-f]{6}$" });
+    let percent = json!({ "type": "integer", "minimum": 0, "maximum": 100 });
+    let quality_defaults: Vec<String> = OutputFormat::ALL
+        .into_iter()
+        .filter_map(|format| Some(format!("{format} {}", format.default_quality()?)))
+        .collect();
+
+    // Split across several `json!` calls to stay under the macro recursion limit
+    let mut properties = serde_json::Map::new();
+    for group in [
+        json!({
+            "overwrite": {
+                "type": "boolean",
+                "default": false,
+                "description": "Replace an existing output file"
+            },
+            "quality": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 100,
+                "description": format!(
+                    "Compression quality 1-100 (default: format-specific \u{2014} {})",
+                    quality_defaults.join(", ")
+                )
+            },
+            "lossless": {
+                "type": "boolean",
+                "default": false,
+                "description": "Use lossless compression (WebP and AVIF only)"
+            },
+            "progressive": {
+                "type": "boolean",
+                "default": false,
+                "description": "Progressive JPEG"
+            },
+            "strip_metadata": {
+                "type": "boolean",
+                "default": true,
+                "description": "Drop EXIF and XMP from the output"
+            },
+            "resize": {
+                "type": "object",
+                "required": ["width", "height"],
+                "additionalProperties": false,
+                "properties": {
+                    "width": { "type": "integer", "minimum": 1 },
+                    "height": { "type": "integer", "minimum": 1 },
+                    "mode": {
+                        "enum": ["fit", "exact", "fit-width", "fit-height", "pad"],
+                        "default": "fit"
+                    }
+                },
+                "description": "Target size; fit-width and fit-height use only one axis"
+            },
+            "png_level": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 6,
+                "description": "oxipng optimization level"
+            },
+            "avif_speed": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 10,
+                "default": DEFAULT_AVIF_SPEED,
+                "description": "AVIF encoding speed (1 = slowest, best)"
+            },
+            "avif_premultiplied_alpha": {
+                "type": "boolean",
+                "default": false,
+                "description": "Store AVIF color channels premultiplied by alpha"
+            },
+            "webp_exact": {
+                "type": "boolean",
+                "default": false,
+                "description": "Preserve RGB values under fully transparent WebP pixels"
+            },
+            "webp_sharp_yuv": {
+                "type": "boolean",
+                "default": false,
+                "description": "Sharper (slower) RGB to YUV conversion for lossy WebP"
+            },
+            "webp_alpha_filter": {
+                "enum": ["none", "fast", "best"],
+                "description": "Lossy WebP alpha plane filtering (libwebp default: fast)"
+            }
+        }),
+        json!({
+            "fast_downscale": {
+                "type": "boolean",
+                "default": false,
+                "description": "Box-filter reductions over 4x before the final Lanczos pass"
+            },
+            "atomic_write": {
+                "type": "boolean",
+                "default": true,
+                "description": "Write via a temp file renamed into place"
+            },
+            "png_optimize_alpha": {
+                "type": "boolean",
+                "default": false,
+                "description": "Let oxipng rewrite the color of fully transparent pixels"
+            },
+            "jpeg_trellis": {
+                "type": "boolean",
+                "description": "JPEG trellis quantization (default: on)"
+            },
+            "jpeg_optimize_coding": {
+                "type": "boolean",
+                "description": "JPEG optimized Huffman coding (default: profile's choice)"
+            },
+            "background": {
+                "oneOf": [
+                    color,
+                    {
+                        "type": "object",
+                        "required": ["top", "bottom"],
+                        "additionalProperties": false,
+                        "properties": { "top": color, "bottom": color }
+                    }
+                ],
+                "description": "Solid color or vertical gradient behind transparency in JPEG output and pad margins"
+            },
+            "deterministic": {
+                "type": "boolean",
+                "default": false,
+                "description": "Bit-reproducible output across machines"
+            },
+            "min_psnr": {
+                "type": "number",
+                "exclusiveMinimum": 0,
+                "description": "Flag lossy JPEG/WebP output below this PSNR (dB)"
+            },
+            "quality_guard_strict": {
+                "type": "boolean",
+                "default": false,
+                "description": "Fail files below min_psnr instead of writing them (needs min_psnr)"
+            },
+            "encoder_opts": {
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+                "description": "Raw key=value encoder knobs, applied best-effort"
+            },
+            "avif_tiles": {
+                "oneOf": [
+                    { "const": "auto" },
+                    {
+                        "type": "array",
+                        "prefixItems": [
+                            { "type": "integer", "minimum": 1, "maximum": 64 },
+                            { "type": "integer", "minimum": 1, "maximum": 64 }
+                        ],
+                        "minItems": 2,
+                        "maxItems": 2
+                    }
+                ],
+                "description": "AVIF [rows, cols] tiles (at most 64) or \"auto\" by image size; also the encoder thread count"
+            },
+            "auto_lossless": {
+                "type": "boolean",
+                "default": false,
+                "description": "WebP: lossless for graphics, lossy for photos"
+            }
+        }),
+        json!({
+            "trim": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "color": color,
+                    "tolerance": { "type": "integer", "minimum": 0, "maximum": 255, "default": 10 }
+                },
+                "description": "Crop uniform borders (color from the corners unless given)"
+            },
+            "watermark": {
+                "type": "object",
+                "required": ["path"],
+                "additionalProperties": false,
+                "properties": {
+                    "path": { "type": "string" },
+                    "position": {
+                        "enum": ["top-left", "top-right", "bottom-left", "bottom-right", "center"],
+                        "default": "bottom-right"
+                    },
+                    "opacity": { "type": "number", "minimum": 0, "maximum": 1, "default": 1.0 },
+                    "margin": { "type": "integer", "minimum": 0, "default": 16 }
+                },
+                "description": "Overlay image stamped onto every output after resizing"
+            },
+            "png_filter": {
+                "enum": ["none", "sub", "up", "average", "paeth", "try-all"],
+                "description": "PNG row filter strategy (default: png_level's choice)"
+            },
+            "compare_original": {
+                "type": "number",
+                "minimum": 0,
+                "maximum": 1,
+                "description": "Keep the original unless the output is smaller and at least this SSIM"
+            },
+            "strip_exif_thumbnail": {
+                "type": "boolean",
+                "default": false,
+                "description": "With metadata kept, drop the EXIF thumbnail and MakerNote"
+            },
+            "quantize_colors": {
+                "type": "integer",
+                "minimum": 2,
+                "maximum": 256,
+                "description": "Quantize PNG output to at most this many colors"
+            },
+            "png_lossy_quality": {
+                "type": "array",
+                "prefixItems": [percent, percent],
+                "minItems": 2,
+                "maxItems": 2,
+                "description": "pngquant-style [min, max] quality for lossy PNG"
+            },
+            "dither": {
+                "type": "boolean",
+                "default": true,
+                "description": "Dither when the PNG palette is reduced"
+            },
+            "assume_srgb": {
+                "type": "boolean",
+                "default": false,
+                "description": "Tag outputs of untagged sources as sRGB"
+            },
+            "io_retries": {
+                "type": "integer",
+                "minimum": 0,
+                "default": 0,
+                "description": "Retries for transient IO errors"
+            },
+            "resize_algorithm": {
+                "enum": ["resize", "thumbnail", "auto"],
+                "default": "resize",
+                "description": "Lanczos, faster lower-quality box sampling, or area averaging for large reductions and Lanczos otherwise"
+            },
+            "output_bit_depth": {
+                "enum": [8, 10],
+                "description": "Bits per channel for WebP/AVIF output (WebP 8 only, AVIF 8 or 10)"
+            }
+        }),
+        json!({
+            "temp_dir": {
+                "type": "string",
+                "description": "Directory for atomic-write temp files (default: next to the output)"
+            },
+            "xmp_sidecar": {
+                "type": "boolean",
+                "default": false,
+                "description": "Embed the input's XMP sidecar in JPEG and WebP output"
+            },
+            "apng": {
+                "type": "boolean",
+                "default": false,
+                "description": "Write animated sources as APNG (PNG output)"
+            },
+            "max_dimension": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Scale down so neither side is longer than this"
+            },
+            "avif_preset": {
+                "enum": ["fast", "balanced", "max"],
+                "description": "AVIF speed and quality together"
+            },
+            "mark_output": {
+                "type": "boolean",
+                "default": false,
+                "description": "Embed a marker naming this tool, the format and the quality"
+            },
+            "max_aspect_ratio": {
+                "type": "number",
+                "minimum": 1,
+                "description": "Longest-to-shortest side ratio past which aspect_ratio_action applies"
+            },
+            "aspect_ratio_action": {
+                "enum": ["warn", "crop"],
+                "default": "warn",
+                "description": "What happens past max_aspect_ratio"
+            },
+            "adaptive_quality": {
+                "type": "array",
+                "prefixItems": [
+                    { "type": "integer", "minimum": 1, "maximum": 100 },
+                    { "type": "integer", "minimum": 1, "maximum": 100 }
+                ],
+                "minItems": 2,
+                "maxItems": 2,
+                "description": "[min, max] band each image's quality is picked from by its detail"
+            },
+            "two_pass": {
+                "type": "boolean",
+                "default": false,
+                "description": "Two analysis passes for lossy WebP"
+            },
+            "crop": {
+                "type": "object",
+                "required": ["x", "y", "width", "height"],
+                "additionalProperties": false,
+                "properties": {
+                    "x": { "type": "integer", "minimum": 0 },
+                    "y": { "type": "integer", "minimum": 0 },
+                    "width": { "type": "integer", "minimum": 1 },
+                    "height": { "type": "integer", "minimum": 1 }
+                },
+                "description": "Region of the source to keep, cut before any resize"
+            }
+        }),
+    ] {
+        if let Value::Object(group) = group {
+            properties.extend(group);
+        }
+    }
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "CompressOptions",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": properties
+    })
+}
+
 /// How to resize
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResizeMode {
     Fit,
     Exact,
+    /// Scale to the given width; height follows the aspect ratio
+    FitWidth,
+    /// Scale to the given height; width follows the aspect ratio
+    FitHeight,
+    /// Scale to fit inside the box like `Fit`, then center on a canvas of
+    /// exactly the given size. The padding is `background` if set (which then
+    /// also flattens transparency), otherwise transparent.
+    Pad,
+}
+
+/// Coherent AVIF speed/quality pairs for `avif_preset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvifPreset {
+    /// Speed 8, quality 70: quick drafts and previews
+    Fast,
+    /// Speed 4, quality 80: the same as no preset
+    Balanced,
+    /// Speed 2, quality 82: smallest files at high quality, several times slower
+    Max,
+}
+
+impl AvifPreset {
+    /// `(speed, quality)` this preset encodes with
+    pub fn speed_and_quality(self) -> (u8, u8) {
+        match self {
+            Self::Fast => (8, 70),
+            Self::Balanced => (DEFAULT_AVIF_SPEED, DEFAULT_AVIF_QUALITY),
+            Self::Max => (2, 82),
+        }
+    }
+}
+
+/// AVIF tile layout for `avif_tiles`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvifTiles {
+    /// `rows` x `cols` tiles, at most 64 in all
+    Grid { rows: u8, cols: u8 },
+    /// One tile per [`AVIF_AUTO_TILE_PIXELS`] of image area, up to 64
+    Auto,
 }
 
+/// Image area each tile covers with [`AvifTiles::Auto`] (2 megapixels)
+pub const AVIF_AUTO_TILE_PIXELS: u64 = 1 << 21;
+
+impl AvifTiles {
+    /// Number of tiles for a `width` x `height` image
+    pub fn count(self, width: u32, height: u32) -> usize {
+        match self {
+            Self::Grid { rows, cols } => usize::from(rows) * usize::from(cols),
+            Self::Auto => {
+                let area = u64::from(width) * u64::from(height);
+                area.div_ceil(AVIF_AUTO_TILE_PIXELS).clamp(1, 64) as usize
+            }
+        }
+    }
+}
+
+/// What `max_aspect_ratio` does with an image that exceeds it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AspectRatioAction {
+    /// Print a warning and encode the image as it is
+    #[default]
+    Warn,
+    /// Keep the middle of the long side, cropped to exactly the ratio
+    Crop,
+}
+
+/// How resized pixels are computed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeAlgorithm {
+    /// Lanczos3 resampling; sharpest, and the slowest
+    #[default]
+    Resize,
+    /// `image`'s `thumbnail_exact` box sampling; several times faster but
+    /// softer and prone to aliasing, meant for large batches of small thumbnails
+    Thumbnail,
+    /// Area averaging for reductions of [`AUTO_AREA_AVERAGE_FACTOR`] or more on
+    /// both axes, where Lanczos rings around high-contrast edges; Lanczos for
+    /// milder reductions and enlargements
+    Auto,
+}
+
+/// Per-axis reduction from which `ResizeAlgorithm::Auto` area-averages
+pub const AUTO_AREA_AVERAGE_FACTOR: u32 = 3;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ResizeOptions {
     pub width: u32,
@@ -42,9 +542,17 @@ pub struct ResizeOptions {
 }
 
 impl ResizeOptions {
+    /// `FitWidth` ignores `height` and `FitHeight` ignores `width`
     pub fn new(width: u32, height: u32, mode: ResizeMode) -> Result<Self> {
-        if width == 0 || height == 0 {
-            bail!("resize width and height must be greater than zero");
+        match mode {
+            ResizeMode::Fit | ResizeMode::Exact | ResizeMode::Pad if width == 0 || height == 0 => {
+                bail!("resize width and height must be greater than zero")
+            }
+            ResizeMode::FitWidth if width == 0 => bail!("resize width must be greater than zero"),
+            ResizeMode::FitHeight if height == 0 => {
+                bail!("resize height must be greater than zero")
+            }
+            _ => {}
         }
         Ok(Self {
             width,
@@ -54,8 +562,146 @@ impl ResizeOptions {
     }
 }
 
+/// Alpha plane filtering for lossy WebP (libwebp `alpha_filtering`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebPAlphaFilter {
+    None,
+    Fast,
+    Best,
+}
+
+/// PNG row filter strategy for oxipng
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// Every fixed filter and heuristic oxipng has, keeping the smallest (slow)
+    TryAll,
+}
+
+impl PngFilter {
+    fn row_filters(self) -> oxipng::IndexSet<oxipng::RowFilter> {
+        use oxipng::RowFilter;
+        match self {
+            Self::None => oxipng::indexset! { RowFilter::None },
+            Self::Sub => oxipng::indexset! { RowFilter::Sub },
+            Self::Up => oxipng::indexset! { RowFilter::Up },
+            Self::Average => oxipng::indexset! { RowFilter::Average },
+            Self::Paeth => oxipng::indexset! { RowFilter::Paeth },
+            Self::TryAll => oxipng::indexset! {
+                RowFilter::None,
+                RowFilter::Sub,
+                RowFilter::Up,
+                RowFilter::Average,
+                RowFilter::Paeth,
+                RowFilter::MinSum,
+                RowFilter::Entropy,
+                RowFilter::Bigrams,
+                RowFilter::BigEnt,
+                RowFilter::Brute,
+            },
+        }
+    }
+}
+
+/// What transparent pixels are composited onto when the output format has no
+/// alpha channel (JPEG)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    Solid([u8; 3]),
+    /// Top-to-bottom linear gradient
+    VerticalGradient {
+        top: [u8; 3],
+        bottom: [u8; 3],
+    },
+}
+
+/// Border trimming: rows and columns within `tolerance` of the border color
+/// are cropped away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrimOptions {
+    /// Border color; `None` takes it from the image corners
+    pub color: Option<[u8; 3]>,
+    /// Largest per-channel difference still counted as border
+    pub tolerance: u8,
+}
+
+/// Pixel rectangle: top-left corner plus size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl fmt::Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}x{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Corner (or center) a watermark is anchored to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// A decoded overlay plus placement. The overlay is decoded once by
+/// [`WatermarkOptions::load`] and shared by every clone, so a batch doesn't
+/// re-read it per file.
+#[derive(Clone)]
+pub struct WatermarkOptions {
+    overlay: Arc<RgbaImage>,
+    pub position: WatermarkPosition,
+    /// 0.0 (invisible) to 1.0 (the overlay's own alpha)
+    pub opacity: f32,
+    /// Distance from the anchored edges, in pixels
+    pub margin: u32,
+}
+
+impl WatermarkOptions {
+    pub fn load(
+        path: &Path,
+        position: WatermarkPosition,
+        opacity: f32,
+        margin: u32,
+    ) -> Result<Self> {
+        if !(0.0..=1.0).contains(&opacity) {
+            bail!("watermark opacity must be between 0.0 and 1.0");
+        }
+        let overlay = image::open(path)
+            .with_context(|| format!("failed to load watermark: {}", path.display()))?
+            .to_rgba8();
+        Ok(Self {
+            overlay: Arc::new(overlay),
+            position,
+            opacity,
+            margin,
+        })
+    }
+}
+
+impl fmt::Debug for WatermarkOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatermarkOptions")
+            .field("overlay", &self.overlay.dimensions())
+            .field("position", &self.position)
+            .field("opacity", &self.opacity)
+            .field("margin", &self.margin)
+            .finish()
+    }
+}
+
 /// Main configuration for compression
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct CompressOptions {
     pub overwrite: bool,
     pub quality: Option<u8>,
@@ -65,6 +711,135 @@ pub struct CompressOptions {
     pub resize: Option<ResizeOptions>,
     pub png_level: Option<u8>,
     pub avif_speed: Option<u8>,
+    /// Store AVIF color channels premultiplied by alpha. Transparent images
+    /// are then always 10-bit, so an `output_bit_depth` of 8 is an error for them.
+    pub avif_premultiplied_alpha: bool,
+    /// Preserve RGB values under fully transparent WebP pixels
+    pub webp_exact: bool,
+    /// Use libwebp's sharper (slower) RGB to YUV conversion for lossy WebP
+    pub webp_sharp_yuv: bool,
+    /// Predictive filtering of the lossy WebP alpha plane (libwebp default: fast)
+    pub webp_alpha_filter: Option<WebPAlphaFilter>,
+    /// Box-filter very large reductions (over 4x) to twice the target before the
+    /// final Lanczos pass; much faster for thumbnails with minimal quality loss
+    pub fast_downscale: bool,
+    /// Write output to a sibling temp file and rename it into place, so an
+    /// interrupted run never leaves a half-written file
+    pub atomic_write: bool,
+    /// Let oxipng rewrite the color of fully transparent pixels for better
+    /// compression. Lossy for the hidden RGB data, so off by default.
+    pub png_optimize_alpha: bool,
+    /// JPEG trellis quantization (None = mozjpeg default, on). Turning it off
+    /// switches mozjpeg to its fastest, libjpeg-turbo compatible profile;
+    /// `Some(true)` is the same as `None`
+    pub jpeg_trellis: Option<bool>,
+    /// JPEG optimized Huffman coding (None = profile default)
+    pub jpeg_optimize_coding: Option<bool>,
+    /// Backdrop for transparent images encoded to JPEG (None = alpha is dropped)
+    pub background: Option<Background>,
+    /// Make output bit-reproducible across machines: AVIF is encoded single-threaded
+    /// unless `avif_tiles` fixes the tile count (the tile layout otherwise
+    /// follows the core count), and PNG drops tIME
+    pub deterministic: bool,
+    /// Post-encode quality guard: JPEG/WebP results below this PSNR (dB) are
+    /// flagged with a warning, or rejected with `quality_guard_strict`
+    pub min_psnr: Option<f64>,
+    /// Fail files that fall below `min_psnr` instead of writing them
+    pub quality_guard_strict: bool,
+    /// Raw `key=value` knobs applied best-effort to the active encoder (see
+    /// [`encoder_opt_keys`]); unknown keys and bad values are warned about and ignored
+    pub encoder_opts: BTreeMap<String, String>,
+    /// AVIF tiling (None = ravif's choice: one tile per encoder thread, fewer
+    /// for small images). ravif only sizes tiles from its thread count, so
+    /// this sets the encoder threads to the tile count and rav1e picks the
+    /// row/column split for that many tiles. The count depends only on the
+    /// options and image size, so it is kept with `deterministic`.
+    pub avif_tiles: Option<AvifTiles>,
+    /// WebP only: pick lossless for graphic-like images (few colors or large
+    /// flat areas) and lossy for photos; `lossless` still forces lossless
+    pub auto_lossless: bool,
+    /// Crop uniform borders after decoding, before resize
+    pub trim: Option<TrimOptions>,
+    /// Overlay stamped onto every image after resizing
+    pub watermark: Option<WatermarkOptions>,
+    /// PNG row filter strategy (None = the `png_level` preset's choice)
+    pub png_filter: Option<PngFilter>,
+    /// Only write output that is both smaller than the input and at least this
+    /// SSIM (0-1) against the decoded source; otherwise nothing is written and
+    /// `CompressionStats::kept_original` says why
+    pub compare_original: Option<f64>,
+    /// With metadata kept, drop the embedded EXIF thumbnail (IFD1) and MakerNote
+    /// from JPEG and WebP output while keeping the other EXIF fields
+    pub strip_exif_thumbnail: bool,
+    /// Quantize PNG output to at most this many colors (2-256) before oxipng,
+    /// trading controlled banding for an indexed PNG
+    pub quantize_colors: Option<u16>,
+    /// pngquant-style lossy PNG: quantize to the fewest colors that reach the
+    /// `(min, max)` quality (0-100), falling back to full color below `min`
+    pub png_lossy_quality: Option<(u8, u8)>,
+    /// Floyd–Steinberg dithering when `quantize_colors` or `png_lossy_quality`
+    /// reduces the palette
+    pub dither: bool,
+    /// When the source has no ICC profile, tag the output as sRGB (an sRGB
+    /// chunk in PNG, a compact sRGB ICC profile in JPEG and WebP) instead of
+    /// leaving it untagged. Applies with `strip_metadata` too. AVIF is
+    /// unaffected: untagged AVIF already means sRGB.
+    pub assume_srgb: bool,
+    /// Retry reads and writes this many times, with exponential backoff, when
+    /// they fail with a transient IO error (interrupted, would block, timed
+    /// out), e.g. on network filesystems. Missing files and permission errors
+    /// fail at once.
+    pub io_retries: u32,
+    /// Scaling algorithm for `resize`: Lanczos (default) or the much faster,
+    /// lower quality `thumbnail` box sampling
+    pub resize_algorithm: ResizeAlgorithm,
+    /// Bits per channel for WebP and AVIF output: 8 or 10 (None = the
+    /// encoder's default: 8 for WebP, 10 for AVIF). WebP is 8-bit only.
+    /// Sources with more bits than the output are reduced
+    /// with ordered dithering rather than rounding, to avoid banding.
+    pub output_bit_depth: Option<u8>,
+    /// Directory for atomic-write temp files (None = next to the output), for
+    /// output directories that are read-only to new files or short on space.
+    /// Across filesystems the temp file is copied next to the output and renamed
+    /// from there, so replacing the output stays atomic.
+    pub temp_dir: Option<PathBuf>,
+    /// Embed the XMP from a sidecar next to the input (`photo.jpg.xmp`, else
+    /// `photo.xmp`) in JPEG and WebP output, in place of any XMP from the
+    /// source and even with `strip_metadata`. A missing sidecar is a warning.
+    pub xmp_sidecar: bool,
+    /// With PNG output, write an animated GIF or APNG source as an APNG: every
+    /// frame goes through the pixel edits and keeps its delay, and the animation
+    /// keeps the source's loop count. Still sources are unaffected.
+    pub apng: bool,
+    /// Scale down (keeping the aspect ratio) so neither side is longer than
+    /// this, after any `resize`; images already within it are left alone.
+    /// An `Exact` or `Pad` resize larger than this is an error.
+    pub max_dimension: Option<u32>,
+    /// AVIF speed and quality picked together; an explicit `avif_speed` or
+    /// `quality` still wins over the preset's
+    pub avif_preset: Option<AvifPreset>,
+    /// Embed a short marker naming this tool, the format and the quality in
+    /// JPEG (a comment) and PNG (a `tEXt` chunk) output, for
+    /// `BatchOptions::skip_marked` to recognize on a later run
+    pub mark_output: bool,
+    /// Longest-to-shortest side ratio past which `aspect_ratio_action` applies
+    /// (None = no limit), checked after trimming and before resizing
+    pub max_aspect_ratio: Option<f64>,
+    /// What an image beyond `max_aspect_ratio` gets: a warning, or a center crop
+    /// of its long side down to the ratio
+    pub aspect_ratio_action: AspectRatioAction,
+    /// Lossy WebP: two entropy-analysis passes instead of one, the second using
+    /// the first's statistics; slower encodes for slightly better rate
+    /// distribution. AVIF's encoder has no multi-pass mode, so it is WebP only.
+    pub two_pass: bool,
+    /// Region cut out of the source before any other pixel edit; it must lie
+    /// within the source image
+    pub crop: Option<Rect>,
+    /// Pick each image's quality between `(min, max)` from its detail: flat
+    /// graphics get `max`, where compression artifacts show, busy photos
+    /// nearer `min`, where texture hides them. An explicit `quality` (e.g. from a
+    /// rule or quality map) wins. Lossy JPEG, WebP and AVIF only.
+    pub adaptive_quality: Option<(u8, u8)>,
 }
 
 impl Default for CompressOptions {
@@ -78,420 +853,7292 @@ impl Default for CompressOptions {
             resize: None,
             png_level: None,
             avif_speed: None,
+            avif_premultiplied_alpha: false,
+            webp_exact: false,
+            webp_sharp_yuv: false,
+            webp_alpha_filter: None,
+            fast_downscale: false,
+            atomic_write: true,
+            png_optimize_alpha: false,
+            jpeg_trellis: None,
+            jpeg_optimize_coding: None,
+            background: None,
+            deterministic: false,
+            min_psnr: None,
+            quality_guard_strict: false,
+            encoder_opts: BTreeMap::new(),
+            avif_tiles: None,
+            auto_lossless: false,
+            trim: None,
+            watermark: None,
+            png_filter: None,
+            compare_original: None,
+            strip_exif_thumbnail: false,
+            quantize_colors: None,
+            png_lossy_quality: None,
+            dither: true,
+            assume_srgb: false,
+            io_retries: 0,
+            resize_algorithm: ResizeAlgorithm::Resize,
+            output_bit_depth: None,
+            temp_dir: None,
+            xmp_sidecar: false,
+            apng: false,
+            max_dimension: None,
+            avif_preset: None,
+            mark_output: false,
+            max_aspect_ratio: None,
+            aspect_ratio_action: AspectRatioAction::Warn,
+            two_pass: false,
+            crop: None,
+            adaptive_quality: None,
         }
     }
 }
 
-/// Stats for a single compression operation
-#[derive(Debug, Clone, Copy)]
-pub struct CompressionStats {
-    pub original_bytes: u64,
-    pub compressed_bytes: u64,
-    pub savings_percent: f64,
+/// Chainable construction of [`CompressOptions`], starting from the defaults:
+///
+/// ```
+/// use image_compressor_rs::CompressOptions;
+///
+/// let options = CompressOptions::builder()
+///     .quality(85)
+///     .progressive(true)
+///     .build()
+///     .unwrap();
+/// assert_eq!(options.quality, Some(85));
+/// ```
+///
+/// Setters take the plain value for optional fields (`quality(85)` sets
+/// `Some(85)`); see the [`CompressOptions`] fields for what each controls.
+#[derive(Debug, Clone, Default)]
+pub struct CompressOptionsBuilder {
+    options: CompressOptions,
 }
 
-/// Batch operation report
-#[derive(Debug, Default, Clone, Copy)]
-pub struct BatchReport {
-    pub compressed: usize,
-    pub skipped: usize,
-    pub failed: usize,
-    pub total_original_bytes: u64,
-    pub total_compressed_bytes: u64,
+impl CompressOptions {
+    pub fn builder() -> CompressOptionsBuilder {
+        CompressOptionsBuilder::default()
+    }
 }
 
-// ---------------------------------------------------------------------------
-// Public API
-// ---------------------------------------------------------------------------
+impl CompressOptionsBuilder {
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.options.overwrite = overwrite;
+        self
+    }
 
-pub fn compress_image_file(
-    input: &Path,
-    output: &Path,
-    options: &CompressOptions,
-) -> Result<CompressionStats> {
-    validate_input_and_output(input, output, options)?;
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.options.quality = Some(quality);
+        self
+    }
 
-    let input_bytes = fs::read(input)
-        .with_context(|| format!("failed to read input file: {}", input.display()))?;
-    let original_bytes = input_bytes.len() as u64;
+    pub fn lossless(mut self, lossless: bool) -> Self {
+        self.options.lossless = lossless;
+        self
+    }
 
-    let ext = output
-        .extension()
-        .and_then(|v| v.to_str())
-        .context("output path must include a file extension")?;
-    let format = OutputFormat::from_extension(ext)?;
+    pub fn auto_lossless(mut self, auto_lossless: bool) -> Self {
+        self.options.auto_lossless = auto_lossless;
+        self
+    }
 
-    // Special case: PNG input → PNG output without resize — run oxipng directly
-    let compressed = if format == OutputFormat::Png && options.resize.is_none() {
-        let is_png = image::guess_format(&input_bytes)
-            .map(|f| f == ImageFormat::Png)
-            .unwrap_or(false);
-        if is_png {
-            compress_png(&input_bytes, None, options)?
-        } else {
-            let image = decode_and_resize(&input_bytes, options)?;
-            compress_png(&[], Some(&image), options)?
-        }
-    } else {
-        let image = decode_and_resize(&input_bytes, options)?;
-        match format {
-            OutputFormat::Jpeg => compress_jpeg(&image, options)?,
-            OutputFormat::Png => compress_png(&[], Some(&image), options)?,
-            OutputFormat::WebP => compress_webp(&image, options)?,
-            OutputFormat::Avif => compress_avif(&image, options)?,
-        }
-    };
+    pub fn progressive(mut self, progressive: bool) -> Self {
+        self.options.progressive = progressive;
+        self
+    }
 
-    fs::write(output, &compressed)
-        .with_context(|| format!("failed to write output file: {}", output.display()))?;
+    pub fn strip_metadata(mut self, strip_metadata: bool) -> Self {
+        self.options.strip_metadata = strip_metadata;
+        self
+    }
 
-    let compressed_bytes = compressed.len() as u64;
-    let savings_percent = if original_bytes > 0 {
-        (1.0 - compressed_bytes as f64 / original_bytes as f64) * 100.0
-    } else {
-        0.0
-    };
+    pub fn resize(mut self, resize: ResizeOptions) -> Self {
+        self.options.resize = Some(resize);
+        self
+    }
 
-    Ok(CompressionStats {
-        original_bytes,
-        compressed_bytes,
-        savings_percent,
-    })
-}
+    pub fn fast_downscale(mut self, fast_downscale: bool) -> Self {
+        self.options.fast_downscale = fast_downscale;
+        self
+    }
 
-pub fn compress_directory(
-    input_dir: &Path,
-    output_dir: &Path,
-    to_extension: &str,
-    options: &CompressOptions,
-    recursive: bool,
-) -> Result<BatchReport> {
-    if !input_dir.is_dir() {
-        bail!("input directory not found: {}", input_dir.display());
+    pub fn trim(mut self, trim: TrimOptions) -> Self {
+        self.options.trim = Some(trim);
+        self
     }
 
-    fs::create_dir_all(output_dir).with_context(|| {
-        format!(
-            "failed to create output directory: {}",
-            output_dir.display()
-        )
-    })?;
+    pub fn watermark(mut self, watermark: WatermarkOptions) -> Self {
+        self.options.watermark = Some(watermark);
+        self
+    }
 
-    let to_extension = normalize_extension(to_extension)?;
-    let files = collect_input_files(input_dir, recursive)?;
-    let mut report = BatchReport::default();
+    pub fn atomic_write(mut self, atomic_write: bool) -> Self {
+        self.options.atomic_write = atomic_write;
+        self
+    }
 
-    for source_path in files {
-        let Ok(relative_path) = source_path.strip_prefix(input_dir) else {
-            report.failed += 1;
-            continue;
-        };
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.options.deterministic = deterministic;
+        self
+    }
 
-        let mut target_path = output_dir.join(relative_path);
-        target_path.set_extension(&to_extension);
+    pub fn min_psnr(mut self, min_psnr: f64) -> Self {
+        self.options.min_psnr = Some(min_psnr);
+        self
+    }
 
-        if let Some(parent) = target_path.parent() {
-            fs::create_dir_all(parent).ok();
-        }
+    pub fn quality_guard_strict(mut self, quality_guard_strict: bool) -> Self {
+        self.options.quality_guard_strict = quality_guard_strict;
+        self
+    }
 
-        if target_path.exists() && !options.overwrite {
-            report.skipped += 1;
-            continue;
-        }
+    pub fn compare_original(mut self, compare_original: f64) -> Self {
+        self.options.compare_original = Some(compare_original);
+        self
+    }
 
-        let source_name = source_path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("?");
-        let target_name = target_path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("?");
+    pub fn png_level(mut self, png_level: u8) -> Self {
+        self.options.png_level = Some(png_level);
+        self
+    }
 
-        match compress_image_file(&source_path, &target_path, options) {
-            Ok(stats) => {
-                println!(
-                    "compressed {} \u{2192} {} ({} \u{2192} {}, saved {:.1}%)",
-                    source_name,
-                    target_name,
-                    format_size(stats.original_bytes),
-                    format_size(stats.compressed_bytes),
-                    stats.savings_percent,
-                );
-                report.compressed += 1;
-                report.total_original_bytes += stats.original_bytes;
-                report.total_compressed_bytes += stats.compressed_bytes;
-            }
-            Err(err) => {
-                eprintln!("failed {}: {err:#}", source_name);
-                report.failed += 1;
-            }
-        }
+    pub fn png_filter(mut self, png_filter: PngFilter) -> Self {
+        self.options.png_filter = Some(png_filter);
+        self
     }
 
-    Ok(report)
-}
+    pub fn png_optimize_alpha(mut self, png_optimize_alpha: bool) -> Self {
+        self.options.png_optimize_alpha = png_optimize_alpha;
+        self
+    }
 
-// ---------------------------------------------------------------------------
-// Format-specific encoders
-// ---------------------------------------------------------------------------
+    pub fn avif_speed(mut self, avif_speed: u8) -> Self {
+        self.options.avif_speed = Some(avif_speed);
+        self
+    }
 
-fn compress_jpeg(image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>> {
-    let rgb = image.to_rgb8();
-    let (width, height) = (rgb.width() as usize, rgb.height() as usize);
-    let pixels = rgb.as_raw();
+    pub fn avif_tiles(mut self, avif_tiles: AvifTiles) -> Self {
+        self.options.avif_tiles = Some(avif_tiles);
+        self
+    }
 
-    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
-    comp.set_size(width, height);
-    comp.set_quality(options.quality.unwrap_or(85) as f32);
+    pub fn avif_premultiplied_alpha(mut self, avif_premultiplied_alpha: bool) -> Self {
+        self.options.avif_premultiplied_alpha = avif_premultiplied_alpha;
+        self
+    }
 
-    if options.progressive {
-        comp.set_scan_optimization_mode(mozjpeg::ScanMode::AllComponentsTogether);
+    pub fn webp_exact(mut self, webp_exact: bool) -> Self {
+        self.options.webp_exact = webp_exact;
+        self
     }
 
-    let mut comp = comp.start_compress(Vec::new())?;
-    comp.write_scanlines(pixels)
-        .context("failed to write JPEG scanlines")?;
-    let result = comp.finish()?;
+    pub fn webp_sharp_yuv(mut self, webp_sharp_yuv: bool) -> Self {
+        self.options.webp_sharp_yuv = webp_sharp_yuv;
+        self
+    }
 
-    Ok(result)
-}
+    pub fn webp_alpha_filter(mut self, webp_alpha_filter: WebPAlphaFilter) -> Self {
+        self.options.webp_alpha_filter = Some(webp_alpha_filter);
+        self
+    }
 
-fn compress_png(
-    input_bytes: &[u8],
-    image: Option<&DynamicImage>,
-    options: &CompressOptions,
-) -> Result<Vec<u8>> {
-    let png_bytes = if let Some(img) = image {
-        let mut buf = Vec::new();
-        img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
-            .context("failed to encode PNG")?;
-        buf
-    } else {
-        input_bytes.to_vec()
-    };
+    pub fn jpeg_trellis(mut self, jpeg_trellis: bool) -> Self {
+        self.options.jpeg_trellis = Some(jpeg_trellis);
+        self
+    }
 
-    let level = options.png_level.unwrap_or(2);
-    let mut opts = oxipng::Options::from_preset(level);
-    if options.strip_metadata {
-        opts.strip = oxipng::StripChunks::Safe;
+    pub fn jpeg_optimize_coding(mut self, jpeg_optimize_coding: bool) -> Self {
+        self.options.jpeg_optimize_coding = Some(jpeg_optimize_coding);
+        self
     }
 
-    oxipng::optimize_from_memory(&png_bytes, &opts).context("PNG optimization failed")
-}
+    pub fn background(mut self, background: Background) -> Self {
+        self.options.background = Some(background);
+        self
+    }
 
-fn compress_webp(image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>> {
-    let rgba = image.to_rgba8();
-    let (width, height) = rgba.dimensions();
-    let encoder = webp::Encoder::from_rgba(rgba.as_raw(), width, height);
+    pub fn quantize_colors(mut self, quantize_colors: u16) -> Self {
+        self.options.quantize_colors = Some(quantize_colors);
+        self
+    }
 
-    let memory = if options.lossless {
-        encoder.encode_lossless()
-    } else {
-        let quality = options.quality.unwrap_or(85) as f32;
-        encoder.encode(quality)
-    };
+    pub fn png_lossy_quality(mut self, min: u8, max: u8) -> Self {
+        self.options.png_lossy_quality = Some((min, max));
+        self
+    }
 
-    Ok(memory.to_vec())
-}
+    pub fn dither(mut self, dither: bool) -> Self {
+        self.options.dither = dither;
+        self
+    }
 
-fn compress_avif(image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>> {
-    let rgba = image.to_rgba8();
-    let (width, height) = rgba.dimensions();
+    pub fn strip_exif_thumbnail(mut self, strip_exif_thumbnail: bool) -> Self {
+        self.options.strip_exif_thumbnail = strip_exif_thumbnail;
+        self
+    }
+
+    pub fn assume_srgb(mut self, assume_srgb: bool) -> Self {
+        self.options.assume_srgb = assume_srgb;
+        self
+    }
+
+    pub fn resize_algorithm(mut self, resize_algorithm: ResizeAlgorithm) -> Self {
+        self.options.resize_algorithm = resize_algorithm;
+        self
+    }
+
+    pub fn output_bit_depth(mut self, output_bit_depth: u8) -> Self {
+        self.options.output_bit_depth = Some(output_bit_depth);
+        self
+    }
+
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.options.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    pub fn xmp_sidecar(mut self, xmp_sidecar: bool) -> Self {
+        self.options.xmp_sidecar = xmp_sidecar;
+        self
+    }
+
+    pub fn apng(mut self, apng: bool) -> Self {
+        self.options.apng = apng;
+        self
+    }
+
+    pub fn max_dimension(mut self, max_dimension: u32) -> Self {
+        self.options.max_dimension = Some(max_dimension);
+        self
+    }
+
+    pub fn adaptive_quality(mut self, min: u8, max: u8) -> Self {
+        self.options.adaptive_quality = Some((min, max));
+        self
+    }
+
+    pub fn crop(mut self, crop: Rect) -> Self {
+        self.options.crop = Some(crop);
+        self
+    }
+
+    pub fn avif_preset(mut self, avif_preset: AvifPreset) -> Self {
+        self.options.avif_preset = Some(avif_preset);
+        self
+    }
+
+    pub fn mark_output(mut self, mark_output: bool) -> Self {
+        self.options.mark_output = mark_output;
+        self
+    }
+
+    pub fn max_aspect_ratio(mut self, max_aspect_ratio: f64) -> Self {
+        self.options.max_aspect_ratio = Some(max_aspect_ratio);
+        self
+    }
+
+    pub fn aspect_ratio_action(mut self, aspect_ratio_action: AspectRatioAction) -> Self {
+        self.options.aspect_ratio_action = aspect_ratio_action;
+        self
+    }
+
+    pub fn two_pass(mut self, two_pass: bool) -> Self {
+        self.options.two_pass = two_pass;
+        self
+    }
+
+    pub fn io_retries(mut self, io_retries: u32) -> Self {
+        self.options.io_retries = io_retries;
+        self
+    }
+
+    /// Add one raw encoder knob (see [`CompressOptions::encoder_opts`])
+    pub fn encoder_opt(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.encoder_opts.insert(key.into(), value.into());
+        self
+    }
+
+    /// Check value ranges and combinations, then return the options.
+    /// Out-of-range values and settings that need another one (`quality_guard_strict`
+    /// without `min_psnr`) are errors. Settings the chosen encoder ignores,
+    /// such as `quality` with lossless WebP, are reported by [`ignored_options`].
+    pub fn build(self) -> Result<CompressOptions> {
+        let options = self.options;
+        let check_range =
+            |name: &str, value: Option<u16>, range: std::ops::RangeInclusive<u16>| match value {
+                Some(value) if !range.contains(&value) => bail!(
+                    "{name} must be between {} and {}, got {value}",
+                    range.start(),
+                    range.end()
+                ),
+                _ => Ok(()),
+            };
+        check_range("quality", options.quality.map(u16::from), 1..=100)?;
+        check_range("png_level", options.png_level.map(u16::from), 1..=6)?;
+        check_range("quantize_colors", options.quantize_colors, 2..=256)?;
+        check_range("avif_speed", options.avif_speed.map(u16::from), 1..=10)?;
+        if let Some(AvifTiles::Grid { rows, cols }) = options.avif_tiles
+            && (rows == 0 || cols == 0 || u16::from(rows) * u16::from(cols) > 64)
+        {
+            bail!("avif_tiles must be ROWSxCOLS with 1 to 64 tiles in all");
+        }
+        if options
+            .output_bit_depth
+            .is_some_and(|depth| depth != 8 && depth != 10)
+        {
+            bail!("output_bit_depth must be 8 or 10");
+        }
+        if options
+            .min_psnr
+            .is_some_and(|psnr| !psnr.is_finite() || psnr <= 0.0)
+        {
+            bail!("min_psnr must be a positive number of dB");
+        }
+        if options
+            .max_aspect_ratio
+            .is_some_and(|ratio| !ratio.is_finite() || ratio < 1.0)
+        {
+            bail!("max_aspect_ratio must be a number of at least 1.0");
+        }
+        if options
+            .adaptive_quality
+            .is_some_and(|(min, max)| min == 0 || min > max || max > 100)
+        {
+            bail!("adaptive_quality must be MIN-MAX with 1 <= MIN <= MAX <= 100");
+        }
+        if options
+            .crop
+            .is_some_and(|crop| crop.width == 0 || crop.height == 0)
+        {
+            bail!("crop width and height must be greater than zero");
+        }
+        if options.quality_guard_strict && options.min_psnr.is_none() {
+            bail!("quality_guard_strict needs min_psnr");
+        }
+        if options
+            .compare_original
+            .is_some_and(|ssim| !(0.0..=1.0).contains(&ssim))
+        {
+            bail!("compare_original must be an SSIM between 0.0 and 1.0");
+        }
+        if let Some((min, max)) = options.png_lossy_quality {
+            if min > max || max > 100 {
+                bail!("png_lossy_quality must be MIN-MAX with 0 <= MIN <= MAX <= 100");
+            }
+            if options.quantize_colors.is_some() {
+                bail!("png_lossy_quality and quantize_colors both pick the palette size");
+            }
+        }
+        check_exact_resize_fits(&options)?;
+        Ok(options)
+    }
+}
+
+/// Fields of `options` that are set but have no effect when encoding to
+/// `format`, such as `progressive` for WebP or `quality` in lossless mode
+pub fn ignored_options(options: &CompressOptions, format: OutputFormat) -> Vec<&'static str> {
+    let jpeg = format == OutputFormat::Jpeg;
+    let png = format == OutputFormat::Png;
+    let webp = format == OutputFormat::WebP;
+    let avif = format == OutputFormat::Avif;
+
+    [
+        (
+            options.quality.is_some() && (png || options.lossless && !jpeg),
+            "quality",
+        ),
+        (options.lossless && (jpeg || png), "lossless"),
+        (options.auto_lossless && !webp, "auto_lossless"),
+        (options.progressive && !jpeg, "progressive"),
+        (options.jpeg_trellis.is_some() && !jpeg, "jpeg_trellis"),
+        (
+            options.jpeg_optimize_coding.is_some() && !jpeg,
+            "jpeg_optimize_coding",
+        ),
+        (options.background.is_some() && !jpeg, "background"),
+        (options.png_level.is_some() && !png, "png_level"),
+        (options.png_filter.is_some() && !png, "png_filter"),
+        (options.quantize_colors.is_some() && !png, "quantize_colors"),
+        (
+            options.png_lossy_quality.is_some() && !png,
+            "png_lossy_quality",
+        ),
+        (options.png_optimize_alpha && !png, "png_optimize_alpha"),
+        (options.apng && !png, "apng"),
+        (options.mark_output && !(jpeg || png), "mark_output"),
+        (options.avif_speed.is_some() && !avif, "avif_speed"),
+        (options.avif_preset.is_some() && !avif, "avif_preset"),
+        (options.avif_tiles.is_some() && !avif, "avif_tiles"),
+        (
+            options.avif_premultiplied_alpha && !avif,
+            "avif_premultiplied_alpha",
+        ),
+        (
+            options.output_bit_depth.is_some() && !(webp || avif),
+            "output_bit_depth",
+        ),
+        (options.webp_exact && !webp, "webp_exact"),
+        (options.webp_sharp_yuv && !webp, "webp_sharp_yuv"),
+        (options.two_pass && !webp, "two_pass"),
+        (
+            options.adaptive_quality.is_some() && (png || options.lossless && !jpeg),
+            "adaptive_quality",
+        ),
+        (
+            options.webp_alpha_filter.is_some() && !webp,
+            "webp_alpha_filter",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(ignored, name)| ignored.then_some(name))
+    .collect()
+}
+
+/// Configuration for directory batch runs
+#[derive(Debug, Default, Clone)]
+pub struct BatchOptions {
+    pub recursive: bool,
+    /// Skip files recorded as completed in the output directory's state file,
+    /// as long as their source hash is unchanged
+    pub resume: bool,
+    /// Units used for the per-file size lines printed during the run
+    pub size_units: SizeUnits,
+    /// Follow each per-file line with its decode/resize/encode timings
+    pub verbose: bool,
+    /// Keep each source's own format (JPEG, PNG, WebP, AVIF) instead of
+    /// converting; sources that can't be written back use the batch target
+    pub follow_input_format: bool,
+    /// Keep only this many leading directories of each relative path and fold
+    /// the deeper ones into the file name, joined with `_` (None = full mirror)
+    pub keep_structure_depth: Option<usize>,
+    /// Stop starting new files once this many output bytes have been written
+    pub total_budget: Option<u64>,
+    /// Per-file quality overrides keyed by relative path (`/`-separated) or
+    /// file name; see [`parse_quality_map`]
+    pub quality_map: BTreeMap<String, u8>,
+    /// Per-subfolder settings; the first rule whose prefix matches a file's
+    /// relative path applies, before `quality_map`. See [`parse_batch_rules`]
+    pub rules: Vec<BatchRule>,
+    /// Only process sources modified at or after this time; older ones are
+    /// counted in `BatchReport::skipped_unchanged`. See [`parse_since`]
+    pub modified_since: Option<SystemTime>,
+    /// Output path layout under the output directory (None = mirror the
+    /// input tree); see [`NameTemplate`]
+    pub name_template: Option<NameTemplate>,
+    /// Skip sources already in the output format that are no larger than
+    /// [`estimate_output_size`] predicts a re-encode would be. Nothing is
+    /// written for them; they are counted in `BatchReport::skipped_optimized`.
+    pub skip_optimized: bool,
+    /// Copy files that aren't recognized images to their mirrored path as
+    /// they are (keeping their extension) instead of failing them; counted in
+    /// `BatchReport::copied`
+    pub copy_non_images: bool,
+    /// Append the output extension instead of replacing the source's, so
+    /// `photo.jpg` becomes `photo.jpg.webp`
+    pub preserve_input_extension: bool,
+    /// Put each output under a folder named for its format (`webp/img/a.webp`,
+    /// `avif/img/b.avif`), for runs that write several formats through
+    /// `rules` or `follow_input_format`. Copied non-images go under the
+    /// batch target's folder.
+    pub per_format_subdir: bool,
+    /// Process only the first N input files in path order (None = all)
+    pub limit: Option<usize>,
+    /// Replace an existing output only when its source was modified after
+    /// it, like `make`; up-to-date outputs are counted in
+    /// `BatchReport::skipped_uptodate`. Takes precedence over `overwrite`.
+    pub overwrite_newer_only: bool,
+    /// When an existing output would be replaced, leave it (and its mtime)
+    /// alone if the new encode is byte-identical; counted in
+    /// `BatchReport::skipped_identical`
+    pub skip_identical: bool,
+    /// Skip sources in the output format that carry the `mark_output` marker
+    /// for this run's quality, i.e. written by an earlier run at the same
+    /// settings. Nothing is written for them; they are counted in
+    /// `BatchReport::skipped_already_optimized`.
+    pub skip_marked: bool,
+    /// Write a JSON manifest here at the end of the run, mapping each file
+    /// this run wrote (relative to the output directory, `/`-separated) to
+    /// its SHA-256 and size. Skipped files and kept originals are not listed.
+    pub manifest: Option<PathBuf>,
+}
+
+/// Batch output naming such as `export/{date}/{stem}`, rendered relative to
+/// the output directory. The output extension from the target format is
+/// appended to the rendered name, replacing any extension the template text
+/// ends with; dots that `{stem}` brings along are kept.
+///
+/// Tokens: `{dir}` (the file's relative directory, after
+/// `keep_structure_depth`), `{stem}` (file name without extension), and
+/// `{date}` (`YYYY-MM-DD`), `{time}` (`HHMMSS`) and `{timestamp}` (Unix
+/// seconds), all UTC and taken once from the clock passed to [`NameTemplate::new`].
+/// `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<NamePart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NamePart {
+    Text(String),
+    Dir,
+    Stem,
+}
+
+impl NameTemplate {
+    /// Parse `template`, filling the date and time tokens from `now`
+    pub fn new(template: &str, now: SystemTime) -> Result<Self> {
+        let seconds = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+        let time_of_day = seconds % 86_400;
+
+        let mut parts = Vec::new();
+        for piece in template_pieces(template)? {
+            let part = match piece {
+                TemplatePiece::Text(text) => NamePart::Text(text.to_string()),
+                TemplatePiece::Token("dir") => NamePart::Dir,
+                TemplatePiece::Token("stem") => NamePart::Stem,
+                TemplatePiece::Token("date") => {
+                    NamePart::Text(format!("{year:04}-{month:02}-{day:02}"))
+                }
+                TemplatePiece::Token("time") => NamePart::Text(format!(
+                    "{:02}{:02}{:02}",
+                    time_of_day / 3_600,
+                    time_of_day / 60 % 60,
+                    time_of_day % 60
+                )),
+                TemplatePiece::Token("timestamp") => NamePart::Text(seconds.to_string()),
+                TemplatePiece::Token(token) => bail!("unknown name template token {{{token}}}"),
+            };
+            parts.push(part);
+        }
+        if let Some(NamePart::Text(text)) = parts.last_mut() {
+            let name_start = text.rfind('/').map_or(0, |slash| slash + 1);
+            if let Some(dot) = text[name_start..].rfind('.') {
+                text.truncate(name_start + dot);
+            }
+        }
+
+        if !parts.contains(&NamePart::Stem) {
+            bail!("name template needs {{stem}}, or every file would get the same name");
+        }
+        let escapes = parts.iter().any(|part| match part {
+            NamePart::Text(text) => text.split('/').any(|segment| segment == ".."),
+            _ => false,
+        });
+        if escapes {
+            bail!("name template must stay inside the output directory");
+        }
+        Ok(Self { parts })
+    }
+
+    /// Relative output path for a file at `relative_path`, before the output
+    /// extension is appended. `/` separates directories; empty segments are dropped.
+    fn render(&self, relative_path: &Path) -> PathBuf {
+        // Built from OS strings, so non-UTF-8 names come through unchanged
+        let mut rendered = PathBuf::new();
+        let mut segment = OsString::new();
+        let mut end_segment = |segment: &mut OsString| {
+            if !segment.is_empty() && segment != "." {
+                rendered.push(&*segment);
+            }
+            segment.clear();
+        };
+        for part in &self.parts {
+            match part {
+                NamePart::Text(text) => {
+                    let mut pieces = text.split('/');
+                    segment.push(pieces.next().unwrap_or_default());
+                    for piece in pieces {
+                        end_segment(&mut segment);
+                        segment.push(piece);
+                    }
+                }
+                NamePart::Dir => {
+                    let dir = relative_path.parent().unwrap_or(Path::new(""));
+                    for (i, component) in dir.components().enumerate() {
+                        if i > 0 {
+                            end_segment(&mut segment);
+                        }
+                        segment.push(component.as_os_str());
+                    }
+                }
+                NamePart::Stem => segment.push(name_stem(relative_path).unwrap_or_default()),
+            }
+        }
+        end_segment(&mut segment);
+        rendered
+    }
+}
+
+/// A literal run or a `{token}` of a name template
+enum TemplatePiece<'a> {
+    Text(&'a str),
+    Token(&'a str),
+}
+
+/// Split a template into literal text and `{token}` names, unescaping `{{` and `}}`
+fn template_pieces(template: &str) -> Result<Vec<TemplatePiece<'_>>> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find(['{', '}']) {
+        if pos > 0 {
+            pieces.push(TemplatePiece::Text(&rest[..pos]));
+        }
+        let tail = &rest[pos..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            pieces.push(TemplatePiece::Text(&tail[..1]));
+            rest = &tail[2..];
+        } else if let Some(tail) = tail.strip_prefix('{')
+            && let Some(end) = tail.find('}')
+        {
+            pieces.push(TemplatePiece::Token(&tail[..end]));
+            rest = &tail[end + 1..];
+        } else {
+            bail!("unbalanced brace in template {template:?}");
+        }
+    }
+    if !rest.is_empty() {
+        pieces.push(TemplatePiece::Text(rest));
+    }
+    Ok(pieces)
+}
+
+/// Batch settings for the files under one relative directory
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchRule {
+    /// Relative directory the rule covers, matched by whole path components
+    /// (`photos` matches `photos/a.jpg`, not `photos2/a.jpg`; empty matches all)
+    pub prefix: String,
+    /// Output extension for matching files (None = the batch target)
+    pub to: Option<String>,
+    pub quality: Option<u8>,
+    pub lossless: Option<bool>,
+    pub progressive: Option<bool>,
+    pub png_level: Option<u8>,
+    pub avif_speed: Option<u8>,
+    pub keep_metadata: Option<bool>,
+}
+
+impl BatchRule {
+    fn matches(&self, relative_path: &Path) -> bool {
+        relative_path.starts_with(Path::new(&self.prefix))
+    }
+
+    fn apply(&self, options: &mut CompressOptions) {
+        if let Some(quality) = self.quality {
+            options.quality = Some(quality);
+        }
+        if let Some(lossless) = self.lossless {
+            options.lossless = lossless;
+        }
+        if let Some(progressive) = self.progressive {
+            options.progressive = progressive;
+        }
+        if let Some(level) = self.png_level {
+            options.png_level = Some(level);
+        }
+        if let Some(speed) = self.avif_speed {
+            options.avif_speed = Some(speed);
+        }
+        if let Some(keep) = self.keep_metadata {
+            options.strip_metadata = !keep;
+        }
+    }
+}
+
+/// Per-file progress reported by [`compress_directory_with_progress`]
+#[derive(Debug)]
+pub enum BatchEvent<'a> {
+    /// Compression of `source` is starting
+    Started {
+        source: &'a Path,
+        target: &'a Path,
+    },
+    Done {
+        source: &'a Path,
+        target: &'a Path,
+        stats: CompressionStats,
+    },
+    /// The target already exists and was left alone
+    Skipped {
+        source: &'a Path,
+        target: &'a Path,
+    },
+    /// A non-image was copied verbatim (`copy_non_images`)
+    Copied {
+        source: &'a Path,
+        target: &'a Path,
+        bytes: u64,
+    },
+    Failed {
+        source: &'a Path,
+        error: String,
+    },
+}
+
+/// One entry of a batch plan: where a source would be written and whether
+/// the run would leave it alone
+#[derive(Debug, Clone)]
+pub struct PlannedFile {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// Input format detected from the file header, if recognized
+    pub detected_format: Option<ImageFormat>,
+    /// The target already exists and would be kept (no overwrite, or
+    /// unchanged since a resumed run)
+    pub skip: bool,
+    /// Not an image; `copy_non_images` would copy it to `target` as it is
+    pub copy: bool,
+}
+
+/// Unit system for human-readable sizes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SizeUnits {
+    /// 1,000-based (KB, MB)
+    #[default]
+    Decimal,
+    /// 1,024-based (KiB, MiB)
+    Binary,
+}
+
+impl SizeUnits {
+    pub fn format(self, bytes: u64) -> String {
+        match self {
+            Self::Decimal => format_size(bytes),
+            Self::Binary => format_size_binary(bytes),
+        }
+    }
+}
+
+/// Stats for a single compression operation
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompressionStats {
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+    pub savings_percent: f64,
+    /// Output dimensions in pixels
+    pub width: u32,
+    pub height: u32,
+    /// Payload size of source EXIF, XMP and ICC blocks not carried into the output
+    pub metadata_bytes_removed: u64,
+    /// The source had EXIF or XMP and the output carries none of it:
+    /// `strip_metadata` was on, or the output can't carry it (AVIF,
+    /// re-encoded PNG, a custom encoder). False for sources without any
+    pub metadata_stripped: bool,
+    /// PSNR of the output against the (resized) source, measured when
+    /// `min_psnr` is set and the format is lossy JPEG or WebP
+    pub psnr: Option<f64>,
+    /// Written despite falling below `min_psnr`
+    pub quality_flagged: bool,
+    /// SSIM of the output against the (resized) source, measured when
+    /// `compare_original` is set (1.0 for lossless output)
+    pub ssim: Option<f64>,
+    /// Set when `compare_original` rejected the output; nothing was written
+    pub kept_original: Option<KeptOriginal>,
+    /// Quality `adaptive_quality` picked for this image
+    pub adaptive_quality: Option<u8>,
+    /// Time spent in each stage of this file
+    pub timings: StageTimings,
+}
+
+/// Wall-clock time per compression stage. `resize` covers every pixel edit
+/// (crop, trim, resize, watermark); `encode` is the encoder call, oxipng
+/// included, but not quality checks. An APNG's per-frame edits count as
+/// `encode`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StageTimings {
+    pub decode: Duration,
+    pub resize: Duration,
+    pub encode: Duration,
+}
+
+impl fmt::Display for StageTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "decode {}ms, resize {}ms, encode {}ms",
+            self.decode.as_millis(),
+            self.resize.as_millis(),
+            self.encode.as_millis()
+        )
+    }
+}
+
+/// Why `compare_original` kept the original instead of writing the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeptOriginal {
+    /// The output was not smaller than the input
+    NotSmaller,
+    /// The output's SSIM fell below the floor
+    BelowSsimFloor,
+}
+
+impl fmt::Display for KeptOriginal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotSmaller => "output not smaller than the original",
+            Self::BelowSsimFloor => "output SSIM below the floor",
+        })
+    }
+}
+
+/// What [`probe_image`] reads from a file's headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Detected format in lowercase, e.g. `jpeg`, `png`, `tiff`
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+    pub file_bytes: u64,
+    /// Decoder color type, e.g. `Rgb8`, `La8`, `Rgba16`
+    pub color: String,
+    pub has_alpha: bool,
+    /// Payload sizes of the EXIF, XMP and ICC blocks (0 when absent)
+    pub exif_bytes: u64,
+    pub xmp_bytes: u64,
+    pub icc_bytes: u64,
+    /// Distinct RGBA colors, counted only by [`probe_image_colors`]. None when
+    /// not counted, or when there are more than [`UNIQUE_COLORS_LIMIT`].
+    pub unique_colors: Option<u64>,
+}
+
+/// [`probe_image_colors`] stops counting past this many colors
+pub const UNIQUE_COLORS_LIMIT: u64 = 100_000;
+
+/// Batch operation report
+#[derive(Debug, Default, Clone)]
+pub struct BatchReport {
+    pub compressed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub total_original_bytes: u64,
+    pub total_compressed_bytes: u64,
+    /// Sum of `CompressionStats::metadata_bytes_removed` over compressed files
+    pub total_metadata_bytes_removed: u64,
+    /// Number of compressed files that had any metadata removed
+    pub files_with_metadata_removed: usize,
+    /// Files written although their PSNR fell below `min_psnr`
+    pub quality_flagged: usize,
+    /// Files left untouched because `total_budget` was reached
+    pub not_processed: usize,
+    /// Files whose output `compare_original` rejected (not counted as compressed)
+    pub kept_original: usize,
+    /// Files skipped because they were not modified since `modified_since`
+    pub skipped_unchanged: usize,
+    /// Files skipped by `skip_optimized` as already smaller than a re-encode
+    pub skipped_optimized: usize,
+    /// Files skipped by `overwrite_newer_only` because the output is newer
+    pub skipped_uptodate: usize,
+    /// Files encoded but not written by `skip_identical`, because the
+    /// existing output already had exactly those bytes
+    pub skipped_identical: usize,
+    /// Files skipped by `skip_marked` as written by an earlier run
+    pub skipped_already_optimized: usize,
+    /// Non-image files copied verbatim by `copy_non_images`
+    pub copied: usize,
+    /// Wall-clock time of the whole run, including skipped and failed files
+    pub elapsed: Duration,
+    /// Compressed-file totals keyed by output format
+    pub by_format: BTreeMap<String, BatchTotals>,
+    /// Compressed-file totals keyed by top-level subdirectory ("." for the input root)
+    pub by_directory: BTreeMap<String, BatchTotals>,
+    /// Compressed files counted by savings: under 20%, 20-40%, 40-60%,
+    /// 60-80% and 80% or more. Outputs that grew count in the first bucket.
+    pub savings_histogram: [usize; SAVINGS_BUCKETS],
+}
+
+/// Number of 20%-wide buckets in `BatchReport::savings_histogram`
+pub const SAVINGS_BUCKETS: usize = 5;
+
+/// Aggregated sizes for one group of compressed files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchTotals {
+    pub files: usize,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl BatchReport {
+    /// Compressed files per second of `elapsed` (0 for an instant run)
+    pub fn files_per_sec(&self) -> f64 {
+        per_second(self.compressed as f64, self.elapsed)
+    }
+
+    /// Megabytes (10^6 bytes) of compressed files' input per second of `elapsed`
+    pub fn mb_per_sec(&self) -> f64 {
+        per_second(self.total_original_bytes as f64 / 1e6, self.elapsed)
+    }
+
+    fn record(&mut self, stats: &CompressionStats, format: String, directory: String) {
+        if stats.kept_original.is_some() {
+            self.kept_original += 1;
+            return;
+        }
+        self.compressed += 1;
+        self.total_original_bytes += stats.original_bytes;
+        self.total_compressed_bytes += stats.compressed_bytes;
+        if stats.metadata_bytes_removed > 0 {
+            self.total_metadata_bytes_removed += stats.metadata_bytes_removed;
+            self.files_with_metadata_removed += 1;
+        }
+        if stats.quality_flagged {
+            self.quality_flagged += 1;
+        }
+        let bucket = (stats.savings_percent / 20.0).floor().max(0.0) as usize;
+        self.savings_histogram[bucket.min(SAVINGS_BUCKETS - 1)] += 1;
+        self.by_format.entry(format).or_default().add(stats);
+        self.by_directory.entry(directory).or_default().add(stats);
+    }
+}
+
+impl BatchTotals {
+    fn add(&mut self, stats: &CompressionStats) {
+        self.files += 1;
+        self.original_bytes += stats.original_bytes;
+        self.compressed_bytes += stats.compressed_bytes;
+    }
+
+    pub fn savings_percent(&self) -> f64 {
+        if self.original_bytes > 0 {
+            (1.0 - self.compressed_bytes as f64 / self.original_bytes as f64) * 100.0
+        } else {
+            0.0
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Public API
+// ---------------------------------------------------------------------------
+
+/// An encoder that can stand in for the built-in one of an output format; see
+/// [`compress_image_file_with_encoder`]
+pub trait ImageEncoder {
+    /// Encode `image`, which already has trim, resize and watermark applied
+    fn encode(&self, image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>>;
+}
+
+/// The built-in encoders, e.g. for a custom encoder to fall back on. Source
+/// metadata doesn't pass through this trait, so none is embedded.
+impl ImageEncoder for OutputFormat {
+    fn encode(&self, image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>> {
+        encode_image(image, *self, options, &SourceMetadata::default())
+    }
+}
+
+pub fn compress_image_file(
+    input: &Path,
+    output: &Path,
+    options: &CompressOptions,
+) -> Result<CompressionStats> {
+    compress_image_file_with_encoder(input, output, options, None)
+}
+
+/// [`compress_image_file`], with `encoder` (if any) replacing the built-in
+/// encoder of the format the output extension selects. Pixel options and the
+/// quality checks still apply; source metadata is not carried, and PNG input
+/// is decoded rather than passed straight to oxipng.
+pub fn compress_image_file_with_encoder(
+    input: &Path,
+    output: &Path,
+    options: &CompressOptions,
+    encoder: Option<&dyn ImageEncoder>,
+) -> Result<CompressionStats> {
+    let (compressed, stats) = encode_file(input, output, options, encoder)?;
+    if stats.kept_original.is_none() {
+        write_output(output, &compressed, options)?;
+    }
+    Ok(stats)
+}
+
+/// Everything [`compress_image_file_with_encoder`] does except the write:
+/// returns the encoded bytes for `output` and their stats
+fn encode_file(
+    input: &Path,
+    output: &Path,
+    options: &CompressOptions,
+    encoder: Option<&dyn ImageEncoder>,
+) -> Result<(Vec<u8>, CompressionStats)> {
+    validate_input_and_output(input, output, options)?;
+    let input_bytes = open_input(input, options.io_retries)?;
+    encode_input(input, &input_bytes, output, options, encoder)
+}
+
+/// [`encode_file`] for an input that has already been validated and read
+fn encode_input(
+    input: &Path,
+    input_bytes: &[u8],
+    output: &Path,
+    options: &CompressOptions,
+    encoder: Option<&dyn ImageEncoder>,
+) -> Result<(Vec<u8>, CompressionStats)> {
+    let ext = output
+        .extension()
+        .and_then(|v| v.to_str())
+        .context("output path must include a file extension")?;
+    let format = OutputFormat::from_extension(ext)?;
+    let sidecar_xmp = read_xmp_sidecar(input, options)?;
+
+    let (compressed, stats) = compress_source(
+        SourceImage::Encoded(input_bytes),
+        format,
+        options,
+        &input.display().to_string(),
+        encoder,
+        sidecar_xmp.as_deref(),
+    )?;
+
+    Ok((compressed, stats))
+}
+
+/// Compress an image that is already in memory, returning the encoded output.
+pub fn compress_bytes(
+    input: &[u8],
+    format: OutputFormat,
+    options: &CompressOptions,
+) -> Result<Vec<u8>> {
+    compress_bytes_with_stats(input, format, options).map(|(bytes, _)| bytes)
+}
+
+/// Like [`compress_bytes`], but also returns the same [`CompressionStats`]
+/// that [`compress_image_file`] reports.
+///
+/// When `stats.kept_original` is set, the caller should keep using `input`;
+/// the returned bytes are the rejected encode.
+pub fn compress_bytes_with_stats(
+    input: &[u8],
+    format: OutputFormat,
+    options: &CompressOptions,
+) -> Result<(Vec<u8>, CompressionStats)> {
+    if input.is_empty() {
+        bail!("input is empty");
+    }
+    compress_in_memory(input, format, options, "in-memory input")
+}
+
+/// Compress raw RGBA8 pixels (row-major, no padding) and write them to
+/// `output`, for callers that render or generate images instead of reading
+/// files.
+///
+/// Trim, resize and watermark apply as for decoded files. There is no source
+/// metadata to carry, and `stats.original_bytes` is the size of `pixels`.
+/// `compare_original` is an error, as there is no original file to keep.
+pub fn compress_raw_rgba8(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    output: &Path,
+    format: OutputFormat,
+    options: &CompressOptions,
+) -> Result<CompressionStats> {
+    if width == 0 || height == 0 {
+        bail!("raw image must be at least 1x1, got {width}x{height}");
+    }
+    let expected = u64::from(width) * u64::from(height) * 4;
+    if pixels.len() as u64 != expected {
+        bail!(
+            "raw RGBA8 buffer is {} bytes, expected {expected} for {width}x{height}",
+            pixels.len()
+        );
+    }
+    if options.compare_original.is_some() {
+        bail!("compare_original has no original file to keep for raw RGBA8 input");
+    }
+    validate_output(output, options)?;
+
+    let image = RgbaImage::from_raw(width, height, pixels.to_vec())
+        .context("raw RGBA8 buffer does not match its dimensions")?;
+    let (compressed, stats) = compress_source(
+        SourceImage::Decoded(DynamicImage::ImageRgba8(image)),
+        format,
+        options,
+        "raw RGBA8 input",
+        None,
+        None,
+    )?;
+    if stats.kept_original.is_none() {
+        write_output(output, &compressed, options)?;
+    }
+
+    Ok(stats)
+}
+
+/// Side length of the square-ish proxy used by [`estimate_output_size`]
+const ESTIMATE_PROXY_SIDE: u32 = 256;
+
+/// Predict the output size without a full-resolution encode.
+///
+/// This is an estimate, not a guarantee. The image is decoded (and resized if
+/// requested), downscaled to a small proxy, encoded with the real encoder and
+/// settings, and the result is scaled up by pixel count. Images already at or
+/// below proxy size are encoded in full, so their estimate is exact. Expect
+/// larger errors for PNG and lossless modes, where downscaling changes how
+/// compressible the content is.
+pub fn estimate_output_size(
+    input: &Path,
+    format: OutputFormat,
+    options: &CompressOptions,
+) -> Result<u64> {
+    let input_bytes = read_input(input, options.io_retries)?;
+    let image = decode_and_resize(&input_bytes, options, &mut StageTimings::default())?;
+    estimate_encoded_size(&image, format, options)
+}
+
+/// Decode and resize exactly as compression would, returning the RGBA8 pixels
+/// the encoders start from. Meant for debugging color issues.
+pub fn decode_to_rgba8(input: &Path, options: &CompressOptions) -> Result<RgbaImage> {
+    let input_bytes = read_input(input, options.io_retries)?;
+    Ok(decode_and_resize(&input_bytes, options, &mut StageTimings::default())?.to_rgba8())
+}
+
+/// Read an image's format, dimensions, color type and metadata sizes without
+/// decoding its pixels, e.g. to decide whether and how to compress it
+pub fn probe_image(input: &Path) -> Result<ImageInfo> {
+    probe_bytes(input, &open_input(input, 0)?)
+}
+
+/// [`probe_image`] plus a count of the distinct colors, which tells graphics
+/// (suited to lossless PNG/WebP) from photos (suited to lossy). This decodes
+/// the pixels; counting stops early past [`UNIQUE_COLORS_LIMIT`].
+pub fn probe_image_colors(input: &Path) -> Result<ImageInfo> {
+    let input_bytes = open_input(input, 0)?;
+    let mut info = probe_bytes(input, &input_bytes)?;
+    let image = image::load_from_memory(&input_bytes)
+        .with_context(|| format!("failed to decode image: {}", input.display()))?;
+    info.unique_colors = unique_colors_up_to(&image.to_rgba8(), UNIQUE_COLORS_LIMIT as usize)
+        .map(|count| count as u64);
+    Ok(info)
+}
+
+fn probe_bytes(input: &Path, input_bytes: &[u8]) -> Result<ImageInfo> {
+    use image::ImageDecoder;
+
+    let reader = image::ImageReader::new(Cursor::new(input_bytes)).with_guessed_format()?;
+    let format = reader
+        .format()
+        .with_context(|| format!("unrecognized image format: {}", input.display()))?;
+    let decoder = reader
+        .into_decoder()
+        .with_context(|| format!("failed to read image header: {}", input.display()))?;
+    let (width, height) = decoder.dimensions();
+    let color = decoder.color_type();
+    let metadata = read_source_metadata(input_bytes);
+    let block_len = |block: &Option<Vec<u8>>| block.as_ref().map_or(0, |b| b.len() as u64);
+
+    Ok(ImageInfo {
+        format: format!("{format:?}").to_lowercase(),
+        width,
+        height,
+        file_bytes: input_bytes.len() as u64,
+        color: format!("{color:?}"),
+        has_alpha: color.has_alpha(),
+        exif_bytes: block_len(&metadata.exif),
+        xmp_bytes: block_len(&metadata.xmp),
+        icc_bytes: metadata.icc_len(),
+        unique_colors: None,
+    })
+}
+
+/// Name of the resume state file kept in the output directory
+pub const RESUME_STATE_FILE: &str = ".image-compressor-state.jsonl";
+
+pub fn compress_directory(
+    input_dir: &Path,
+    output_dir: &Path,
+    to_extension: &str,
+    options: &CompressOptions,
+    recursive: bool,
+) -> Result<BatchReport> {
+    let batch = BatchOptions {
+        recursive,
+        ..BatchOptions::default()
+    };
+    compress_directory_with_options(input_dir, output_dir, to_extension, options, &batch)
+}
+
+pub fn compress_directory_with_options(
+    input_dir: &Path,
+    output_dir: &Path,
+    to_extension: &str,
+    options: &CompressOptions,
+    batch: &BatchOptions,
+) -> Result<BatchReport> {
+    compress_directory_with_progress(input_dir, output_dir, to_extension, options, batch, |_| {})
+}
+
+/// [`compress_directory_with_options`] that reports each file's progress to
+/// `on_event` as the batch runs.
+pub fn compress_directory_with_progress(
+    input_dir: &Path,
+    output_dir: &Path,
+    to_extension: &str,
+    options: &CompressOptions,
+    batch: &BatchOptions,
+    mut on_event: impl FnMut(BatchEvent<'_>),
+) -> Result<BatchReport> {
+    if !input_dir.is_dir() {
+        bail!("input directory not found: {}", input_dir.display());
+    }
+
+    fs::create_dir_all(output_dir).with_context(|| {
+        format!(
+            "failed to create output directory: {}",
+            output_dir.display()
+        )
+    })?;
+
+    let to_extension = normalize_extension(to_extension)?;
+    let files = batch_input_files(input_dir, batch)?;
+    let started = Instant::now();
+    let mut report = BatchReport::default();
+
+    let state_path = output_dir.join(RESUME_STATE_FILE);
+    let completed = if batch.resume {
+        load_resume_state(&state_path)?
+    } else {
+        HashMap::new()
+    };
+    let mut manifest = BTreeMap::new();
+
+    let total_files = files.len();
+    for (index, source_path) in files.into_iter().enumerate() {
+        if let Some(budget) = batch.total_budget
+            && report.total_compressed_bytes >= budget
+        {
+            report.not_processed = total_files - index;
+            break;
+        }
+
+        let Ok(relative_path) = source_path.strip_prefix(input_dir) else {
+            on_event(BatchEvent::Failed {
+                source: &source_path,
+                error: "source is outside the input directory".to_string(),
+            });
+            report.failed += 1;
+            continue;
+        };
+
+        let target_path = batch_target_path(
+            output_dir,
+            &source_path,
+            relative_path,
+            &to_extension,
+            batch,
+        );
+
+        if unchanged_since(&source_path, batch) {
+            on_event(BatchEvent::Skipped {
+                source: &source_path,
+                target: &target_path,
+            });
+            report.skipped_unchanged += 1;
+            continue;
+        }
+        if batch.copy_non_images && is_non_image(&source_path) {
+            let copy_path = verbatim_target(&target_path, &source_path, batch);
+            if copy_path.exists() && !options.overwrite {
+                on_event(BatchEvent::Skipped {
+                    source: &source_path,
+                    target: &copy_path,
+                });
+                report.skipped += 1;
+                continue;
+            }
+            if let Some(parent) = copy_path.parent() {
+                fs::create_dir_all(parent).ok();
+            }
+            // Read back only when the manifest needs the copy's hash
+            let copied = fs::copy(&source_path, &copy_path).and_then(|bytes| {
+                if batch.manifest.is_some() {
+                    let contents = fs::read(&copy_path)?;
+                    record_manifest_entry(&mut manifest, output_dir, &copy_path, &contents);
+                }
+                Ok(bytes)
+            });
+            match copied {
+                Ok(bytes) => {
+                    on_event(BatchEvent::Copied {
+                        source: &source_path,
+                        target: &copy_path,
+                        bytes,
+                    });
+                    println!(
+                        "copied {} \u{2192} {}",
+                        relative_path.display(),
+                        copy_path.display()
+                    );
+                    report.copied += 1;
+                }
+                Err(err) => {
+                    eprintln!("failed {}: {err}", source_path.display());
+                    on_event(BatchEvent::Failed {
+                        source: &source_path,
+                        error: err.to_string(),
+                    });
+                    report.failed += 1;
+                }
+            }
+            continue;
+        }
+        if skip_as_marked(&source_path, &target_path, options, batch, relative_path) {
+            on_event(BatchEvent::Skipped {
+                source: &source_path,
+                target: &target_path,
+            });
+            report.skipped_already_optimized += 1;
+            continue;
+        }
+
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).ok();
+        }
+
+        // With --resume the state file, not the target's existence, decides
+        // what is done: a target without a state entry may be a partial write.
+        let mut file_options = batch_file_options(options, batch, relative_path);
+        let mut source_hash = None;
+        // The bytes hashed for --resume are the ones encoded, so the source is read once
+        let mut opened = None;
+        if batch.resume {
+            let key = relative_path.to_string_lossy().into_owned();
+            let input_bytes = match open_input(&source_path, options.io_retries) {
+                Ok(input_bytes) => input_bytes,
+                Err(err) => {
+                    eprintln!("failed {}: {err:#}", source_path.display());
+                    on_event(BatchEvent::Failed {
+                        source: &source_path,
+                        error: format!("{err:#}"),
+                    });
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            let hash = sha256_hex(&input_bytes);
+            if completed.get(&key) == Some(&hash) && target_path.exists() {
+                on_event(BatchEvent::Skipped {
+                    source: &source_path,
+                    target: &target_path,
+                });
+                report.skipped += 1;
+                continue;
+            }
+            file_options.overwrite = true;
+            source_hash = Some((key, hash));
+            opened = Some(input_bytes);
+        } else if batch.overwrite_newer_only && target_path.exists() {
+            if !source_is_newer(&source_path, &target_path) {
+                on_event(BatchEvent::Skipped {
+                    source: &source_path,
+                    target: &target_path,
+                });
+                report.skipped_uptodate += 1;
+                continue;
+            }
+            file_options.overwrite = true;
+        } else if target_path.exists() && !options.overwrite {
+            on_event(BatchEvent::Skipped {
+                source: &source_path,
+                target: &target_path,
+            });
+            report.skipped += 1;
+            continue;
+        }
+        // Decodes and test-encodes the source, so only for files that would
+        // otherwise be encoded
+        if skip_as_optimized(&source_path, &target_path, options, batch, relative_path) {
+            on_event(BatchEvent::Skipped {
+                source: &source_path,
+                target: &target_path,
+            });
+            report.skipped_optimized += 1;
+            continue;
+        }
+
+        // Display only; all IO goes through the paths themselves
+        let source_name = source_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let target_name = target_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+
+        on_event(BatchEvent::Started {
+            source: &source_path,
+            target: &target_path,
+        });
+        let encoded = match &opened {
+            Some(input_bytes) => {
+                validate_input_and_output(&source_path, &target_path, &file_options).and_then(
+                    |()| encode_input(&source_path, input_bytes, &target_path, &file_options, None),
+                )
+            }
+            None => encode_file(&source_path, &target_path, &file_options, None),
+        };
+        let result = encoded.and_then(|encoded| {
+            let (compressed, stats) = &encoded;
+            let identical = batch.skip_identical
+                && stats.kept_original.is_none()
+                && output_is_identical(&target_path, compressed);
+            if stats.kept_original.is_none() && !identical {
+                write_output(&target_path, compressed, &file_options)?;
+            }
+            Ok((encoded, identical))
+        });
+        match result {
+            Ok(((compressed, stats), identical)) => {
+                if let Some((key, hash)) = source_hash {
+                    append_resume_state(&state_path, &key, &hash)?;
+                }
+                if identical {
+                    on_event(BatchEvent::Skipped {
+                        source: &source_path,
+                        target: &target_path,
+                    });
+                    println!("unchanged {target_name} (identical to the existing output)");
+                    report.skipped_identical += 1;
+                    continue;
+                }
+                if batch.manifest.is_some() && stats.kept_original.is_none() {
+                    record_manifest_entry(&mut manifest, output_dir, &target_path, &compressed);
+                }
+                on_event(BatchEvent::Done {
+                    source: &source_path,
+                    target: &target_path,
+                    stats,
+                });
+                print_file_result(
+                    &source_name,
+                    &target_name,
+                    &stats,
+                    batch.size_units,
+                    batch.verbose,
+                );
+
+                let format_key = target_path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .and_then(|ext| OutputFormat::from_extension(ext).ok())
+                    .map_or_else(|| to_extension.clone(), |format| format.to_string());
+                report.record(&stats, format_key, top_level_directory(relative_path));
+            }
+            Err(err) => {
+                eprintln!("failed {}: {err:#}", source_name);
+                on_event(BatchEvent::Failed {
+                    source: &source_path,
+                    error: format!("{err:#}"),
+                });
+                report.failed += 1;
+            }
+        }
+    }
+
+    if let Some(path) = &batch.manifest {
+        write_manifest(path, &manifest)?;
+    }
+
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+/// Compress every image entry of a ZIP archive into `output_dir`, keeping the
+/// archive's internal paths. Entries are decoded from memory, so nothing is
+/// unzipped to disk; non-image entries are counted as skipped.
+#[cfg(feature = "zip")]
+pub fn compress_zip_archive(
+    archive: &Path,
+    output_dir: &Path,
+    to_extension: &str,
+    options: &CompressOptions,
+    size_units: SizeUnits,
+) -> Result<BatchReport> {
+    use std::io::Read;
+
+    let file = fs::File::open(archive)
+        .with_context(|| format!("failed to open archive: {}", archive.display()))?;
+    let mut zip = zip::ZipArchive::new(file)
+        .with_context(|| format!("failed to read ZIP archive: {}", archive.display()))?;
+
+    let to_extension = normalize_extension(to_extension)?;
+    let format = OutputFormat::from_extension(&to_extension)?;
+    let started = Instant::now();
+    let mut report = BatchReport::default();
+
+    for index in 0..zip.len() {
+        let mut entry = zip
+            .by_index(index)
+            .with_context(|| format!("failed to read entry {index} of {}", archive.display()))?;
+        if !entry.is_file() {
+            continue;
+        }
+        // Entries with absolute or `..` paths are never written outside output_dir
+        let Some(relative_path) = entry.enclosed_name() else {
+            eprintln!("failed {}: unsafe path in archive", entry.name());
+            report.failed += 1;
+            continue;
+        };
+
+        // The declared size comes from the archive and may be forged, so
+        // `read_to_end` grows the buffer from what is actually there
+        let mut input_bytes = Vec::new();
+        if let Err(err) = entry.read_to_end(&mut input_bytes) {
+            eprintln!("failed {}: {err}", relative_path.display());
+            report.failed += 1;
+            continue;
+        }
+        if image::guess_format(&input_bytes).is_err() {
+            report.skipped += 1;
+            continue;
+        }
+
+        let target_path = with_name_extension(&output_dir.join(&relative_path), &to_extension);
+        if target_path.exists() && !options.overwrite {
+            report.skipped += 1;
+            continue;
+        }
+
+        let source_label = relative_path.display().to_string();
+        let result = compress_in_memory(&input_bytes, format, options, &source_label).and_then(
+            |(compressed, stats)| {
+                if stats.kept_original.is_some() {
+                    return Ok(stats);
+                }
+                if let Some(parent) = target_path.parent() {
+                    fs::create_dir_all(parent).with_context(|| {
+                        format!("failed to create directory: {}", parent.display())
+                    })?;
+                }
+                write_output(&target_path, &compressed, options)?;
+                Ok(stats)
+            },
+        );
+
+        match result {
+            Ok(stats) => {
+                print_file_result(
+                    &source_label,
+                    &target_path.display().to_string(),
+                    &stats,
+                    size_units,
+                    false,
+                );
+                report.record(
+                    &stats,
+                    format.to_string(),
+                    top_level_directory(&relative_path),
+                );
+            }
+            Err(err) => {
+                eprintln!("failed {source_label}: {err:#}");
+                report.failed += 1;
+            }
+        }
+    }
+
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+/// Parse a quality map: a TOML table of `"file name or relative path" = quality`.
+pub fn parse_quality_map(text: &str) -> Result<BTreeMap<String, u8>> {
+    let table: toml::Table = text.parse().context("invalid quality map")?;
+    table
+        .into_iter()
+        .map(|(name, value)| {
+            let quality = value
+                .as_integer()
+                .filter(|quality| (1..=100).contains(quality))
+                .with_context(|| {
+                    format!("quality for {name:?} must be an integer from 1 to 100")
+                })?;
+            Ok((name, quality as u8))
+        })
+        .collect()
+}
+
+/// Parse a `--since` value: an RFC 3339 timestamp (`2024-05-01T08:00:00Z`,
+/// `2024-05-01T10:00:00+02:00` or just `2024-05-01`, read as UTC midnight), or
+/// a duration before now such as `90m`, `24h`, `7d` or `2w`.
+pub fn parse_since(value: &str) -> Result<SystemTime> {
+    let value = value.trim();
+    if let Some(timestamp) = parse_rfc3339(value) {
+        return Ok(timestamp);
+    }
+    let ago = parse_duration(value).with_context(|| {
+        format!("invalid time {value:?} (use RFC 3339 like 2024-05-01T08:00:00Z, or 24h, 7d)")
+    })?;
+    SystemTime::now()
+        .checked_sub(ago)
+        .context("duration reaches before the system clock's range")
+}
+
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3_600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+fn parse_rfc3339(value: &str) -> Option<SystemTime> {
+    let field = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = value.get(range)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    if value.get(4..5)? != "-" || value.get(7..8)? != "-" {
+        return None;
+    }
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut seconds = days_from_civil(year, month, day) * 86_400;
+    if value.len() > 10 {
+        if !matches!(value.get(10..11)?, "T" | "t" | " ")
+            || value.get(13..14)? != ":"
+            || value.get(16..17)? != ":"
+        {
+            return None;
+        }
+        let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+        if hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        seconds += hour * 3_600 + minute * 60 + second;
+
+        // Fractional seconds are dropped; mtimes are compared at second precision
+        let mut rest = &value[19..];
+        if let Some(fraction) = rest.strip_prefix('.') {
+            let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+            if digits == 0 {
+                return None;
+            }
+            rest = &fraction[digits..];
+        }
+        seconds -= match rest {
+            "Z" | "z" => 0,
+            offset if offset.len() == 6 && offset.get(3..4) == Some(":") => {
+                let sign = match offset.get(0..1)? {
+                    "+" => 1,
+                    "-" => -1,
+                    _ => return None,
+                };
+                let hours: i64 = offset.get(1..3)?.parse().ok()?;
+                let minutes: i64 = offset.get(4..6)?.parse().ok()?;
+                sign * (hours * 3_600 + minutes * 60)
+            }
+            _ => return None,
+        };
+    }
+
+    let seconds = u64::try_from(seconds).ok()?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's
+/// `days_from_civil`)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of [`days_from_civil`]: `(year, month, day)` for days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// Parse a batch rules file: a TOML list of `[[rule]]` tables, each with a
+/// `prefix` and any of `to`, `quality`, `lossless`, `progressive`,
+/// `png_level`, `avif_speed` and `keep_metadata`.
+pub fn parse_batch_rules(text: &str) -> Result<Vec<BatchRule>> {
+    let mut table: toml::Table = text.parse().context("invalid rules file")?;
+    let rules = match table.remove("rule") {
+        Some(toml::Value::Array(rules)) => rules,
+        Some(_) => bail!("`rule` must be a list of [[rule]] tables"),
+        None => Vec::new(),
+    };
+    if let Some(key) = table.keys().next() {
+        bail!("unknown top-level key {key:?} (expected [[rule]] tables)");
+    }
+    rules
+        .into_iter()
+        .enumerate()
+        .map(|(index, rule)| {
+            parse_batch_rule(rule).with_context(|| format!("in rule {}", index + 1))
+        })
+        .collect()
+}
+
+fn parse_batch_rule(value: toml::Value) -> Result<BatchRule> {
+    let toml::Value::Table(table) = value else {
+        bail!("rule must be a table");
+    };
+    let int = |key: &str, value: &toml::Value, range: std::ops::RangeInclusive<i64>| {
+        value
+            .as_integer()
+            .filter(|n| range.contains(n))
+            .map(|n| n as u8)
+            .with_context(|| {
+                format!(
+                    "`{key}` must be an integer from {} to {}",
+                    range.start(),
+                    range.end()
+                )
+            })
+    };
+    let boolean = |key: &str, value: &toml::Value| {
+        value
+            .as_bool()
+            .with_context(|| format!("`{key}` must be true or false"))
+    };
+
+    let mut prefix = None;
+    let mut rule = BatchRule::default();
+    for (key, value) in &table {
+        match key.as_str() {
+            "prefix" => {
+                prefix = Some(
+                    value
+                        .as_str()
+                        .context("`prefix` must be a string")?
+                        .to_string(),
+                );
+            }
+            "to" => {
+                let extension =
+                    normalize_extension(value.as_str().context("`to` must be a string")?)?;
+                OutputFormat::from_extension(&extension)?;
+                rule.to = Some(extension);
+            }
+            "quality" => rule.quality = Some(int(key, value, 1..=100)?),
+            "png_level" => rule.png_level = Some(int(key, value, 1..=6)?),
+            "avif_speed" => rule.avif_speed = Some(int(key, value, 1..=10)?),
+            "lossless" => rule.lossless = Some(boolean(key, value)?),
+            "progressive" => rule.progressive = Some(boolean(key, value)?),
+            "keep_metadata" => rule.keep_metadata = Some(boolean(key, value)?),
+            other => bail!("unknown key {other:?}"),
+        }
+    }
+    rule.prefix = prefix.context("rule is missing `prefix`")?;
+    Ok(rule)
+}
+
+/// List what [`compress_directory_with_options`] would do with the same
+/// arguments, without compressing or writing anything.
+pub fn plan_batch(
+    input_dir: &Path,
+    output_dir: &Path,
+    to_extension: &str,
+    options: &CompressOptions,
+    batch: &BatchOptions,
+) -> Result<Vec<PlannedFile>> {
+    if !input_dir.is_dir() {
+        bail!("input directory not found: {}", input_dir.display());
+    }
+
+    let to_extension = normalize_extension(to_extension)?;
+    let completed = if batch.resume {
+        load_resume_state(&output_dir.join(RESUME_STATE_FILE))?
+    } else {
+        HashMap::new()
+    };
+
+    let mut plan = Vec::new();
+    for source in batch_input_files(input_dir, batch)? {
+        let Ok(relative_path) = source.strip_prefix(input_dir) else {
+            continue;
+        };
+        let target = batch_target_path(output_dir, &source, relative_path, &to_extension, batch);
+        let copy = batch.copy_non_images && is_non_image(&source);
+        let target = if copy {
+            verbatim_target(&target, &source, batch)
+        } else {
+            target
+        };
+
+        let skip = if unchanged_since(&source, batch) {
+            true
+        } else if copy {
+            target.exists() && !options.overwrite
+        } else if skip_as_optimized(&source, &target, options, batch, relative_path) {
+            true
+        } else if batch.resume {
+            let key = relative_path.to_string_lossy();
+            target.exists()
+                && hash_file(&source).is_ok_and(|hash| completed.get(key.as_ref()) == Some(&hash))
+        } else if batch.overwrite_newer_only {
+            target.exists() && !source_is_newer(&source, &target)
+        } else {
+            target.exists() && !options.overwrite
+        };
+
+        let detected_format = image::ImageReader::open(&source)
+            .ok()
+            .and_then(|reader| reader.with_guessed_format().ok())
+            .and_then(|reader| reader.format());
+
+        plan.push(PlannedFile {
+            source,
+            target,
+            detected_format,
+            skip,
+            copy,
+        });
+    }
+
+    Ok(plan)
+}
+
+/// Project what a batch run would save without writing anything: every file
+/// [`plan_batch`] would compress is sized with [`estimate_output_size`]
+/// (a downscaled proxy encode) instead of being encoded in full.
+///
+/// The report has the same shape as a real run's, but its compressed sizes
+/// are estimates; files the estimate can't size count as failed.
+pub fn estimate_batch(
+    input_dir: &Path,
+    output_dir: &Path,
+    to_extension: &str,
+    options: &CompressOptions,
+    batch: &BatchOptions,
+) -> Result<BatchReport> {
+    let started = Instant::now();
+    let plan = plan_batch(input_dir, output_dir, to_extension, options, batch)?;
+    let mut report = BatchReport::default();
+
+    let total_files = plan.len();
+    for (index, file) in plan.into_iter().enumerate() {
+        if let Some(budget) = batch.total_budget
+            && report.total_compressed_bytes >= budget
+        {
+            report.not_processed = total_files - index;
+            break;
+        }
+        if file.skip {
+            report.skipped += 1;
+            continue;
+        }
+        if file.copy {
+            report.copied += 1;
+            continue;
+        }
+
+        let relative_path = file.source.strip_prefix(input_dir).unwrap_or(&file.source);
+        let file_options = batch_file_options(options, batch, relative_path);
+        let estimate = file
+            .target
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .context("output path must include a file extension")
+            .and_then(OutputFormat::from_extension)
+            .and_then(|format| {
+                let original_bytes = fs::metadata(&file.source)?.len();
+                let compressed_bytes = estimate_output_size(&file.source, format, &file_options)?;
+                Ok((format, original_bytes, compressed_bytes))
+            });
+        match estimate {
+            Ok((format, original_bytes, compressed_bytes)) => {
+                let stats = CompressionStats {
+                    original_bytes,
+                    compressed_bytes,
+                    savings_percent: if original_bytes > 0 {
+                        (1.0 - compressed_bytes as f64 / original_bytes as f64) * 100.0
+                    } else {
+                        0.0
+                    },
+                    ..CompressionStats::default()
+                };
+                report.record(
+                    &stats,
+                    format.to_string(),
+                    top_level_directory(relative_path),
+                );
+            }
+            Err(err) => {
+                eprintln!("failed {}: {err:#}", file.source.display());
+                report.failed += 1;
+            }
+        }
+    }
+
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+/// Like [`compress_directory_with_options`], but every result is written into
+/// a single ZIP archive at `archive` (relative paths preserved) instead of an
+/// output directory. `batch.resume` is not supported here.
+#[cfg(feature = "zip")]
+pub fn compress_directory_to_zip(
+    input_dir: &Path,
+    archive: &Path,
+    to_extension: &str,
+    options: &CompressOptions,
+    batch: &BatchOptions,
+) -> Result<BatchReport> {
+    if !input_dir.is_dir() {
+        bail!("input directory not found: {}", input_dir.display());
+    }
+    if batch.resume {
+        bail!("resume is not supported when writing a ZIP archive");
+    }
+    if archive.exists() && !options.overwrite {
+        bail!(
+            "output file exists (use --overwrite to replace): {}",
+            archive.display()
+        );
+    }
+    if let Some(parent) = archive.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    }
+
+    let to_extension = normalize_extension(to_extension)?;
+    let files = batch_input_files(input_dir, batch)?;
+    let started = Instant::now();
+    let mut report = BatchReport::default();
+
+    let write_path = if options.atomic_write {
+        temp_path_for(archive, options.temp_dir.as_deref())?
+    } else {
+        archive.to_path_buf()
+    };
+    let file = fs::File::create(&write_path)
+        .with_context(|| format!("failed to create archive: {}", write_path.display()))?;
+    let mut zip = zip::ZipWriter::new(file);
+    // The entries are already compressed images; deflating them again only costs time
+    let entry_options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+    let result = (|| -> Result<()> {
+        let total_files = files.len();
+        for (index, source_path) in files.into_iter().enumerate() {
+            if let Some(budget) = batch.total_budget
+                && report.total_compressed_bytes >= budget
+            {
+                report.not_processed = total_files - index;
+                break;
+            }
+
+            let Ok(relative_path) = source_path.strip_prefix(input_dir) else {
+                report.failed += 1;
+                continue;
+            };
+            if unchanged_since(&source_path, batch) {
+                report.skipped_unchanged += 1;
+                continue;
+            }
+            let target_path =
+                batch_relative_target(&source_path, relative_path, &to_extension, batch);
+            if batch.copy_non_images && is_non_image(&source_path) {
+                let copy_path = verbatim_target(&target_path, &source_path, batch);
+                let entry_name = copy_path
+                    .components()
+                    .map(|part| part.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let bytes = fs::read(&source_path).with_context(|| {
+                    format!("failed to read input file: {}", source_path.display())
+                })?;
+                zip.start_file(entry_name.as_str(), entry_options)
+                    .with_context(|| format!("failed to add {entry_name} to archive"))?;
+                zip.write_all(&bytes)
+                    .with_context(|| format!("failed to add {entry_name} to archive"))?;
+                println!("copied {} \u{2192} {entry_name}", relative_path.display());
+                report.copied += 1;
+                continue;
+            }
+            if skip_as_optimized(&source_path, &target_path, options, batch, relative_path) {
+                report.skipped_optimized += 1;
+                continue;
+            }
+            if skip_as_marked(&source_path, &target_path, options, batch, relative_path) {
+                report.skipped_already_optimized += 1;
+                continue;
+            }
+
+            let source_name = relative_path.display().to_string();
+            let compressed = target_path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .context("output path must include a file extension")
+                .and_then(OutputFormat::from_extension)
+                .and_then(|format| {
+                    let input_bytes = read_input(&source_path, options.io_retries)?;
+                    let file_options = batch_file_options(options, batch, relative_path);
+                    let sidecar_xmp = read_xmp_sidecar(&source_path, &file_options)?;
+                    let (bytes, stats) = compress_source(
+                        SourceImage::Encoded(&input_bytes),
+                        format,
+                        &file_options,
+                        &source_name,
+                        None,
+                        sidecar_xmp.as_deref(),
+                    )?;
+                    Ok((format, bytes, stats))
+                });
+
+            match compressed {
+                Ok((format, bytes, stats)) => {
+                    // ZIP entry names always use forward slashes
+                    let entry_name = target_path
+                        .components()
+                        .map(|part| part.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    if stats.kept_original.is_none() {
+                        zip.start_file(entry_name.as_str(), entry_options)
+                            .with_context(|| format!("failed to add {entry_name} to archive"))?;
+                        zip.write_all(&bytes)
+                            .with_context(|| format!("failed to add {entry_name} to archive"))?;
+                    }
+
+                    print_file_result(
+                        &source_name,
+                        &entry_name,
+                        &stats,
+                        batch.size_units,
+                        batch.verbose,
+                    );
+                    report.record(
+                        &stats,
+                        format.to_string(),
+                        top_level_directory(relative_path),
+                    );
+                }
+                Err(err) => {
+                    eprintln!("failed {source_name}: {err:#}");
+                    report.failed += 1;
+                }
+            }
+        }
+
+        zip.finish()
+            .with_context(|| format!("failed to finish archive: {}", write_path.display()))?;
+        if write_path != archive {
+            move_into_place(&write_path, archive, 0)?;
+        }
+        Ok(())
+    })();
+
+    if result.is_err() && write_path != archive {
+        fs::remove_file(&write_path).ok();
+    }
+    report.elapsed = started.elapsed();
+    result.map(|()| report)
+}
+
+fn per_second(amount: f64, elapsed: Duration) -> f64 {
+    let seconds = elapsed.as_secs_f64();
+    if seconds > 0.0 { amount / seconds } else { 0.0 }
+}
+
+// ---------------------------------------------------------------------------
+// Format-specific encoders
+// ---------------------------------------------------------------------------
+
+fn compress_in_memory(
+    input_bytes: &[u8],
+    format: OutputFormat,
+    options: &CompressOptions,
+    source_label: &str,
+) -> Result<(Vec<u8>, CompressionStats)> {
+    compress_source(
+        SourceImage::Encoded(input_bytes),
+        format,
+        options,
+        source_label,
+        None,
+        None,
+    )
+}
+
+/// The `xmp_sidecar` file for `input`: `photo.jpg.xmp`, else `photo.xmp`.
+/// `None` when the option is off or neither exists (with a warning).
+fn read_xmp_sidecar(input: &Path, options: &CompressOptions) -> Result<Option<Vec<u8>>> {
+    if !options.xmp_sidecar {
+        return Ok(None);
+    }
+    let mut full_name = input.as_os_str().to_os_string();
+    full_name.push(".xmp");
+    for sidecar in [PathBuf::from(full_name), input.with_extension("xmp")] {
+        match fs::read(&sidecar) {
+            Ok(xmp) => return Ok(Some(xmp)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read XMP sidecar: {}", sidecar.display()));
+            }
+        }
+    }
+    eprintln!("warning: no XMP sidecar for {}", input.display());
+    Ok(None)
+}
+
+/// Input to [`compress_source`]
+enum SourceImage<'a> {
+    /// File contents in any format the decoder reads
+    Encoded(&'a [u8]),
+    /// Pixels that never had an encoded form
+    Decoded(DynamicImage),
+}
+
+impl SourceImage<'_> {
+    /// Decode if needed, then apply crop, trim, resize and watermark
+    fn into_prepared(
+        self,
+        options: &CompressOptions,
+        timings: &mut StageTimings,
+    ) -> Result<DynamicImage> {
+        match self {
+            SourceImage::Encoded(bytes) => decode_and_resize(bytes, options, timings),
+            SourceImage::Decoded(image) => {
+                let started = Instant::now();
+                let image = prepare_image(crop_image(image, options)?, options);
+                timings.resize += started.elapsed();
+                Ok(image)
+            }
+        }
+    }
+}
+
+fn compress_source(
+    source: SourceImage<'_>,
+    format: OutputFormat,
+    options: &CompressOptions,
+    source_label: &str,
+    encoder: Option<&dyn ImageEncoder>,
+    sidecar_xmp: Option<&[u8]>,
+) -> Result<(Vec<u8>, CompressionStats)> {
+    // Decoded pixels have no file bytes: no metadata, no PNG passthrough
+    let input_bytes: &[u8] = match &source {
+        SourceImage::Encoded(bytes) => bytes,
+        SourceImage::Decoded(_) => &[],
+    };
+    let original_bytes = match &source {
+        SourceImage::Encoded(bytes) => bytes.len() as u64,
+        SourceImage::Decoded(image) => image.as_bytes().len() as u64,
+    };
+
+    let source_metadata = read_source_metadata(input_bytes);
+    let mut carried = if options.strip_metadata {
+        SourceMetadata::default()
+    } else if options.strip_exif_thumbnail {
+        source_metadata.without_exif_thumbnail()
+    } else {
+        source_metadata.clone()
+    };
+    carried.srgb = options.assume_srgb && source_metadata.icc.is_none();
+    if let Some(xmp) = sidecar_xmp {
+        carried.xmp = Some(xmp.to_vec());
+        if format == OutputFormat::Png {
+            eprintln!("warning: PNG output cannot carry XMP, sidecar ignored for {source_label}");
+        }
+    }
+
+    warn_unknown_encoder_opts(options, format, source_label);
+    check_exact_resize_fits(options)?;
+    if options.compare_original.is_some() && format == OutputFormat::Avif {
+        bail!("compare_original needs to decode the output, which this build cannot do for AVIF");
+    }
+
+    // Special case: PNG input → PNG output with no pixel changes — run oxipng directly
+    // PNG output is lossless, so its SSIM against the source is exact
+    let lossless_ssim = options.compare_original.map(|_| 1.0);
+    let keeps_source_metadata = !options.strip_metadata && !source_metadata.is_empty();
+    let mut source_metadata_carried = false;
+    let mut timings = StageTimings::default();
+    let mut adaptive_quality = None;
+    let started = Instant::now();
+    let animation = match &source {
+        SourceImage::Encoded(bytes)
+            if options.apng && format == OutputFormat::Png && encoder.is_none() =>
+        {
+            decode_animation(bytes)?
+        }
+        _ => None,
+    };
+    timings.decode += started.elapsed();
+    let (compressed, metadata_kept, psnr, ssim, (width, height)) = if let Some((frames, plays)) =
+        animation
+    {
+        let ignored = apng_ignored_options(options);
+        if !ignored.is_empty() {
+            eprintln!(
+                "warning: {} not applied to the animated {source_label}",
+                ignored.join(", ")
+            );
+        }
+        let started = Instant::now();
+        let (apng, dimensions) = compress_apng(frames, plays, options)?;
+        timings.encode += started.elapsed();
+        (apng, 0, None, lossless_ssim, dimensions)
+    } else if format == OutputFormat::Png
+        && options.resize.is_none()
+        && options.max_dimension.is_none()
+        && options.crop.is_none()
+        && options.trim.is_none()
+        && options.watermark.is_none()
+        && options.quantize_colors.is_none()
+        && options.png_lossy_quality.is_none()
+        && encoder.is_none()
+    {
+        let is_png = image::guess_format(input_bytes)
+            .map(|f| f == ImageFormat::Png)
+            .unwrap_or(false);
+        if is_png {
+            // oxipng keeps all chunks, or only display-relevant ones (iCCP) when stripping
+            let kept = if options.strip_metadata {
+                source_metadata.icc_len()
+            } else {
+                source_metadata.total_len()
+            };
+            source_metadata_carried = keeps_source_metadata;
+            let dimensions = image::ImageReader::new(Cursor::new(input_bytes))
+                .with_guessed_format()?
+                .into_dimensions()
+                .context("failed to read PNG dimensions")?;
+            let started = Instant::now();
+            let png = compress_png(input_bytes, None, options)?;
+            timings.encode += started.elapsed();
+            (
+                tag_png_srgb(png, &carried),
+                kept,
+                None,
+                lossless_ssim,
+                dimensions,
+            )
+        } else {
+            let image = source.into_prepared(options, &mut timings)?;
+            let dimensions = (image.width(), image.height());
+            let started = Instant::now();
+            let png = compress_png(&[], Some(&image), options)?;
+            timings.encode += started.elapsed();
+            (
+                tag_png_srgb(png, &carried),
+                0,
+                None,
+                lossless_ssim,
+                dimensions,
+            )
+        }
+    } else {
+        let mut image = source.into_prepared(options, &mut timings)?;
+        if let Some(background) = options.background
+            && format == OutputFormat::Jpeg
+            && image.color().has_alpha()
+        {
+            image = flatten_onto(&image, background);
+        }
+        let adaptive_options;
+        let options = match options.adaptive_quality {
+            Some((min, max))
+                if options.quality.is_none() && uses_quality(&image, format, options) =>
+            {
+                let quality = quality_for_complexity(image_complexity(&image), min, max);
+                adaptive_quality = Some(quality);
+                adaptive_options = CompressOptions {
+                    quality: Some(quality),
+                    ..options.clone()
+                };
+                &adaptive_options
+            }
+            _ => options,
+        };
+        if format == OutputFormat::Avif && encoder.is_none() && !carried.is_empty() {
+            eprintln!(
+                "warning: AVIF output cannot carry EXIF/XMP yet, metadata dropped for {source_label}"
+            );
+        }
+        let started = Instant::now();
+        let (compressed, kept) = match encoder {
+            Some(encoder) => (
+                non_empty_output(encoder.encode(&image, options)?, "custom")?,
+                0,
+            ),
+            None => {
+                let kept = match format {
+                    OutputFormat::Jpeg | OutputFormat::WebP => {
+                        source_metadata_carried = keeps_source_metadata;
+                        carried.exif_xmp_len()
+                    }
+                    OutputFormat::Png | OutputFormat::Avif => 0,
+                };
+                (encode_image(&image, format, options, &carried)?, kept)
+            }
+        };
+        timings.encode += started.elapsed();
+        let psnr = match options.min_psnr {
+            Some(min_psnr) => {
+                check_quality(&image, &compressed, format, options, min_psnr, source_label)?
+            }
+            None => None,
+        };
+        let ssim = match options.compare_original {
+            Some(_) => Some(output_ssim(&image, &compressed, format, options)?),
+            None => None,
+        };
+        (
+            compressed,
+            kept,
+            psnr,
+            ssim,
+            (image.width(), image.height()),
+        )
+    };
+    let compressed = if options.mark_output {
+        with_output_marker(compressed, format, options)
+    } else {
+        compressed
+    };
+
+    if let Some(resize) = options.resize
+        && matches!(resize.mode, ResizeMode::Exact | ResizeMode::Pad)
+    {
+        verify_exact_dimensions(&compressed, format, resize)?;
+    }
+
+    let compressed_bytes = compressed.len() as u64;
+    let savings_percent = if original_bytes > 0 {
+        (1.0 - compressed_bytes as f64 / original_bytes as f64) * 100.0
+    } else {
+        0.0
+    };
+
+    let kept_original = options.compare_original.and_then(|min_ssim| {
+        if compressed_bytes >= original_bytes {
+            Some(KeptOriginal::NotSmaller)
+        } else if ssim.is_some_and(|ssim| ssim < min_ssim) {
+            Some(KeptOriginal::BelowSsimFloor)
+        } else {
+            None
+        }
+    });
+
+    let stats = CompressionStats {
+        original_bytes,
+        compressed_bytes,
+        savings_percent,
+        width,
+        height,
+        metadata_bytes_removed: source_metadata.total_len().saturating_sub(metadata_kept),
+        metadata_stripped: !source_metadata.is_empty() && !source_metadata_carried,
+        psnr,
+        quality_flagged: psnr
+            .zip(options.min_psnr)
+            .is_some_and(|(psnr, min)| psnr < min),
+        ssim,
+        kept_original,
+        timings,
+        adaptive_quality,
+    };
+    Ok((compressed, stats))
+}
+
+/// Read the encoded output's header and fail unless it is exactly the
+/// requested `Exact` size, catching encoders that pad or align dimensions
+/// An `Exact` or `Pad` resize promises its size, so a `max_dimension` that
+/// would shrink it further is an error rather than a failed header check
+fn check_exact_resize_fits(options: &CompressOptions) -> Result<()> {
+    if let (Some(resize), Some(max)) = (options.resize, options.max_dimension)
+        && matches!(resize.mode, ResizeMode::Exact | ResizeMode::Pad)
+        && resize.width.max(resize.height) > max
+    {
+        bail!(
+            "max_dimension {max} would shrink the exact {}x{} resize",
+            resize.width,
+            resize.height
+        );
+    }
+    Ok(())
+}
+
+fn verify_exact_dimensions(
+    compressed: &[u8],
+    format: OutputFormat,
+    resize: ResizeOptions,
+) -> Result<()> {
+    let (width, height) = encoded_dimensions(compressed, format)?;
+    if (width, height) != (resize.width, resize.height) {
+        bail!(
+            "{format} output is {width}x{height}, expected exactly {}x{}",
+            resize.width,
+            resize.height
+        );
+    }
+    Ok(())
+}
+
+/// Pixel dimensions from an encoded image's header. AVIF is read from its
+/// `ispe` property box, since this build has no AVIF decoder.
+fn encoded_dimensions(bytes: &[u8], format: OutputFormat) -> Result<(u32, u32)> {
+    if format == OutputFormat::Avif {
+        return avif_image_size(bytes).context("AVIF output has no image size (ispe) property");
+    }
+    image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()
+        .with_context(|| format!("failed to read {format} output dimensions"))
+}
+
+/// Returns the payload of the first box of type `kind` among the ISO-BMFF
+/// boxes laid out back to back in `data`.
+fn find_box<'a>(mut data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[0..4].try_into().ok()?) as u64;
+        let (header, size) = match size {
+            0 => (8, data.len() as u64),
+            1 => (16, u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)),
+            size => (8, size),
+        };
+        let size = usize::try_from(size).ok()?;
+        if size < header || size > data.len() {
+            return None;
+        }
+        if &data[4..8] == kind {
+            return Some(&data[header..size]);
+        }
+        data = &data[size..];
+    }
+    None
+}
+
+/// Reads the primary image size from an AVIF container by walking
+/// `meta` → `iprp` → `ipco` → `ispe`.
+fn avif_image_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    // `meta` and `ispe` are full boxes that start with version and flags
+    let meta = find_box(bytes, b"meta")?.get(4..)?;
+    let ipco = find_box(find_box(meta, b"iprp")?, b"ipco")?;
+    let ispe = find_box(ipco, b"ispe")?;
+    let read_u32 = |at: usize| Some(u32::from_be_bytes(ispe.get(at..at + 4)?.try_into().ok()?));
+    Some((read_u32(4)?, read_u32(8)?))
+}
+
+fn encode_image(
+    image: &DynamicImage,
+    format: OutputFormat,
+    options: &CompressOptions,
+    metadata: &SourceMetadata,
+) -> Result<Vec<u8>> {
+    match format {
+        OutputFormat::Jpeg => compress_jpeg(image, options, metadata),
+        OutputFormat::Png => {
+            let gray = as_grayscale(image);
+            let png = compress_png(&[], Some(gray.as_ref().unwrap_or(image)), options)?;
+            Ok(tag_png_srgb(png, metadata))
+        }
+        OutputFormat::WebP => {
+            let webp = compress_webp(image, options)?;
+            embed_webp_metadata(webp, metadata, image.width(), image.height())
+        }
+        OutputFormat::Avif => compress_avif(image, options),
+    }
+}
+
+fn estimate_encoded_size(
+    image: &DynamicImage,
+    format: OutputFormat,
+    options: &CompressOptions,
+) -> Result<u64> {
+    let no_metadata = SourceMetadata::default();
+    let (width, height) = (image.width(), image.height());
+    if width <= ESTIMATE_PROXY_SIDE && height <= ESTIMATE_PROXY_SIDE {
+        return Ok(encode_image(image, format, options, &no_metadata)?.len() as u64);
+    }
+
+    let proxy = image.resize(
+        ESTIMATE_PROXY_SIDE,
+        ESTIMATE_PROXY_SIDE,
+        FilterType::Triangle,
+    );
+    let proxy_bytes = encode_image(&proxy, format, options, &no_metadata)?.len() as f64;
+    let pixel_ratio = (u64::from(width) * u64::from(height)) as f64
+        / (u64::from(proxy.width()) * u64::from(proxy.height())) as f64;
+
+    Ok((proxy_bytes * pixel_ratio).round() as u64)
+}
+
+fn compress_jpeg(
+    image: &DynamicImage,
+    options: &CompressOptions,
+    metadata: &SourceMetadata,
+) -> Result<Vec<u8>> {
+    // Gray content (including bilevel scans) is written as a one-component
+    // JPEG instead of three identical color channels
+    let gray = !image.color().has_color() || has_only_gray_pixels(image);
+    let (pixels, color_space) = if gray {
+        (
+            image.to_luma8().into_raw(),
+            mozjpeg::ColorSpace::JCS_GRAYSCALE,
+        )
+    } else {
+        (image.to_rgb8().into_raw(), mozjpeg::ColorSpace::JCS_RGB)
+    };
+    let (width, height) = (image.width() as usize, image.height() as usize);
+
+    let mut comp = mozjpeg::Compress::new(color_space);
+
+    // Both calls below reset all parameters to the profile defaults, so they
+    // must come before size and quality are set
+    if options.jpeg_trellis == Some(false) {
+        // The mozjpeg crate only exposes trellis quantization through the
+        // compression profile; the fastest profile disables it
+        comp.set_fastest_defaults();
+    }
+    if options.progressive {
+        comp.set_scan_optimization_mode(mozjpeg::ScanMode::AllComponentsTogether);
+    }
+
+    if let Some(mode) = encoder_opt(options, "dc-scan-opt", 0..=2) {
+        comp.set_scan_optimization_mode(match mode {
+            0 => mozjpeg::ScanMode::AllComponentsTogether,
+            1 => mozjpeg::ScanMode::ScanPerComponent,
+            _ => mozjpeg::ScanMode::Auto,
+        });
+    }
+    if options.progressive {
+        // Only the default profile turns progression on by itself; the
+        // fastest one reloaded above is baseline
+        comp.set_progressive_mode();
+    }
+
+    comp.set_size(width, height);
+    comp.set_quality(options.quality.unwrap_or(DEFAULT_JPEG_QUALITY) as f32);
+    if let Some(optimize) = options.jpeg_optimize_coding {
+        comp.set_optimize_coding(optimize);
+    }
+    if let Some(smoothing) = encoder_opt(options, "smoothing", 0..=100) {
+        comp.set_smoothing_factor(smoothing);
+    }
+    if let Some(optimize) = encoder_opt(options, "optimize-scans", false..=true) {
+        comp.set_optimize_scans(optimize);
+    }
+
+    let mut comp = comp.start_compress(Vec::new())?;
+    if let Some(exif) = &metadata.exif {
+        write_jpeg_app1(&mut comp, JPEG_EXIF_HEADER, exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        write_jpeg_app1(&mut comp, JPEG_XMP_HEADER, xmp);
+    }
+    if metadata.srgb {
+        // A single APP2 chunk, numbered 1 of 1. mozjpeg's `write_icc_profile`
+        // numbers chunks from 0, which readers reject.
+        let profile = srgb_icc_profile(gray);
+        let segment = [b"ICC_PROFILE\0".as_slice(), &[1, 1], &profile].concat();
+        comp.write_marker(mozjpeg::Marker::APP(2), &segment);
+    }
+    comp.write_scanlines(&pixels)
+        .context("failed to write JPEG scanlines")?;
+    let result = comp.finish()?;
+
+    Ok(result)
+}
+
+fn compress_png(
+    input_bytes: &[u8],
+    image: Option<&DynamicImage>,
+    options: &CompressOptions,
+) -> Result<Vec<u8>> {
+    let quantized = match (image, options.quantize_colors, options.png_lossy_quality) {
+        (Some(img), Some(colors), _) => Some(quantize_colors(img, colors, options.dither)),
+        (Some(img), None, Some(range)) => quantize_to_quality(img, range, options.dither),
+        _ => None,
+    };
+    let image = quantized.as_ref().or(image);
+    let png_bytes = if let Some(img) = image {
+        let mut buf = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .context("failed to encode PNG")?;
+        buf
+    } else {
+        input_bytes.to_vec()
+    };
+
+    let level = options.png_level.unwrap_or(2);
+    let mut opts = oxipng::Options::from_preset(level);
+    opts.optimize_alpha = options.png_optimize_alpha;
+    if let Some(filter) = options.png_filter {
+        opts.filter = filter.row_filters();
+    }
+    if let Some(compression) = encoder_opt(options, "deflate-level", 0..=12) {
+        opts.deflate = oxipng::Deflaters::Libdeflater { compression };
+    }
+    if let Some(fast) = encoder_opt(options, "fast-evaluation", false..=true) {
+        opts.fast_evaluation = fast;
+    }
+    if options.strip_metadata {
+        opts.strip = oxipng::StripChunks::Safe;
+    } else if options.deterministic {
+        // Keep metadata but drop the last-modification timestamp
+        opts.strip = oxipng::StripChunks::Strip(oxipng::indexset! { *b"tIME" });
+    }
+
+    oxipng::optimize_from_memory(&png_bytes, &opts).context("PNG optimization failed")
+}
+
+/// NeuQuant sampling factor: 1 learns from every pixel, 30 is fastest; 10 is
+/// the library's recommended balance
+const QUANTIZE_SAMPLE_FACTOR: i32 = 10;
+
+/// Map every pixel onto a NeuQuant palette of at most `colors` entries, so
+/// oxipng's palette reduction can store the result as an indexed PNG
+fn quantize_colors(image: &DynamicImage, colors: u16, dither: bool) -> DynamicImage {
+    let rgba = image.to_rgba8();
+    let quantizer =
+        color_quant::NeuQuant::new(QUANTIZE_SAMPLE_FACTOR, colors.into(), rgba.as_raw());
+    DynamicImage::ImageRgba8(remap_to_palette(rgba, &quantizer, dither))
+}
+
+/// Replace each pixel with its nearest palette color, diffusing the error
+/// Floyd–Steinberg style when `dither` is set
+fn remap_to_palette(
+    mut rgba: RgbaImage,
+    quantizer: &color_quant::NeuQuant,
+    dither: bool,
+) -> RgbaImage {
+    if dither {
+        image::imageops::dither(&mut rgba, quantizer);
+    } else {
+        for pixel in rgba.pixels_mut() {
+            quantizer.map_pixel(&mut pixel.0);
+        }
+    }
+    rgba
+}
+
+/// Quantize with the fewest colors whose error meets the `max` quality, or
+/// 256 colors if only `min` is met. `None` (keep full color) when even 256
+/// colors fall short of `min`. Like pngquant, the error is judged on the
+/// plain palette mapping; `dither` only applies to the final image.
+fn quantize_to_quality(
+    image: &DynamicImage,
+    (min, max): (u8, u8),
+    dither: bool,
+) -> Option<DynamicImage> {
+    let original = image.to_rgba8();
+    let error = |colors: u16| {
+        let quantizer =
+            color_quant::NeuQuant::new(QUANTIZE_SAMPLE_FACTOR, colors.into(), original.as_raw());
+        let mse = mse_rgba(
+            &original,
+            &remap_to_palette(original.clone(), &quantizer, false),
+        );
+        (quantizer, mse)
+    };
+
+    let (mut best, mse) = error(256);
+    if mse > quality_to_mse(min) {
+        eprintln!("warning: 256 colors can't reach PNG quality {min}, keeping full color");
+        return None;
+    }
+    if mse <= quality_to_mse(max) {
+        // Binary search for the smallest palette still within the max-quality error
+        let (mut low, mut high) = (2, 256);
+        while low < high {
+            let colors = (low + high) / 2;
+            let (quantizer, mse) = error(colors);
+            if mse <= quality_to_mse(max) {
+                best = quantizer;
+                high = colors;
+            } else {
+                low = colors + 1;
+            }
+        }
+    }
+    Some(DynamicImage::ImageRgba8(remap_to_palette(
+        original, &best, dither,
+    )))
+}
+
+/// pngquant's quality-to-error curve: the mean squared error (channels scaled
+/// to 0-1) that a 0-100 quality allows. Our error is plain RGBA MSE, so the
+/// result tracks pngquant's closely but not exactly.
+fn quality_to_mse(quality: u8) -> f64 {
+    const WEIGHT_MSE: f64 = 0.45;
+    if quality >= 100 {
+        return 0.0;
+    }
+    let quality = f64::from(quality);
+    let low_quality_fudge = (0.016 / (0.001 + quality) - 0.001).max(0.0);
+    WEIGHT_MSE * (low_quality_fudge + 2.5 / (210.0 + quality).powf(1.2) * (100.1 - quality) / 100.0)
+}
+
+/// Mean squared error over all RGBA samples, scaled to 0-1
+fn mse_rgba(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+    let samples = a.as_raw().len().min(b.as_raw().len());
+    if samples == 0 {
+        return 0.0;
+    }
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| ((f64::from(x) - f64::from(y)) / 255.0).powi(2))
+        .sum();
+    squared_error / samples as f64
+}
+
+/// Largest width or height the WebP format can store
+const WEBP_MAX_SIDE: u32 = 16_383;
+
+fn compress_webp(image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>> {
+    match options.output_bit_depth {
+        None | Some(8) => {}
+        Some(10) => bail!("WebP output is 8-bit only (requested 10-bit)"),
+        Some(depth) => bail!("unsupported output bit depth {depth} (expected 8 or 10)"),
+    }
+    let image = &*dithered_to_8_bit(image);
+
+    let (width, height) = (image.width(), image.height());
+    if width > WEBP_MAX_SIDE || height > WEBP_MAX_SIDE {
+        bail!(
+            "WebP images are at most {WEBP_MAX_SIDE} pixels per side, this one is {width}x{height} \
+             (resize it or pick another format)"
+        );
+    }
+    let opaque = is_opaque(image);
+    let pixels = if opaque {
+        image.to_rgb8().into_raw()
+    } else {
+        image.to_rgba8().into_raw()
+    };
+    let encoder = if opaque {
+        webp::Encoder::from_rgb(&pixels, width, height)
+    } else {
+        webp::Encoder::from_rgba(&pixels, width, height)
+    };
+
+    // Same baseline as `Encoder::encode`/`encode_lossless`, plus our knobs
+    let mut config =
+        webp::WebPConfig::new().map_err(|_| anyhow!("failed to initialize WebP encoder config"))?;
+    if webp_lossless(image, options) {
+        config.lossless = 1;
+        config.alpha_compression = 0;
+        config.quality = 75.0;
+    } else {
+        config.lossless = 0;
+        config.alpha_compression = 1;
+        config.quality = options.quality.unwrap_or(DEFAULT_WEBP_QUALITY) as f32;
+    }
+    config.exact = i32::from(options.webp_exact);
+    config.use_sharp_yuv = i32::from(options.webp_sharp_yuv);
+    if let Some(filter) = options.webp_alpha_filter {
+        config.alpha_filtering = match filter {
+            WebPAlphaFilter::None => 0,
+            WebPAlphaFilter::Fast => 1,
+            WebPAlphaFilter::Best => 2,
+        };
+    }
+    if let Some(method) = encoder_opt(options, "method", 0..=6) {
+        config.method = method;
+    }
+    if let Some(strength) = encoder_opt(options, "sns-strength", 0..=100) {
+        config.sns_strength = strength;
+    }
+    if let Some(strength) = encoder_opt(options, "filter-strength", 0..=100) {
+        config.filter_strength = strength;
+    }
+    if let Some(segments) = encoder_opt(options, "segments", 1..=4) {
+        config.segments = segments;
+    }
+    if options.two_pass {
+        config.pass = 2;
+    }
+    if let Some(pass) = encoder_opt(options, "pass", 1..=10) {
+        config.pass = pass;
+    }
+
+    let memory = encoder
+        .encode_advanced(&config)
+        .map_err(|err| anyhow!("WebP encoding failed: {err:?}"))?;
+
+    non_empty_output(memory.to_vec(), "WebP")
+}
+
+/// Reject an encoder result with no bytes, which would otherwise be written
+/// as an empty file and reported as 100% savings
+fn non_empty_output(bytes: Vec<u8>, encoder: &str) -> Result<Vec<u8>> {
+    if bytes.is_empty() {
+        bail!("{encoder} encoder returned no data");
+    }
+    Ok(bytes)
+}
+
+fn webp_lossless(image: &DynamicImage, options: &CompressOptions) -> bool {
+    options.lossless || (options.auto_lossless && looks_graphic(image))
+}
+
+/// Distinct colors at or below which an image counts as a graphic
+const GRAPHIC_MAX_COLORS: usize = 256;
+/// Share of pixels equal to their left neighbor above which an image counts
+/// as a graphic (screenshots, diagrams, flat UI)
+const GRAPHIC_MIN_FLAT_RATIO: f64 = 0.6;
+
+/// Whether lossless coding is likely to beat lossy: few distinct colors or
+/// mostly flat runs. Photos have neither.
+fn looks_graphic(image: &DynamicImage) -> bool {
+    let rgba = image.to_rgba8();
+    let pixels: Vec<[u8; 4]> = rgba.pixels().map(|p| p.0).collect();
+    if pixels.is_empty() {
+        return false;
+    }
+
+    if unique_colors_up_to(&rgba, GRAPHIC_MAX_COLORS).is_some() {
+        return true;
+    }
+
+    let width = rgba.width() as usize;
+    let flat = pixels
+        .chunks(width)
+        .flat_map(|row| row.windows(2))
+        .filter(|pair| pair[0] == pair[1])
+        .count();
+    flat as f64 / pixels.len() as f64 >= GRAPHIC_MIN_FLAT_RATIO
+}
+
+/// Number of distinct colors, or None as soon as there are more than `limit`
+fn unique_colors_up_to(rgba: &RgbaImage, limit: usize) -> Option<usize> {
+    let mut colors = std::collections::HashSet::new();
+    for pixel in rgba.pixels() {
+        colors.insert(pixel.0);
+        if colors.len() > limit {
+            return None;
+        }
+    }
+    Some(colors.len())
+}
+
+/// No alpha channel, or one that is fully opaque everywhere. Such images go
+/// through the encoders' RGB paths, skipping the 4-channel buffer and the
+/// alpha plane.
+fn is_opaque(image: &DynamicImage) -> bool {
+    match image {
+        _ if !image.color().has_alpha() => true,
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().all(|p| p[3] == u8::MAX),
+        DynamicImage::ImageLumaA8(luma_alpha) => luma_alpha.pixels().all(|p| p[1] == u8::MAX),
+        _ => image.to_rgba8().pixels().all(|p| p[3] == u8::MAX),
+    }
+}
+
+/// More than 8 bits per channel (16-bit or float samples)
+fn is_high_bit_depth(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+/// 4x4 Bayer matrix for ordered dithering
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// The image with at most 8 bits per channel. Deeper sources are reduced
+/// with ordered dithering, which keeps smooth gradients from banding the way
+/// rounding every pixel to the nearest 8-bit value does. All channels of a
+/// pixel share one threshold, so gray stays gray.
+fn dithered_to_8_bit(image: &DynamicImage) -> std::borrow::Cow<'_, DynamicImage> {
+    if !is_high_bit_depth(image) {
+        return std::borrow::Cow::Borrowed(image);
+    }
+    let rgba = image.to_rgba16();
+    let dithered = RgbaImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let rank = BAYER_4X4[(y % 4) as usize][(x % 4) as usize];
+        let threshold = (f32::from(rank) + 0.5) / 16.0;
+        image::Rgba(
+            rgba.get_pixel(x, y)
+                .0
+                .map(|value| (f32::from(value) / 257.0 + threshold).floor().min(255.0) as u8),
+        )
+    });
+    std::borrow::Cow::Owned(DynamicImage::ImageRgba8(dithered))
+}
+
+/// An 8-bit RGB(A) image whose pixels are all gray (R = G = B), such as a
+/// bilevel or grayscale scan saved in color, as luma (plus alpha unless it is
+/// opaque). The conversion is exact. `None` for color content and for images
+/// that are already grayscale or not 8-bit.
+fn as_grayscale(image: &DynamicImage) -> Option<DynamicImage> {
+    if !has_only_gray_pixels(image) {
+        None
+    } else if is_opaque(image) {
+        Some(DynamicImage::ImageLuma8(image.to_luma8()))
+    } else {
+        Some(DynamicImage::ImageLumaA8(image.to_luma_alpha8()))
+    }
+}
+
+/// True when every pixel of an 8-bit RGB(A) image has equal color channels;
+/// the scan stops at the first colored pixel
+fn has_only_gray_pixels(image: &DynamicImage) -> bool {
+    let gray = |p: &[u8]| p[0] == p[1] && p[1] == p[2];
+    match image {
+        DynamicImage::ImageRgb8(rgb) => rgb.pixels().all(|p| gray(&p.0)),
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().all(|p| gray(&p.0)),
+        _ => false,
+    }
+}
+
+/// Encoder speed and quality for AVIF: explicit options, then the preset's,
+/// then the defaults (lossless always encodes at quality 100)
+fn avif_speed_and_quality(options: &CompressOptions) -> (u8, f32) {
+    let (preset_speed, preset_quality) = options.avif_preset.map_or(
+        (DEFAULT_AVIF_SPEED, DEFAULT_AVIF_QUALITY),
+        AvifPreset::speed_and_quality,
+    );
+    let quality = if options.lossless {
+        100.0
+    } else {
+        f32::from(options.quality.unwrap_or(preset_quality))
+    };
+    (options.avif_speed.unwrap_or(preset_speed), quality)
+}
+
+fn compress_avif(image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let (speed, quality) = avif_speed_and_quality(options);
+
+    let mut encoder = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_speed(speed)
+        .with_alpha_quality(quality);
+    if let Some(alpha_quality) = encoder_opt(options, "alpha-quality", 1.0..=100.0) {
+        encoder = encoder.with_alpha_quality(alpha_quality);
+    }
+    if let Some(model) = options.encoder_opts.get("color-model") {
+        match model.as_str() {
+            "ycbcr" => encoder = encoder.with_internal_color_model(ravif::ColorModel::YCbCr),
+            "rgb" => encoder = encoder.with_internal_color_model(ravif::ColorModel::RGB),
+            other => eprintln!(
+                "warning: ignoring encoder option color-model={other}: expected ycbcr or rgb"
+            ),
+        }
+    }
+    encoder = encoder.with_num_threads(avif_threads(options, image.width(), image.height()));
+    let depth = match options.output_bit_depth {
+        None => ravif::BitDepth::Auto,
+        Some(8) => ravif::BitDepth::Eight,
+        Some(10) => ravif::BitDepth::Ten,
+        Some(depth) => bail!("unsupported output bit depth {depth} (expected 8 or 10)"),
+    };
+    encoder = encoder.with_bit_depth(depth);
+
+    // The raw-plane path always uses YCbCr, so leave an explicit color model
+    // (and premultiplied alpha, which has its own path) to the 8-bit input
+    if depth != ravif::BitDepth::Eight
+        && is_high_bit_depth(image)
+        && !options.avif_premultiplied_alpha
+        && !options.encoder_opts.contains_key("color-model")
+    {
+        return compress_avif_10_bit(image, encoder);
+    }
+    let image = &*dithered_to_8_bit(image);
+
+    let result = if is_opaque(image) {
+        let pixels: Vec<rgb::RGB8> = image
+            .to_rgb8()
+            .pixels()
+            .map(|p| rgb::RGB8::new(p[0], p[1], p[2]))
+            .collect();
+        encoder.encode_rgb(imgref::Img::new(&pixels[..], width, height))
+    } else {
+        let rgba = image.to_rgba8();
+        if options.avif_premultiplied_alpha {
+            if options.output_bit_depth == Some(8) {
+                bail!("premultiplied AVIF is encoded at 10 bits, not the requested 8");
+            }
+            return compress_avif_premultiplied(&rgba, encoder);
+        }
+        let pixels: Vec<rgb::RGBA8> = rgba
+            .pixels()
+            .map(|p| rgb::RGBA8::new(p[0], p[1], p[2], p[3]))
+            .collect();
+        encoder.encode_rgba(imgref::Img::new(&pixels[..], width, height))
+    }
+    .context("AVIF encoding failed")?;
+
+    non_empty_output(result.avif_file, "AVIF")
+}
+
+/// Encoder thread count for AVIF, which ravif also uses as the tile count
+/// (capped by image area); None leaves it to ravif
+fn avif_threads(options: &CompressOptions, width: u32, height: u32) -> Option<usize> {
+    match options.avif_tiles {
+        Some(tiles) => Some(tiles.count(width, height).max(1)),
+        // One tile per thread makes the bitstream depend on the machine's
+        // core count unless the thread count is pinned
+        None if options.deterministic => Some(1),
+        None => None,
+    }
+}
+
+/// Premultiply color by alpha ourselves and feed raw planes to ravif, which
+/// then only sets the premultiplied flag in the container. `encode_rgba`
+/// expects unassociated input, so it cannot be used for this.
+fn compress_avif_premultiplied(
+    rgba: &image::RgbaImage,
+    encoder: ravif::Encoder,
+) -> Result<Vec<u8>> {
+    let (width, height) = rgba.dimensions();
 
     let pixels: Vec<rgb::RGBA8> = rgba
         .pixels()
-        .map(|p| rgb::RGBA8::new(p[0], p[1], p[2], p[3]))
+        .map(|p| {
+            let a = u16::from(p[3]);
+            let premultiply = |c: u8| ((u16::from(c) * a + 127) / 255) as u8;
+            rgb::RGBA8::new(
+                premultiply(p[0]),
+                premultiply(p[1]),
+                premultiply(p[2]),
+                p[3],
+            )
+        })
         .collect();
+    let img = imgref::Img::new(pixels, width as usize, height as usize);
+
+    let planes = img.pixels().map(|px| rgb_to_10_bit_ycbcr(px.r, px.g, px.b));
+    let alpha = img
+        .pixels()
+        .map(|px| (u16::from(px.a) << 2) | (u16::from(px.a) >> 6));
+
+    let result = encoder
+        .with_alpha_color_mode(ravif::AlphaColorMode::Premultiplied)
+        .encode_raw_planes_10_bit(
+            width as usize,
+            height as usize,
+            planes,
+            Some(alpha),
+            rav1e::prelude::PixelRange::Full,
+            ravif::MatrixCoefficients::BT601,
+        )
+        .context("AVIF encoding failed")?;
+
+    non_empty_output(result.avif_file, "AVIF")
+}
+
+/// Encode a 16-bit (or float) source from 10-bit planes, so its extra
+/// precision reaches 10-bit output instead of passing through 8 bits first
+fn compress_avif_10_bit(image: &DynamicImage, encoder: ravif::Encoder) -> Result<Vec<u8>> {
+    let rgba = image.to_rgba16();
+    let (width, height) = rgba.dimensions();
+    let to_10_bit = |value: u16| f32::from(value) * (1023.0 / 65535.0);
+
+    let planes = rgba
+        .pixels()
+        .map(|p| ycbcr_10_bit(to_10_bit(p[0]), to_10_bit(p[1]), to_10_bit(p[2])));
+    let alpha = (!is_opaque(image)).then(|| {
+        rgba.pixels()
+            .map(|p| to_10_bit(p[3]).round().clamp(0.0, 1023.0) as u16)
+    });
+
+    let result = encoder
+        .encode_raw_planes_10_bit(
+            width as usize,
+            height as usize,
+            planes,
+            alpha,
+            rav1e::prelude::PixelRange::Full,
+            ravif::MatrixCoefficients::BT601,
+        )
+        .context("AVIF encoding failed")?;
+
+    non_empty_output(result.avif_file, "AVIF")
+}
+
+/// Full-range BT.601 conversion matching what ravif uses for RGBA input
+fn rgb_to_10_bit_ycbcr(r: u8, g: u8, b: u8) -> [u16; 3] {
+    let scale = 1023.0 / 255.0;
+    ycbcr_10_bit(
+        f32::from(r) * scale,
+        f32::from(g) * scale,
+        f32::from(b) * scale,
+    )
+}
+
+/// Full-range BT.601 YCbCr from RGB already scaled to 0..=1023
+fn ycbcr_10_bit(r: f32, g: f32, b: f32) -> [u16; 3] {
+    const KR: f32 = 0.299;
+    const KG: f32 = 0.587;
+    const KB: f32 = 0.114;
+    let y = KR * r + KG * g + KB * b;
+    let cb = (b - y) * (0.5 / (1.0 - KB)) + 512.0;
+    let cr = (r - y) * (0.5 / (1.0 - KR)) + 512.0;
+    [y, cb, cr].map(|v| v.round().clamp(0.0, 1023.0) as u16)
+}
+
+// ---------------------------------------------------------------------------
+// Metadata passthrough
+// ---------------------------------------------------------------------------
+
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Largest payload a JPEG marker segment can hold (length field is u16 and counts itself)
+const JPEG_MAX_SEGMENT: usize = 65_533;
+
+/// Metadata blocks read from the source. EXIF (raw TIFF) and XMP are carried
+/// over when `strip_metadata` is off; ICC is only measured.
+#[derive(Debug, Default, Clone)]
+struct SourceMetadata {
+    exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+    icc: Option<Vec<u8>>,
+    /// Tag the output as sRGB (`assume_srgb` on a source without ICC)
+    srgb: bool,
+}
+
+impl SourceMetadata {
+    /// True when there is no EXIF/XMP to carry over
+    fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none()
+    }
+
+    fn exif_xmp_len(&self) -> u64 {
+        [&self.exif, &self.xmp]
+            .into_iter()
+            .flatten()
+            .map(|block| block.len() as u64)
+            .sum()
+    }
+
+    fn icc_len(&self) -> u64 {
+        self.icc.as_ref().map_or(0, |icc| icc.len() as u64)
+    }
+
+    fn total_len(&self) -> u64 {
+        self.exif_xmp_len() + self.icc_len()
+    }
+
+    /// A copy with the EXIF thumbnail and MakerNote removed; EXIF that can't
+    /// be parsed is kept as it is
+    fn without_exif_thumbnail(&self) -> Self {
+        let exif = self
+            .exif
+            .as_ref()
+            .map(|exif| strip_exif_thumbnail(exif).unwrap_or_else(|| exif.clone()));
+        Self {
+            exif,
+            ..self.clone()
+        }
+    }
+}
+
+/// Best-effort: metadata that can't be read is treated as absent
+fn read_source_metadata(bytes: &[u8]) -> SourceMetadata {
+    use image::ImageDecoder;
+
+    let Ok(reader) = image::ImageReader::new(Cursor::new(bytes)).with_guessed_format() else {
+        return SourceMetadata::default();
+    };
+    let Ok(mut decoder) = reader.into_decoder() else {
+        return SourceMetadata::default();
+    };
+
+    SourceMetadata {
+        exif: decoder.exif_metadata().ok().flatten(),
+        xmp: decoder.xmp_metadata().ok().flatten(),
+        icc: decoder.icc_profile().ok().flatten(),
+        srgb: false,
+    }
+}
+
+fn write_jpeg_app1<W>(
+    comp: &mut mozjpeg::compress::CompressStarted<W>,
+    header: &[u8],
+    payload: &[u8],
+) {
+    if header.len() + payload.len() > JPEG_MAX_SEGMENT {
+        eprintln!("warning: metadata block too large for a JPEG segment, dropped");
+        return;
+    }
+    let mut segment = Vec::with_capacity(header.len() + payload.len());
+    segment.extend_from_slice(header);
+    segment.extend_from_slice(payload);
+    comp.write_marker(mozjpeg::Marker::APP(1), &segment);
+}
+
+/// Add ICC/EXIF/XMP chunks to a simple-format WebP, converting it to the
+/// extended (VP8X) layout if needed
+fn embed_webp_metadata(
+    webp: Vec<u8>,
+    metadata: &SourceMetadata,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>> {
+    const FLAG_ICC: u8 = 0x20;
+    const FLAG_ALPHA: u8 = 0x10;
+    const FLAG_EXIF: u8 = 0x08;
+    const FLAG_XMP: u8 = 0x04;
+
+    if metadata.is_empty() && !metadata.srgb {
+        return Ok(webp);
+    }
+    if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        bail!("encoder produced an invalid WebP container");
+    }
+
+    let mut chunks = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= webp.len() {
+        let fourcc: [u8; 4] = webp[offset..offset + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(webp[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let end = offset + 8 + size;
+        if end > webp.len() {
+            bail!("encoder produced a truncated WebP chunk");
+        }
+        chunks.push((fourcc, &webp[offset + 8..end]));
+        offset = end + (size & 1);
+    }
+
+    let mut vp8x = if chunks.first().is_some_and(|(fourcc, _)| fourcc == b"VP8X") {
+        let mut header = chunks.remove(0).1.to_vec();
+        header.resize(10, 0);
+        header
+    } else {
+        let mut header = vec![0u8; 10];
+        header[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+        // VP8L stores an alpha_is_used bit after the 14-bit width/height fields
+        if let Some((_, payload)) = chunks.iter().find(|(fourcc, _)| fourcc == b"VP8L")
+            && payload.len() >= 5
+            && (u32::from_le_bytes(payload[1..5].try_into().unwrap()) >> 28) & 1 == 1
+        {
+            header[0] |= FLAG_ALPHA;
+        }
+        header
+    };
+
+    // ICCP must come before the image data, EXIF and XMP after it
+    let icc = metadata.srgb.then(|| srgb_icc_profile(false));
+    let leading = icc.as_deref().map(|icc| {
+        vp8x[0] |= FLAG_ICC;
+        (*b"ICCP", icc)
+    });
+    let mut trailing = Vec::new();
+    if let Some(exif) = &metadata.exif {
+        vp8x[0] |= FLAG_EXIF;
+        trailing.push((*b"EXIF", exif.as_slice()));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        vp8x[0] |= FLAG_XMP;
+        trailing.push((*b"XMP ", xmp.as_slice()));
+    }
+
+    let mut body = b"WEBP".to_vec();
+    let all_chunks = std::iter::once((*b"VP8X", vp8x.as_slice()))
+        .chain(leading)
+        .chain(chunks.iter().map(|(fourcc, payload)| (*fourcc, *payload)))
+        .chain(trailing);
+    for (fourcc, payload) in all_chunks {
+        body.extend_from_slice(&fourcc);
+        body.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        body.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            body.push(0);
+        }
+    }
+
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Insert an `sRGB` chunk (perceptual intent) after IHDR when `metadata`
+/// asks for an sRGB tag and the PNG has no color tag of its own
+fn tag_png_srgb(png: Vec<u8>, metadata: &SourceMetadata) -> Vec<u8> {
+    const IHDR_END: usize = 8 + 8 + 13 + 4;
+
+    if !metadata.srgb || png.len() < IHDR_END {
+        return png;
+    }
+    let mut offset = 8;
+    while offset + 8 <= png.len() {
+        let kind = &png[offset + 4..offset + 8];
+        if kind == b"iCCP" || kind == b"sRGB" {
+            return png;
+        }
+        if kind == b"IDAT" {
+            break;
+        }
+        let size = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+        offset += 12 + size;
+    }
+
+    let mut chunk = Vec::with_capacity(13);
+    chunk.extend_from_slice(&1u32.to_be_bytes());
+    chunk.extend_from_slice(b"sRGB\0");
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+
+    let mut out = png;
+    out.splice(IHDR_END..IHDR_END, chunk);
+    out
+}
+
+/// How far into a file `skip_marked` looks for the marker, which follows the
+/// JPEG APPn segments or the PNG header
+const OUTPUT_MARKER_SEARCH_BYTES: usize = 256 * 1024;
+
+/// The `mark_output` marker for `format` at `options`' quality, e.g.
+/// `image-compressor-rs jpeg q85;` (the `;` keeps `q8` from matching `q85`)
+fn output_marker(format: OutputFormat, options: &CompressOptions) -> String {
+    let lossy = format == OutputFormat::Jpeg || (format != OutputFormat::Png && !options.lossless);
+    let quality = match options.quality.or(format.default_quality()) {
+        Some(quality) if lossy => format!("q{quality}"),
+        _ => "lossless".to_string(),
+    };
+    format!("image-compressor-rs {format} {quality};")
+}
+
+/// `encoded` with its [`output_marker`] as a JPEG comment (after SOI and the
+/// APPn segments readers expect first) or a PNG `tEXt` chunk (after IHDR).
+/// Other formats are returned unchanged.
+fn with_output_marker(
+    mut encoded: Vec<u8>,
+    format: OutputFormat,
+    options: &CompressOptions,
+) -> Vec<u8> {
+    const IHDR_END: usize = 8 + 8 + 13 + 4;
+
+    let marker = output_marker(format, options);
+    match format {
+        OutputFormat::Jpeg if encoded.starts_with(&[0xFF, 0xD8]) => {
+            let mut offset = 2;
+            while offset + 4 <= encoded.len()
+                && encoded[offset] == 0xFF
+                && (0xE0..=0xEF).contains(&encoded[offset + 1])
+            {
+                let length = u16::from_be_bytes([encoded[offset + 2], encoded[offset + 3]]);
+                offset += 2 + usize::from(length);
+            }
+            let mut segment = vec![0xFF, 0xFE];
+            segment.extend_from_slice(&(marker.len() as u16 + 2).to_be_bytes());
+            segment.extend_from_slice(marker.as_bytes());
+            let offset = offset.min(encoded.len());
+            encoded.splice(offset..offset, segment);
+        }
+        OutputFormat::Png if encoded.len() >= IHDR_END => {
+            let mut chunk = Vec::with_capacity(12 + 9 + marker.len());
+            chunk.extend_from_slice(&((9 + marker.len()) as u32).to_be_bytes());
+            chunk.extend_from_slice(b"tEXtSoftware\0");
+            chunk.extend_from_slice(marker.as_bytes());
+            chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+            encoded.splice(IHDR_END..IHDR_END, chunk);
+        }
+        _ => {}
+    }
+    encoded
+}
+
+/// CRC-32 (ISO-HDLC) as used by PNG chunks
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// A minimal ICC v4 sRGB display profile: the D50-adapted sRGB primaries and
+/// the sRGB tone curve as a parametric curve, under 600 bytes. The `gray`
+/// variant has only the tone curve, for one-channel images.
+fn srgb_icc_profile(gray: bool) -> Vec<u8> {
+    fn fixed(value: f64) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+    fn numbers(kind: &[u8; 4], values: &[f64]) -> Vec<u8> {
+        let mut tag = [kind.as_slice(), &[0; 4]].concat();
+        for &value in values {
+            tag.extend_from_slice(&fixed(value));
+        }
+        tag
+    }
+    fn mluc(text: &str) -> Vec<u8> {
+        let utf16: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        let mut tag = b"mluc\0\0\0\0".to_vec();
+        // One record of 12 bytes: language, country, length, offset
+        for field in [1, 12] {
+            tag.extend_from_slice(&u32::to_be_bytes(field));
+        }
+        tag.extend_from_slice(b"enUS");
+        tag.extend_from_slice(&(utf16.len() as u32).to_be_bytes());
+        tag.extend_from_slice(&28u32.to_be_bytes());
+        tag.extend_from_slice(&utf16);
+        tag
+    }
+
+    // Function type 3: Y = (aX + b)^g for X >= d, else cX
+    let mut curve = b"para\0\0\0\0\0\x03\0\0".to_vec();
+    for value in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+        curve.extend_from_slice(&fixed(value));
+    }
+    let d50 = [0.9642, 1.0, 0.8249];
+    // Bradford adaptation from the sRGB D65 white point to D50
+    let chad = [
+        1.047_811, 0.022_887, -0.050_127, 0.029_542, 0.990_484, -0.017_049, -0.009_234, 0.015_044,
+        0.752_132,
+    ];
+    let mut tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", mluc(if gray { "sRGB gray" } else { "sRGB" })),
+        (b"cprt", mluc("No copyright, use freely")),
+        (b"wtpt", numbers(b"XYZ ", &d50)),
+        (b"chad", numbers(b"sf32", &chad)),
+    ];
+    if gray {
+        tags.push((b"kTRC", curve));
+    } else {
+        tags.extend([
+            (
+                b"rXYZ",
+                numbers(b"XYZ ", &[0.436_075, 0.222_504, 0.013_932]),
+            ),
+            (
+                b"gXYZ",
+                numbers(b"XYZ ", &[0.385_065, 0.716_879, 0.097_104]),
+            ),
+            (
+                b"bXYZ",
+                numbers(b"XYZ ", &[0.143_080, 0.060_617, 0.714_173]),
+            ),
+            (b"rTRC", curve.clone()),
+            (b"gTRC", curve.clone()),
+            (b"bTRC", curve),
+        ]);
+    }
+
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let data_start = 128 + 4 + 12 * tags.len();
+    for (signature, tag) in &tags {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+
+    let mut profile = vec![0u8; 128];
+    let size = (128 + table.len() + data.len()) as u32;
+    profile[0..4].copy_from_slice(&size.to_be_bytes());
+    profile[8..12].copy_from_slice(&0x0430_0000u32.to_be_bytes());
+    profile[12..16].copy_from_slice(b"mntr");
+    profile[16..20].copy_from_slice(if gray { b"GRAY" } else { b"RGB " });
+    profile[20..24].copy_from_slice(b"XYZ ");
+    // Creation date, 2024-01-01 00:00:00
+    profile[24..30].copy_from_slice(&[0x07, 0xE8, 0, 1, 0, 1]);
+    profile[36..40].copy_from_slice(b"acsp");
+    for (i, value) in d50.iter().enumerate() {
+        profile[68 + 4 * i..72 + 4 * i].copy_from_slice(&fixed(*value));
+    }
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+    profile
+}
+
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const GPS_IFD_POINTER: u16 = 0x8825;
+const INTEROP_IFD_POINTER: u16 = 0xA005;
+const EXIF_MAKER_NOTE: u16 = 0x927C;
+
+/// Rebuild a raw EXIF (TIFF) block without IFD1, which holds the embedded
+/// thumbnail, and without MakerNote. Every kept value is rewritten, so
+/// offsets stay valid; `None` if the block can't be parsed.
+fn strip_exif_thumbnail(tiff: &[u8]) -> Option<Vec<u8>> {
+    let big_endian = match tiff.get(0..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let reader = TiffReader {
+        bytes: tiff,
+        big_endian,
+    };
+    if reader.u16(2)? != 42 {
+        return None;
+    }
+    let ifd0 = reader.ifd(reader.u32(4)? as usize, 0)?;
+
+    let mut writer = TiffWriter {
+        out: Vec::with_capacity(tiff.len()),
+        big_endian,
+    };
+    writer.out.extend_from_slice(&tiff[0..4]);
+    writer.out.extend_from_slice(&writer.u32_bytes(8));
+    // IFD0's next-IFD link is written as 0, which drops IFD1
+    writer.write_ifd(&ifd0);
+    Some(writer.out)
+}
+
+/// An IFD entry with its value bytes in the block's byte order
+struct TiffEntry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: Vec<u8>,
+    /// Parsed EXIF, GPS or interoperability sub-IFD for pointer tags
+    sub_ifd: Option<Vec<TiffEntry>>,
+}
+
+struct TiffReader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl TiffReader<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.bytes.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.bytes.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Entries of the IFD at `offset`, minus MakerNote, with sub-IFDs parsed
+    fn ifd(&self, offset: usize, depth: u8) -> Option<Vec<TiffEntry>> {
+        // EXIF nests at most IFD0 -> EXIF -> interoperability
+        if depth > 2 {
+            return None;
+        }
+        let count = usize::from(self.u16(offset)?);
+        let mut entries = Vec::with_capacity(count);
+        for index in 0..count {
+            let at = offset + 2 + index * 12;
+            let tag = self.u16(at)?;
+            let kind = self.u16(at + 2)?;
+            let count = self.u32(at + 4)?;
+            if tag == EXIF_MAKER_NOTE {
+                continue;
+            }
+            let size = tiff_type_size(kind)?.checked_mul(count as usize)?;
+            let value_at = if size <= 4 {
+                at + 8
+            } else {
+                self.u32(at + 8)? as usize
+            };
+            let value = self
+                .bytes
+                .get(value_at..value_at.checked_add(size)?)?
+                .to_vec();
+            let sub_ifd = match tag {
+                EXIF_IFD_POINTER | GPS_IFD_POINTER | INTEROP_IFD_POINTER => {
+                    Some(self.ifd(self.u32(at + 8)? as usize, depth + 1)?)
+                }
+                _ => None,
+            };
+            entries.push(TiffEntry {
+                tag,
+                kind,
+                count,
+                value,
+                sub_ifd,
+            });
+        }
+        Some(entries)
+    }
+}
+
+struct TiffWriter {
+    out: Vec<u8>,
+    big_endian: bool,
+}
+
+impl TiffWriter {
+    fn u16_bytes(&self, value: u16) -> [u8; 2] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn u32_bytes(&self, value: u32) -> [u8; 4] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    /// Values must start on a word boundary
+    fn align(&mut self) {
+        if self.out.len() % 2 == 1 {
+            self.out.push(0);
+        }
+    }
+
+    /// Append an IFD, its out-of-line values and its sub-IFDs; returns the
+    /// IFD's offset
+    fn write_ifd(&mut self, entries: &[TiffEntry]) -> u32 {
+        self.align();
+        let start = self.out.len();
+        self.out.resize(start + 2 + entries.len() * 12 + 4, 0);
+        let count = self.u16_bytes(entries.len() as u16);
+        self.out[start..start + 2].copy_from_slice(&count);
+
+        for (index, entry) in entries.iter().enumerate() {
+            let at = start + 2 + index * 12;
+            let (tag, kind, count) = (
+                self.u16_bytes(entry.tag),
+                self.u16_bytes(entry.kind),
+                self.u32_bytes(entry.count),
+            );
+            self.out[at..at + 2].copy_from_slice(&tag);
+            self.out[at + 2..at + 4].copy_from_slice(&kind);
+            self.out[at + 4..at + 8].copy_from_slice(&count);
+
+            let field = if let Some(sub_ifd) = &entry.sub_ifd {
+                let offset = self.write_ifd(sub_ifd);
+                self.u32_bytes(offset)
+            } else if entry.value.len() <= 4 {
+                let mut field = [0; 4];
+                field[..entry.value.len()].copy_from_slice(&entry.value);
+                field
+            } else {
+                self.align();
+                let offset = self.out.len() as u32;
+                self.out.extend_from_slice(&entry.value);
+                self.u32_bytes(offset)
+            };
+            self.out[at + 8..at + 12].copy_from_slice(&field);
+        }
+        start as u32
+    }
+}
+
+/// Bytes per value of a TIFF field type
+fn tiff_type_size(kind: u16) -> Option<usize> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Helpers
+// ---------------------------------------------------------------------------
+
+fn read_input(input: &Path, retries: u32) -> Result<Vec<u8>> {
+    let bytes = retry_io(retries, || fs::read(input))
+        .with_context(|| format!("failed to read input file: {}", input.display()))?;
+    if bytes.is_empty() {
+        bail!("input file is empty: {}", input.display());
+    }
+    Ok(bytes)
+}
+
+/// Inputs at least this large are memory-mapped instead of read into a heap
+/// buffer
+#[cfg(feature = "mmap")]
+const MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Input file contents, read or memory-mapped; derefs to the bytes
+enum InputBytes {
+    Read(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl std::ops::Deref for InputBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputBytes::Read(bytes) => bytes,
+            #[cfg(feature = "mmap")]
+            InputBytes::Mapped(map) => map,
+        }
+    }
+}
+
+/// [`read_input`], except that with the `mmap` feature files of
+/// `MMAP_THRESHOLD` or more are mapped, so a multi-hundred-megabyte TIFF
+/// isn't copied to the heap before it is decoded
+fn open_input(input: &Path, retries: u32) -> Result<InputBytes> {
+    #[cfg(feature = "mmap")]
+    {
+        let file = retry_io(retries, || fs::File::open(input))
+            .with_context(|| format!("failed to read input file: {}", input.display()))?;
+        if file
+            .metadata()
+            .is_ok_and(|meta| meta.len() >= MMAP_THRESHOLD)
+        {
+            // SAFETY: the map is read-only and lives only while this file is
+            // compressed. As with any mapping, another process truncating the
+            // file meanwhile can fault; that is the accepted cost of `mmap`.
+            let map = unsafe { memmap2::Mmap::map(&file) }
+                .with_context(|| format!("failed to map input file: {}", input.display()))?;
+            return Ok(InputBytes::Mapped(map));
+        }
+    }
+    read_input(input, retries).map(InputBytes::Read)
+}
+
+fn decode_and_resize(
+    bytes: &[u8],
+    options: &CompressOptions,
+    timings: &mut StageTimings,
+) -> Result<DynamicImage> {
+    let started = Instant::now();
+    let image = if image::guess_format(bytes).ok() == Some(ImageFormat::Jpeg)
+        && let Some(image) = decode_jpeg_scaled(bytes, options)
+    {
+        image
+    } else if let Ok(format) = image::guess_format(bytes) {
+        // The header was recognized, so a decode failure here almost always
+        // means the data stops early or is damaged (e.g. a partial download)
+        image::load_from_memory_with_format(bytes, format).map_err(|err| match err {
+            image::ImageError::Decoding(_) | image::ImageError::IoError(_) => {
+                anyhow::Error::new(err)
+                    .context(format!("{format:?} file appears truncated or corrupt"))
+            }
+            other => anyhow::Error::new(other).context("failed to decode image"),
+        })?
+    } else {
+        image::load_from_memory(bytes).context("failed to decode image")?
+    };
+    timings.decode += started.elapsed();
+
+    let started = Instant::now();
+    let image = prepare_image(crop_image(image, options)?, options);
+    timings.resize += started.elapsed();
+    Ok(image)
+}
+
+/// Cut `options.crop` out of the full-size source, or fail if it reaches
+/// past the image
+fn crop_image(image: DynamicImage, options: &CompressOptions) -> Result<DynamicImage> {
+    let Some(crop) = options.crop else {
+        return Ok(image);
+    };
+    let (width, height) = (image.width(), image.height());
+    if u64::from(crop.x) + u64::from(crop.width) > u64::from(width)
+        || u64::from(crop.y) + u64::from(crop.height) > u64::from(height)
+    {
+        bail!("crop {crop} lies outside the {width}x{height} image");
+    }
+    Ok(image.crop_imm(crop.x, crop.y, crop.width, crop.height))
+}
+
+/// Decode a JPEG at 1/2, 1/4 or 1/8 scale straight from its DCT coefficients
+/// when the resize leaves enough room, so [`prepare_image`]'s Lanczos pass
+/// finishes from a fraction of the pixels. `None` means decode normally: no
+/// large reduction, crop, trim or an aspect ratio limit needs the full-size pixels,
+/// the file has no EOI marker (libjpeg would pad a truncated scan with gray
+/// instead of failing), it is CMYK, or libjpeg rejected it.
+fn decode_jpeg_scaled(bytes: &[u8], options: &CompressOptions) -> Option<DynamicImage> {
+    if options.crop.is_some() || options.trim.is_some() || options.max_aspect_ratio.is_some() {
+        return None;
+    }
+    if !bytes.ends_with(&[0xFF, 0xD9]) {
+        return None;
+    }
+
+    // libjpeg errors unwind out of the mozjpeg crate instead of returning
+    let decoded = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+        || -> std::io::Result<Option<DynamicImage>> {
+            let mut decompress = mozjpeg::Decompress::new_mem(bytes)?;
+            let (width, height) = decompress.size();
+            let numerator = dct_scale_numerator(width as u32, height as u32, options);
+            if numerator == 8 {
+                return Ok(None);
+            }
+            decompress.scale(numerator);
+
+            let gray = match decompress.color_space() {
+                mozjpeg::ColorSpace::JCS_GRAYSCALE => true,
+                mozjpeg::ColorSpace::JCS_YCbCr | mozjpeg::ColorSpace::JCS_RGB => false,
+                _ => return Ok(None),
+            };
+            let mut started = if gray {
+                decompress.grayscale()?
+            } else {
+                decompress.rgb()?
+            };
+            let (width, height) = (started.width() as u32, started.height() as u32);
+            let pixels = started.read_scanlines::<u8>()?;
+            started.finish()?;
+            Ok(if gray {
+                image::GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+            } else {
+                image::RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+            })
+        },
+    ));
+    decoded.ok()?.ok()?
+}
+
+/// Smallest libjpeg decode scale (`n/8` for n = 1, 2 or 4) that still leaves
+/// at least twice the final size on both axes, or 8 for a full-size decode
+fn dct_scale_numerator(width: u32, height: u32, options: &CompressOptions) -> u8 {
+    let (mut target_width, mut target_height) = match options.resize {
+        Some(resize) => resize_dimensions(width, height, resize),
+        None => (width, height),
+    };
+    if let Some(max) = options.max_dimension
+        && target_width.max(target_height) > max
+    {
+        (target_width, target_height) = fit_dimensions(target_width, target_height, max, max);
+    }
+
+    // libjpeg rounds scaled sizes up, so rounding down here stays on the safe side
+    [1u8, 2, 4]
+        .into_iter()
+        .find(|&numerator| {
+            let scaled = |side: u32| u64::from(side) * u64::from(numerator) / 8;
+            scaled(width) >= 2 * u64::from(target_width)
+                && scaled(height) >= 2 * u64::from(target_height)
+        })
+        .unwrap_or(8)
+}
+
+/// Every frame of an animated GIF or APNG, composited to the full canvas,
+/// with how many times it plays (0 = forever), or `None` for a still image
+/// (a one-frame animation included)
+fn decode_animation(bytes: &[u8]) -> Result<Option<(Vec<image::Frame>, u32)>> {
+    use image::AnimationDecoder;
+
+    let (frames, plays) = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => {
+            // The NETSCAPE loop count repeats after the first play; no
+            // count at all means the animation plays once
+            let plays = match gif::DecodeOptions::new()
+                .read_info(Cursor::new(bytes))?
+                .repeat()
+            {
+                gif::Repeat::Infinite => 0,
+                gif::Repeat::Finite(repeats) => u32::from(repeats) + 1,
+            };
+            let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(bytes))?;
+            (decoder.into_frames().collect_frames(), plays)
+        }
+        Ok(ImageFormat::Png) => {
+            let decoder = image::codecs::png::PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            let plays = png::Decoder::new(Cursor::new(bytes))
+                .read_info()?
+                .info()
+                .animation_control
+                .map_or(0, |control| control.num_plays);
+            (decoder.apng()?.into_frames().collect_frames(), plays)
+        }
+        _ => return Ok(None),
+    };
+    let frames = frames.context("failed to decode animation frames")?;
+    Ok((frames.len() > 1).then_some((frames, plays)))
+}
+
+/// PNG settings the APNG encoder doesn't apply: frames are written losslessly
+/// by the `png` crate, without oxipng, quantization or a quality check
+fn apng_ignored_options(options: &CompressOptions) -> Vec<&'static str> {
+    [
+        (options.png_level.is_some(), "png_level"),
+        (options.png_filter.is_some(), "png_filter"),
+        (options.png_optimize_alpha, "png_optimize_alpha"),
+        (options.quantize_colors.is_some(), "quantize_colors"),
+        (options.png_lossy_quality.is_some(), "png_lossy_quality"),
+        (options.min_psnr.is_some(), "min_psnr"),
+    ]
+    .into_iter()
+    .filter_map(|(ignored, name)| ignored.then_some(name))
+    .collect()
+}
+
+/// Encode `frames` as an APNG that plays `plays` times (0 = forever), each
+/// frame through [`prepare_image`] and shown for its own delay
+fn compress_apng(
+    frames: Vec<image::Frame>,
+    plays: u32,
+    options: &CompressOptions,
+) -> Result<(Vec<u8>, (u32, u32))> {
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            let image = crop_image(DynamicImage::ImageRgba8(frame.into_buffer()), options)?;
+            Ok((prepare_image(image, options).into_rgba8(), delay))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (width, height) = frames[0].0.dimensions();
+    if frames
+        .iter()
+        .any(|(image, _)| image.dimensions() != (width, height))
+    {
+        bail!("APNG frames came out at different sizes (trim crops each frame on its own)");
+    }
+
+    let mut apng = Vec::new();
+    let mut encoder = png::Encoder::new(&mut apng, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::High);
+    encoder.set_animated(frames.len() as u32, plays)?;
+    let mut writer = encoder.write_header()?;
+    for (image, delay) in &frames {
+        let (numer, denom) = delay.numer_denom_ms();
+        let millis = (f64::from(numer) / f64::from(denom)).round();
+        writer.set_frame_delay(millis.min(f64::from(u16::MAX)) as u16, 1000)?;
+        writer.write_image_data(image.as_raw())?;
+    }
+    writer.finish()?;
+    Ok((apng, (width, height)))
+}
+
+/// Apply the pixel edits (trim, aspect ratio limit, resize, max dimension,
+/// watermark) that run before encoding
+fn prepare_image(mut image: DynamicImage, options: &CompressOptions) -> DynamicImage {
+    if let Some(trim) = options.trim {
+        image = trim_borders(image, trim);
+    }
+
+    if let Some(max_ratio) = options.max_aspect_ratio {
+        image = limit_aspect_ratio(image, max_ratio, options.aspect_ratio_action);
+    }
+
+    if let Some(resize) = options.resize {
+        image = resize_image(
+            image,
+            resize,
+            options.resize_algorithm,
+            options.fast_downscale,
+            options.background,
+        );
+    }
+
+    if let Some(max) = options.max_dimension
+        && image.width().max(image.height()) > max
+    {
+        let fit = ResizeOptions {
+            width: max,
+            height: max,
+            mode: ResizeMode::Fit,
+        };
+        image = resize_image(
+            image,
+            fit,
+            options.resize_algorithm,
+            options.fast_downscale,
+            None,
+        );
+    }
+
+    if let Some(watermark) = &options.watermark {
+        image = apply_watermark(image, watermark);
+    }
+
+    image
+}
+
+/// Decode the encoded output and compare it with what went into the encoder.
+/// Below `min_psnr` this warns, or errors with `quality_guard_strict`. AVIF
+/// output can't be decoded by this build and lossless output is exact, so
+/// neither is measured.
+fn check_quality(
+    encoded_from: &DynamicImage,
+    compressed: &[u8],
+    format: OutputFormat,
+    options: &CompressOptions,
+    min_psnr: f64,
+    source_label: &str,
+) -> Result<Option<f64>> {
+    if !is_lossy_output(encoded_from, format, options) {
+        return Ok(None);
+    }
+
+    let decoded =
+        image::load_from_memory(compressed).context("failed to decode output for quality check")?;
+    let psnr = psnr_rgb(encoded_from, &decoded);
+    if psnr < min_psnr {
+        if options.quality_guard_strict {
+            bail!("PSNR {psnr:.1} dB is below the {min_psnr:.1} dB minimum");
+        }
+        eprintln!(
+            "warning: {source_label}: PSNR {psnr:.1} dB is below the {min_psnr:.1} dB minimum"
+        );
+    }
+    Ok(Some(psnr))
+}
+
+fn is_lossy_output(image: &DynamicImage, format: OutputFormat, options: &CompressOptions) -> bool {
+    match format {
+        OutputFormat::Jpeg => true,
+        OutputFormat::WebP => !webp_lossless(image, options),
+        OutputFormat::Png => {
+            options.quantize_colors.is_some() || options.png_lossy_quality.is_some()
+        }
+        OutputFormat::Avif => false,
+    }
+}
+
+/// Whether the encoder for `format` reads `quality` for this image
+fn uses_quality(image: &DynamicImage, format: OutputFormat, options: &CompressOptions) -> bool {
+    match format {
+        OutputFormat::Jpeg => true,
+        OutputFormat::WebP => !webp_lossless(image, options),
+        OutputFormat::Avif => !options.lossless,
+        OutputFormat::Png => false,
+    }
+}
+
+/// Mean 8x8-block luma standard deviation at which [`image_complexity`]
+/// reaches 1.0; dense foliage or gravel sits around here
+const COMPLEXITY_FULL_SCALE: f64 = 40.0;
+
+/// How much fine detail `image` has, from 0.0 (flat) to 1.0 (busy): the mean
+/// standard deviation of its 8x8 luma blocks, the ones JPEG's DCT works on,
+/// measured on a proxy at most 256 pixels on a side
+fn image_complexity(image: &DynamicImage) -> f64 {
+    const PROXY_SIDE: u32 = 256;
+    let luma = if image.width().max(image.height()) > PROXY_SIDE {
+        image
+            .resize(PROXY_SIDE, PROXY_SIDE, FilterType::Triangle)
+            .to_luma8()
+    } else {
+        image.to_luma8()
+    };
+
+    let (width, height) = luma.dimensions();
+    let mut total = 0.0;
+    let mut blocks = 0u32;
+    for block_y in (0..height).step_by(8) {
+        for block_x in (0..width).step_by(8) {
+            let (mut sum, mut sum_sq, mut count) = (0.0, 0.0, 0.0);
+            for y in block_y..(block_y + 8).min(height) {
+                for x in block_x..(block_x + 8).min(width) {
+                    let value = f64::from(luma.get_pixel(x, y).0[0]);
+                    sum += value;
+                    sum_sq += value * value;
+                    count += 1.0;
+                }
+            }
+            let mean = sum / count;
+            total += (sum_sq / count - mean * mean).max(0.0).sqrt();
+            blocks += 1;
+        }
+    }
+    if blocks == 0 {
+        return 0.0;
+    }
+    (total / f64::from(blocks) / COMPLEXITY_FULL_SCALE).min(1.0)
+}
+
+/// `max` for a flat image, falling linearly to `min` for a fully busy one.
+/// A reversed pair is swapped rather than trusted to have been validated.
+fn quality_for_complexity(complexity: f64, min: u8, max: u8) -> u8 {
+    let (min, max) = (min.min(max), min.max(max));
+    let span = f64::from(max - min);
+    max - (complexity.clamp(0.0, 1.0) * span).round() as u8
+}
+
+/// SSIM of the encoded output against what went into the encoder; lossless
+/// output is 1.0 without decoding
+fn output_ssim(
+    encoded_from: &DynamicImage,
+    compressed: &[u8],
+    format: OutputFormat,
+    options: &CompressOptions,
+) -> Result<f64> {
+    if !is_lossy_output(encoded_from, format, options) {
+        return Ok(1.0);
+    }
+    let decoded =
+        image::load_from_memory(compressed).context("failed to decode output for SSIM")?;
+    Ok(ssim_luma(encoded_from, &decoded))
+}
+
+/// Side of the square windows [`ssim_luma`] averages over
+const SSIM_WINDOW: u32 = 8;
+
+/// Mean structural similarity of the luma planes over non-overlapping 8x8
+/// windows (edge windows are smaller); 1.0 for identical images
+fn ssim_luma(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (a, b) = (a.to_luma8(), b.to_luma8());
+    let width = a.width().min(b.width());
+    let height = a.height().min(b.height());
+    let mut total = 0.0;
+    let mut windows = 0u32;
+    for y0 in (0..height).step_by(SSIM_WINDOW as usize) {
+        for x0 in (0..width).step_by(SSIM_WINDOW as usize) {
+            let (x1, y1) = (
+                (x0 + SSIM_WINDOW).min(width),
+                (y0 + SSIM_WINDOW).min(height),
+            );
+            let n = f64::from((x1 - x0) * (y1 - y0));
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let pa = f64::from(a.get_pixel(x, y)[0]);
+                    let pb = f64::from(b.get_pixel(x, y)[0]);
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        1.0
+    } else {
+        total / f64::from(windows)
+    }
+}
+
+/// Peak signal-to-noise ratio over the RGB channels, in dB (infinite when identical)
+fn psnr_rgb(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    let samples = a.as_raw().len().min(b.as_raw().len());
+    if samples == 0 {
+        return f64::INFINITY;
+    }
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| (f64::from(x) - f64::from(y)).powi(2))
+        .sum();
+    let mse = squared_error / samples as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+/// Keys each encoder understands in [`CompressOptions::encoder_opts`]
+pub fn encoder_opt_keys(format: OutputFormat) -> &'static [&'static str] {
+    match format {
+        OutputFormat::Jpeg => &["dc-scan-opt", "smoothing", "optimize-scans"],
+        OutputFormat::Png => &["deflate-level", "fast-evaluation"],
+        OutputFormat::WebP => &[
+            "method",
+            "sns-strength",
+            "filter-strength",
+            "segments",
+            "pass",
+        ],
+        OutputFormat::Avif => &["alpha-quality", "color-model"],
+    }
+}
+
+fn warn_unknown_encoder_opts(options: &CompressOptions, format: OutputFormat, source_label: &str) {
+    let known = encoder_opt_keys(format);
+    for key in options.encoder_opts.keys() {
+        if !known.contains(&key.as_str()) {
+            eprintln!(
+                "warning: {source_label}: encoder option {key} does not apply to {format}, ignored"
+            );
+        }
+    }
+}
+
+/// A raw encoder option parsed and range-checked; bad values are warned
+/// about and ignored
+fn encoder_opt<T>(
+    options: &CompressOptions,
+    key: &str,
+    range: std::ops::RangeInclusive<T>,
+) -> Option<T>
+where
+    T: std::str::FromStr + PartialOrd + fmt::Display,
+{
+    let value = options.encoder_opts.get(key)?;
+    match value.parse::<T>() {
+        Ok(parsed) if range.contains(&parsed) => Some(parsed),
+        _ => {
+            eprintln!(
+                "warning: ignoring encoder option {key}={value}: expected {}..={}",
+                range.start(),
+                range.end()
+            );
+            None
+        }
+    }
+}
+
+/// Composite `image` over `background`, returning an opaque RGB image
+fn flatten_onto(image: &DynamicImage, background: Background) -> DynamicImage {
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let row_color = |y: u32| match background {
+        Background::Solid(color) => color,
+        Background::VerticalGradient { top, bottom } => {
+            let t = if height > 1 {
+                y as f32 / (height - 1) as f32
+            } else {
+                0.0
+            };
+            std::array::from_fn(|c| {
+                (f32::from(top[c]) + (f32::from(bottom[c]) - f32::from(top[c])) * t).round() as u8
+            })
+        }
+    };
+
+    let flattened = image::RgbImage::from_fn(width, height, |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let backdrop = row_color(y);
+        let alpha = u16::from(a);
+        let blend = |fg: u8, bg: u8| {
+            ((u16::from(fg) * alpha + u16::from(bg) * (255 - alpha) + 127) / 255) as u8
+        };
+        image::Rgb([
+            blend(r, backdrop[0]),
+            blend(g, backdrop[1]),
+            blend(b, backdrop[2]),
+        ])
+    });
+    DynamicImage::ImageRgb8(flattened)
+}
+
+fn apply_watermark(image: DynamicImage, watermark: &WatermarkOptions) -> DynamicImage {
+    let had_alpha = image.color().has_alpha();
+
+    let mut overlay = (*watermark.overlay).clone();
+    if watermark.opacity < 1.0 {
+        for pixel in overlay.pixels_mut() {
+            pixel[3] = (f32::from(pixel[3]) * watermark.opacity).round() as u8;
+        }
+    }
+
+    let (width, height) = (i64::from(image.width()), i64::from(image.height()));
+    let (mark_width, mark_height) = (i64::from(overlay.width()), i64::from(overlay.height()));
+    let margin = i64::from(watermark.margin);
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (margin, margin),
+        WatermarkPosition::TopRight => (width - mark_width - margin, margin),
+        WatermarkPosition::BottomLeft => (margin, height - mark_height - margin),
+        WatermarkPosition::BottomRight => {
+            (width - mark_width - margin, height - mark_height - margin)
+        }
+        WatermarkPosition::Center => ((width - mark_width) / 2, (height - mark_height) / 2),
+    };
+
+    // Deep sources are blended at 16 bits, so their precision still reaches
+    // 10-bit AVIF and the dithering down to 8 bits
+    if is_high_bit_depth(&image) {
+        let mut base = image.to_rgba16();
+        let overlay = DynamicImage::ImageRgba8(overlay).to_rgba16();
+        image::imageops::overlay(&mut base, &overlay, x, y);
+        let stamped = DynamicImage::ImageRgba16(base);
+        return if had_alpha {
+            stamped
+        } else {
+            DynamicImage::ImageRgb16(stamped.to_rgb16())
+        };
+    }
+
+    let mut base = image.to_rgba8();
+    image::imageops::overlay(&mut base, &overlay, x, y);
+    let stamped = DynamicImage::ImageRgba8(base);
+    if had_alpha {
+        stamped
+    } else {
+        DynamicImage::ImageRgb8(stamped.to_rgb8())
+    }
+}
+
+fn trim_borders(image: DynamicImage, trim: TrimOptions) -> DynamicImage {
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    if width == 0 || height == 0 {
+        return image;
+    }
+
+    let border = match trim.color {
+        Some([r, g, b]) => [r, g, b, 255],
+        None => corner_color(&rgba),
+    };
+    let is_border = |x: u32, y: u32| {
+        let pixel = rgba.get_pixel(x, y).0;
+        pixel
+            .iter()
+            .zip(border)
+            .all(|(&channel, expected)| channel.abs_diff(expected) <= trim.tolerance)
+    };
+    let row_is_border = |y: u32| (0..width).all(|x| is_border(x, y));
+    let column_is_border = |x: u32, top: u32, bottom: u32| (top..bottom).all(|y| is_border(x, y));
+
+    let Some(top) = (0..height).find(|&y| !row_is_border(y)) else {
+        // Nothing but border: leave the image alone rather than erase it
+        return image;
+    };
+    let bottom = (top..height)
+        .rev()
+        .find(|&y| !row_is_border(y))
+        .unwrap_or(top)
+        + 1;
+    let left = (0..width)
+        .find(|&x| !column_is_border(x, top, bottom))
+        .unwrap_or(0);
+    let right = (left..width)
+        .rev()
+        .find(|&x| !column_is_border(x, top, bottom))
+        .unwrap_or(left)
+        + 1;
+
+    if (left, top, right, bottom) == (0, 0, width, height) {
+        return image;
+    }
+    image.crop_imm(left, top, right - left, bottom - top)
+}
+
+/// The color shared by most of the four corners (top-left on a tie)
+fn corner_color(rgba: &RgbaImage) -> [u8; 4] {
+    let (right, bottom) = (rgba.width() - 1, rgba.height() - 1);
+    let corners =
+        [(0, 0), (right, 0), (0, bottom), (right, bottom)].map(|(x, y)| rgba.get_pixel(x, y).0);
+    // `max_by_key` keeps the last maximum, so reverse to let top-left win ties
+    corners
+        .iter()
+        .rev()
+        .max_by_key(|corner| corners.iter().filter(|other| other == corner).count())
+        .copied()
+        .unwrap_or(corners[0])
+}
+
+/// Reduction factor (per axis) above which `fast_downscale` pre-shrinks
+const FAST_DOWNSCALE_THRESHOLD: u32 = 4;
+
+/// Apply `action` to an image whose long side is more than `max_ratio` times
+/// its short side
+fn limit_aspect_ratio(
+    image: DynamicImage,
+    max_ratio: f64,
+    action: AspectRatioAction,
+) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let (long, short) = (width.max(height), width.min(height));
+    if f64::from(long) <= f64::from(short) * max_ratio {
+        return image;
+    }
+    match action {
+        AspectRatioAction::Warn => {
+            eprintln!(
+                "warning: {width}x{height} image exceeds the {max_ratio}:1 aspect ratio limit"
+            );
+            image
+        }
+        AspectRatioAction::Crop => {
+            let kept = (f64::from(short) * max_ratio).round().max(1.0) as u32;
+            if width > height {
+                image.crop_imm((width - kept) / 2, 0, kept, height)
+            } else {
+                image.crop_imm(0, (height - kept) / 2, width, kept)
+            }
+        }
+    }
+}
+
+/// Cap on the side a single-axis fit leaves free (JPEG's limit, and more
+/// than any other encoder here takes), so a 10000x1 strip fit to a height of
+/// 100 doesn't turn into a million pixels wide
+const MAX_FIT_SIDE: u32 = 65_535;
+
+fn resize_image(
+    image: DynamicImage,
+    resize: ResizeOptions,
+    algorithm: ResizeAlgorithm,
+    fast_downscale: bool,
+    background: Option<Background>,
+) -> DynamicImage {
+    let (width, height) = resize_dimensions(image.width(), image.height(), resize);
+    let scaled = scale_image(image, width, height, algorithm, fast_downscale);
+    if resize.mode == ResizeMode::Pad {
+        return pad_to(&scaled, resize.width, resize.height, background);
+    }
+    scaled
+}
+
+/// Size a `width`x`height` image is scaled to before any padding
+fn resize_dimensions(width: u32, height: u32, resize: ResizeOptions) -> (u32, u32) {
+    match resize.mode {
+        ResizeMode::Fit | ResizeMode::Pad => {
+            fit_dimensions(width, height, resize.width, resize.height)
+        }
+        ResizeMode::Exact => (resize.width, resize.height),
+        ResizeMode::FitWidth => fit_dimensions(width, height, resize.width, MAX_FIT_SIDE),
+        ResizeMode::FitHeight => fit_dimensions(width, height, MAX_FIT_SIDE, resize.height),
+    }
+}
+
+fn scale_image(
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+    algorithm: ResizeAlgorithm,
+    fast_downscale: bool,
+) -> DynamicImage {
+    let area_average = match algorithm {
+        ResizeAlgorithm::Resize => false,
+        ResizeAlgorithm::Thumbnail => true,
+        ResizeAlgorithm::Auto => {
+            image.width() >= width.saturating_mul(AUTO_AREA_AVERAGE_FACTOR)
+                && image.height() >= height.saturating_mul(AUTO_AREA_AVERAGE_FACTOR)
+        }
+    };
+    if area_average {
+        return image.thumbnail_exact(width, height);
+    }
+
+    let image = if fast_downscale
+        && image.width() / width > FAST_DOWNSCALE_THRESHOLD
+        && image.height() / height > FAST_DOWNSCALE_THRESHOLD
+    {
+        image.thumbnail_exact(width.saturating_mul(2), height.saturating_mul(2))
+    } else {
+        image
+    };
+
+    image.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+/// Center `image` on a `width`x`height` canvas, transparent or `background`
+fn pad_to(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    background: Option<Background>,
+) -> DynamicImage {
+    let mut canvas = RgbaImage::new(width, height);
+    let x = (width - image.width()) / 2;
+    let y = (height - image.height()) / 2;
+    image::imageops::overlay(&mut canvas, &image.to_rgba8(), x.into(), y.into());
+    let canvas = DynamicImage::ImageRgba8(canvas);
+    match background {
+        Some(background) => flatten_onto(&canvas, background),
+        None => canvas,
+    }
+}
+
+/// Largest size with the source aspect ratio that fits the bounds (same
+/// rounding as `DynamicImage::resize`)
+fn fit_dimensions(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let ratio = f64::min(
+        f64::from(max_width) / f64::from(width),
+        f64::from(max_height) / f64::from(height),
+    );
+    let scale = |value: u32| {
+        (f64::from(value) * ratio)
+            .round()
+            .clamp(1.0, f64::from(u32::MAX)) as u32
+    };
+    (scale(width), scale(height))
+}
+
+fn validate_input_and_output(input: &Path, output: &Path, options: &CompressOptions) -> Result<()> {
+    if !input.is_file() {
+        bail!("input file not found: {}", input.display());
+    }
+
+    validate_output(output, options)
+}
+
+fn validate_output(output: &Path, options: &CompressOptions) -> Result<()> {
+    if output.exists() && !options.overwrite {
+        bail!(
+            "output file exists (use --overwrite to replace): {}",
+            output.display()
+        );
+    }
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    }
+
+    Ok(())
+}
+
+fn write_output(output: &Path, bytes: &[u8], options: &CompressOptions) -> Result<()> {
+    let retries = options.io_retries;
+    if !options.atomic_write {
+        return retry_io(retries, || fs::write(output, bytes))
+            .with_context(|| format!("failed to write output file: {}", output.display()));
+    }
+
+    let temp_path = temp_path_for(output, options.temp_dir.as_deref())?;
+    let result = retry_io(retries, || fs::write(&temp_path, bytes))
+        .with_context(|| format!("failed to write temp file: {}", temp_path.display()))
+        .and_then(|()| move_into_place(&temp_path, output, retries));
+    if result.is_err() {
+        fs::remove_file(&temp_path).ok();
+    }
+    result
+}
+
+/// Rename `temp` over `output`. Renames can't cross filesystems (EXDEV), so a
+/// temp file from `temp_dir` on another mount is copied next to `output` and
+/// renamed from there instead, keeping the final replace atomic.
+fn move_into_place(temp: &Path, output: &Path, retries: u32) -> Result<()> {
+    let context = || format!("failed to move temp file into place: {}", output.display());
+    match retry_io(retries, || fs::rename(temp, output)) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            let sibling = temp_path_for(output, None)?;
+            let result = retry_io(retries, || fs::copy(temp, &sibling))
+                .and_then(|_| retry_io(retries, || fs::rename(&sibling, output)));
+            if result.is_err() {
+                fs::remove_file(&sibling).ok();
+            }
+            result.with_context(context)?;
+            fs::remove_file(temp).ok();
+            Ok(())
+        }
+        result => result.with_context(context),
+    }
+}
+
+/// Delay before the first IO retry; doubled for each further attempt
+const IO_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Run `op`, retrying up to `retries` times with exponential backoff while it
+/// fails with a transient error (see [`CompressOptions::io_retries`])
+fn retry_io<T>(retries: u32, mut op: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(err) if attempt < retries && is_transient_io_error(&err) => {
+                // Capped so a large retry count waits at most ~6 s per attempt
+                std::thread::sleep(IO_RETRY_BACKOFF * 2u32.pow(attempt.min(6)));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// EINTR, EAGAIN and timeouts; everything else (ENOENT, EACCES, ...) is
+/// treated as permanent
+fn is_transient_io_error(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    matches!(
+        err.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/// Hidden sibling of `output` (or file in `temp_dir`) used for atomic writes
+fn temp_path_for(output: &Path, temp_dir: Option<&Path>) -> Result<PathBuf> {
+    let file_name = output
+        .file_name()
+        .context("output path must include a file name")?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    Ok(match temp_dir {
+        Some(dir) => dir.join(temp_name),
+        None => output.with_file_name(temp_name),
+    })
+}
+
+fn collect_input_files(input_dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    if recursive {
+        for entry in WalkDir::new(input_dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+        {
+            if entry.file_type().is_file() {
+                files.push(entry.into_path());
+            }
+        }
+    } else {
+        for entry in fs::read_dir(input_dir)
+            .with_context(|| format!("failed to read directory: {}", input_dir.display()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+
+    // Directory order varies by platform and filesystem; sorting keeps
+    // progress output, `--limit` and resume runs reproducible
+    files.sort();
+    Ok(files)
+}
+
+/// Input files for a batch run in path order, cut to `batch.limit`
+fn batch_input_files(input_dir: &Path, batch: &BatchOptions) -> Result<Vec<PathBuf>> {
+    let mut files = collect_input_files(input_dir, batch.recursive)?;
+    if let Some(limit) = batch.limit {
+        files.truncate(limit);
+    }
+    Ok(files)
+}
+
+/// Where a batch source is written under `output_dir`
+fn batch_target_path(
+    output_dir: &Path,
+    source_path: &Path,
+    relative_path: &Path,
+    to_extension: &str,
+    batch: &BatchOptions,
+) -> PathBuf {
+    output_dir.join(batch_relative_target(
+        source_path,
+        relative_path,
+        to_extension,
+        batch,
+    ))
+}
+
+/// A file name's extension, except that a dotted tail with whitespace in it
+/// is part of the name: `at 10.15.32 AM` has no extension, not `32 AM`
+fn name_extension(path: &Path) -> Option<&OsStr> {
+    path.extension()
+        .filter(|ext| !ext.as_encoded_bytes().iter().any(u8::is_ascii_whitespace))
+}
+
+/// The file name without its [`name_extension`]
+fn name_stem(path: &Path) -> Option<&OsStr> {
+    if name_extension(path).is_some() {
+        path.file_stem()
+    } else {
+        path.file_name()
+    }
+}
+
+/// `path` with its [`name_extension`] replaced by `extension` (or added)
+fn with_name_extension(path: &Path, extension: impl AsRef<OsStr>) -> PathBuf {
+    if name_extension(path).is_some() {
+        return path.with_extension(extension);
+    }
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(extension);
+    name.into()
+}
+
+/// A batch source's output path relative to the output directory: the
+/// structure-depth fold, then the name template, the format folder and the
+/// output extension
+fn batch_relative_target(
+    source_path: &Path,
+    relative_path: &Path,
+    to_extension: &str,
+    batch: &BatchOptions,
+) -> PathBuf {
+    let folded = limit_structure_depth(relative_path, batch.keep_structure_depth);
+    let target_path = match &batch.name_template {
+        Some(template) => template.render(&folded),
+        None => folded,
+    };
+    let extension = batch_target_extension(source_path, relative_path, batch, to_extension);
+    let target_path = if batch.per_format_subdir {
+        // `jpg` and `jpeg` outputs share one folder
+        let folder = OutputFormat::from_extension(&extension).map_or_else(
+            |_| extension.to_ascii_lowercase(),
+            |format| format.to_string(),
+        );
+        Path::new(&folder).join(target_path)
+    } else {
+        target_path
+    };
+    if batch.name_template.is_none() && !batch.preserve_input_extension {
+        return with_name_extension(&target_path, extension);
+    }
+
+    // A mirrored path still ends with the source's extension; a template's
+    // `{stem}` doesn't, so it is put back first when preserving it. Either
+    // way a rendered name is only appended to, as dots in `{stem}` belong to it
+    let mut name = target_path.into_os_string();
+    if batch.name_template.is_some()
+        && batch.preserve_input_extension
+        && let Some(source_extension) = name_extension(relative_path)
+    {
+        name.push(".");
+        name.push(source_extension);
+    }
+    name.push(".");
+    name.push(extension);
+    name.into()
+}
+
+/// Output extension for a batch source: a matching rule's `to`, its own
+/// format with `follow_input_format`, otherwise the batch target
+fn batch_target_extension(
+    source_path: &Path,
+    relative_path: &Path,
+    batch: &BatchOptions,
+    to_extension: &str,
+) -> String {
+    if let Some(to) = matching_rule(&batch.rules, relative_path).and_then(|rule| rule.to.as_ref()) {
+        return to.clone();
+    }
+    let kept_extension = if batch.follow_input_format {
+        source_format_extension(source_path)
+    } else {
+        None
+    };
+    kept_extension.unwrap_or_else(|| to_extension.to_string())
+}
+
+/// Extension that keeps a source in its detected format, or `None` if that
+/// format can't be written. The source's own spelling (e.g. `.jpeg`) is kept
+/// when it agrees with the content.
+fn source_format_extension(source_path: &Path) -> Option<String> {
+    let detected = image::ImageReader::open(source_path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .format()?;
+    let format = OutputFormat::from_image_format(detected)?;
+
+    let own_extension = source_path.extension().and_then(|ext| ext.to_str());
+    match own_extension {
+        Some(ext) if OutputFormat::from_extension(ext).ok() == Some(format) => {
+            Some(ext.to_string())
+        }
+        _ => Some(format.extension().to_string()),
+    }
+}
+
+/// Whether `modified_since` is set and the source's mtime is older. Sources
+/// whose mtime can't be read are processed.
+fn unchanged_since(source_path: &Path, batch: &BatchOptions) -> bool {
+    batch.modified_since.is_some_and(|since| {
+        fs::metadata(source_path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified < since)
+    })
+}
+
+/// Neither the content nor the extension is an image format this build
+/// decodes; unreadable files are left for the normal error path
+fn is_non_image(source_path: &Path) -> bool {
+    image::ImageReader::open(source_path)
+        .ok()
+        .and_then(|reader| reader.with_guessed_format().ok())
+        .is_some_and(|reader| reader.format().is_none())
+}
+
+/// `target_path` with the source's own extension (or none) for a verbatim copy
+fn verbatim_target(target_path: &Path, source_path: &Path, batch: &BatchOptions) -> PathBuf {
+    if batch.preserve_input_extension && name_extension(source_path).is_some() {
+        // Already `name.ext.<output>`; dropping the appended part is enough
+        return target_path.with_extension("");
+    }
+    target_path.with_extension(name_extension(source_path).unwrap_or_default())
+}
+
+/// True when `source` was modified after `target`, or either time can't be
+/// read (rebuilding is the safe choice)
+fn source_is_newer(source: &Path, target: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified());
+    match (modified(source), modified(target)) {
+        (Ok(source), Ok(target)) => source > target,
+        _ => true,
+    }
+}
+
+/// `skip_optimized` for one batch file, with its rule and quality-map
+/// settings applied; unreadable sources are left for the normal error path
+fn skip_as_optimized(
+    source_path: &Path,
+    target_path: &Path,
+    options: &CompressOptions,
+    batch: &BatchOptions,
+    relative_path: &Path,
+) -> bool {
+    if !batch.skip_optimized {
+        return false;
+    }
+    let Some(format) = target_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| OutputFormat::from_extension(ext).ok())
+    else {
+        return false;
+    };
+    let file_options = batch_file_options(options, batch, relative_path);
+    read_input(source_path, file_options.io_retries)
+        .is_ok_and(|bytes| already_optimized(&bytes, format, &file_options))
+}
+
+/// The source is already in `format` and no larger than a re-encode is
+/// estimated to be, so compressing it again would likely not help
+fn already_optimized(input_bytes: &[u8], format: OutputFormat, options: &CompressOptions) -> bool {
+    let source_format = image::guess_format(input_bytes)
+        .ok()
+        .and_then(OutputFormat::from_image_format);
+    source_format == Some(format)
+        && decode_and_resize(input_bytes, options, &mut StageTimings::default())
+            .and_then(|image| estimate_encoded_size(&image, format, options))
+            .is_ok_and(|estimate| input_bytes.len() as u64 <= estimate)
+}
+
+/// `skip_marked` for one batch file, with its rule and quality-map settings
+/// applied; unreadable sources are left for the normal error path
+fn skip_as_marked(
+    source_path: &Path,
+    target_path: &Path,
+    options: &CompressOptions,
+    batch: &BatchOptions,
+    relative_path: &Path,
+) -> bool {
+    use std::io::Read;
+
+    if !batch.skip_marked {
+        return false;
+    }
+    let Some(format) = target_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| OutputFormat::from_extension(ext).ok())
+    else {
+        return false;
+    };
+    let marker = output_marker(format, &batch_file_options(options, batch, relative_path));
+    let mut head = Vec::new();
+    fs::File::open(source_path)
+        .and_then(|file| {
+            file.take(OUTPUT_MARKER_SEARCH_BYTES as u64)
+                .read_to_end(&mut head)
+        })
+        .is_ok_and(|_| {
+            head.windows(marker.len())
+                .any(|window| window == marker.as_bytes())
+        })
+}
+
+fn matching_rule<'a>(rules: &'a [BatchRule], relative_path: &Path) -> Option<&'a BatchRule> {
+    rules.iter().find(|rule| rule.matches(relative_path))
+}
+
+/// `options` with the file's matching rule and quality-map entry applied
+fn batch_file_options(
+    options: &CompressOptions,
+    batch: &BatchOptions,
+    relative_path: &Path,
+) -> CompressOptions {
+    let mut file_options = options.clone();
+    if let Some(rule) = matching_rule(&batch.rules, relative_path) {
+        rule.apply(&mut file_options);
+    }
+    if let Some(quality) = mapped_quality(&batch.quality_map, relative_path) {
+        file_options.quality = Some(quality);
+    }
+    file_options
+}
+
+/// Quality override for a batch file: an exact relative-path entry wins over
+/// a bare file name
+fn mapped_quality(map: &BTreeMap<String, u8>, relative_path: &Path) -> Option<u8> {
+    if map.is_empty() {
+        return None;
+    }
+    let key = relative_path
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    map.get(&key)
+        .or_else(|| {
+            let name = relative_path.file_name()?.to_string_lossy();
+            map.get(name.as_ref())
+        })
+        .copied()
+}
+
+/// Keep the first `depth` directories of `relative_path` and flatten the rest
+/// into the file name, e.g. `a/b/c/x.png` at depth 1 becomes `a/b_c_x.png`
+fn limit_structure_depth(relative_path: &Path, depth: Option<usize>) -> PathBuf {
+    let components: Vec<_> = relative_path.components().collect();
+    match depth {
+        Some(depth) if components.len() > depth + 1 => {
+            let (kept, folded) = components.split_at(depth);
+            let mut file_name = OsString::new();
+            for (i, part) in folded.iter().enumerate() {
+                if i > 0 {
+                    file_name.push("_");
+                }
+                file_name.push(part.as_os_str());
+            }
+            kept.iter().collect::<PathBuf>().join(file_name)
+        }
+        _ => relative_path.to_path_buf(),
+    }
+}
+
+/// The per-file line printed by the batch functions, plus stage timings on
+/// stderr when `verbose`
+fn print_file_result(
+    source: &str,
+    target: &str,
+    stats: &CompressionStats,
+    units: SizeUnits,
+    verbose: bool,
+) {
+    match stats.kept_original {
+        Some(reason) => println!("kept original {source}: {reason}"),
+        None => println!(
+            "compressed {} \u{2192} {} ({} \u{2192} {}, saved {:.1}%)",
+            source,
+            target,
+            units.format(stats.original_bytes),
+            units.format(stats.compressed_bytes),
+            stats.savings_percent,
+        ),
+    }
+    if verbose {
+        eprintln!("  {}", stats.timings);
+    }
+}
+
+/// First component of a relative path when it lives in a subdirectory
+fn top_level_directory(relative_path: &Path) -> String {
+    let mut components = relative_path.components();
+    match (components.next(), components.next()) {
+        (Some(first), Some(_)) => first.as_os_str().to_string_lossy().into_owned(),
+        _ => ".".to_string(),
+    }
+}
+
+fn load_resume_state(state_path: &Path) -> Result<HashMap<String, String>> {
+    if !state_path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let contents = fs::read_to_string(state_path)
+        .with_context(|| format!("failed to read state file: {}", state_path.display()))?;
+    Ok(contents.lines().filter_map(parse_resume_line).collect())
+}
+
+/// Parse one state line; a torn trailing line from an interrupted run is ignored
+fn parse_resume_line(line: &str) -> Option<(String, String)> {
+    let value: Value = serde_json::from_str(line).ok()?;
+    let source = value.get("source")?.as_str()?;
+    let hash = value.get("hash")?.as_str()?;
+    Some((source.to_string(), hash.to_string()))
+}
+
+fn append_resume_state(state_path: &Path, source: &str, hash: &str) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_path)
+        .with_context(|| format!("failed to open state file: {}", state_path.display()))?;
+    writeln!(file, "{}", json!({ "source": source, "hash": hash }))
+        .with_context(|| format!("failed to write state file: {}", state_path.display()))
+}
+
+/// `target` exists and holds exactly `bytes` (sizes are compared first, so
+/// most changed outputs are told apart without reading them)
+fn output_is_identical(target: &Path, bytes: &[u8]) -> bool {
+    fs::metadata(target).is_ok_and(|meta| meta.len() == bytes.len() as u64)
+        && fs::read(target).is_ok_and(|existing| existing == bytes)
+}
+
+/// Add `target`'s SHA-256 and size to a `--manifest`, keyed by its path
+/// relative to `output_dir`
+fn record_manifest_entry(
+    manifest: &mut BTreeMap<String, Value>,
+    output_dir: &Path,
+    target: &Path,
+    contents: &[u8],
+) {
+    let relative = target.strip_prefix(output_dir).unwrap_or(target);
+    let key = relative
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    manifest.insert(
+        key,
+        json!({ "sha256": sha256_hex(contents), "bytes": contents.len() }),
+    );
+}
+
+fn write_manifest(path: &Path, manifest: &BTreeMap<String, Value>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    let text = serde_json::to_string_pretty(manifest)?;
+    fs::write(path, text + "\n")
+        .with_context(|| format!("failed to write manifest: {}", path.display()))
+}
+
+/// SHA-256 of `bytes`, lowercase hex as printed by `sha256sum`
+fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// [`sha256_hex`] of the file contents, as recorded in the resume state
+fn hash_file(path: &Path) -> Result<String> {
+    let bytes =
+        fs::read(path).with_context(|| format!("failed to read file: {}", path.display()))?;
+    Ok(sha256_hex(&bytes))
+}
+
+fn normalize_extension(extension: &str) -> Result<String> {
+    let extension = extension.trim().trim_start_matches('.');
+    if extension.is_empty() {
+        bail!("format/extension cannot be empty");
+    }
+    Ok(extension.to_ascii_lowercase())
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes >= 1_000_000 {
+        format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+    } else if bytes >= 1_000 {
+        format!("{:.0} KB", bytes as f64 / 1_000.0)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+pub fn format_size_binary(bytes: u64) -> String {
+    if bytes >= 1 << 20 {
+        format!("{:.1} MiB", bytes as f64 / f64::from(1 << 20))
+    } else if bytes >= 1 << 10 {
+        format!("{:.0} KiB", bytes as f64 / f64::from(1 << 10))
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    #[test]
+    fn parse_supported_output_format_extensions() {
+        assert_eq!(
+            OutputFormat::from_extension("jpg").unwrap(),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_extension("jpeg").unwrap(),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            OutputFormat::from_extension("png").unwrap(),
+            OutputFormat::Png
+        );
+        assert_eq!(
+            OutputFormat::from_extension("webp").unwrap(),
+            OutputFormat::WebP
+        );
+        assert_eq!(
+            OutputFormat::from_extension("avif").unwrap(),
+            OutputFormat::Avif
+        );
+        assert_eq!(OutputFormat::Avif.default_quality(), Some(80));
+        assert_eq!(OutputFormat::Png.default_quality(), None);
+        for format in OutputFormat::ALL {
+            assert_eq!(
+                OutputFormat::from_extension(format.extension()).unwrap(),
+                format
+            );
+            assert!(format.mime_type().ends_with(format.to_string().as_str()));
+        }
+    }
+
+    #[test]
+    fn supported_formats_cover_all_outputs() {
+        let formats = supported_formats();
+        assert_eq!(formats.output, OutputFormat::ALL);
+        for ext in ["jpg", "png", "webp", "gif", "bmp", "tiff"] {
+            assert!(formats.input.contains(&ext), "missing input format {ext}");
+        }
+    }
+
+    #[test]
+    fn reject_unknown_output_extension() {
+        assert!(OutputFormat::from_extension("bmp").is_err());
+        assert!(OutputFormat::from_extension("gif").is_err());
+        assert!(OutputFormat::from_extension("tiff").is_err());
+    }
+
+    #[test]
+    fn normalize_extension_values() {
+        assert_eq!(normalize_extension(" JPG ").unwrap(), "jpg");
+        assert_eq!(normalize_extension(".WebP").unwrap(), "webp");
+        assert_eq!(normalize_extension("AVIF").unwrap(), "avif");
+    }
+
+    #[test]
+    fn reject_empty_extension() {
+        assert!(normalize_extension("").is_err());
+        assert!(normalize_extension("  ").is_err());
+        assert!(normalize_extension(".").is_err());
+    }
+
+    #[test]
+    fn validate_resize_bounds() {
+        assert!(ResizeOptions::new(0, 200, ResizeMode::Fit).is_err());
+        assert!(ResizeOptions::new(200, 0, ResizeMode::Fit).is_err());
+        assert!(ResizeOptions::new(200, 100, ResizeMode::Fit).is_ok());
+        assert!(ResizeOptions::new(200, 0, ResizeMode::FitWidth).is_ok());
+        assert!(ResizeOptions::new(0, 100, ResizeMode::FitWidth).is_err());
+        assert!(ResizeOptions::new(0, 100, ResizeMode::FitHeight).is_ok());
+        assert!(ResizeOptions::new(200, 0, ResizeMode::FitHeight).is_err());
+    }
+
+    #[test]
+    fn default_compress_options() {
+        let opts = CompressOptions::default();
+        assert!(!opts.overwrite);
+        assert!(opts.quality.is_none());
+        assert!(!opts.lossless);
+        assert!(!opts.progressive);
+        assert!(opts.strip_metadata);
+        assert!(opts.resize.is_none());
+        assert!(opts.png_level.is_none());
+        assert!(opts.avif_speed.is_none());
+        assert!(!opts.avif_premultiplied_alpha);
+        assert!(!opts.webp_exact);
+        assert!(!opts.webp_sharp_yuv);
+        assert!(opts.webp_alpha_filter.is_none());
+        assert!(!opts.fast_downscale);
+        assert!(opts.atomic_write);
+        assert!(!opts.png_optimize_alpha);
+        assert_eq!(opts.jpeg_trellis, None);
+        assert_eq!(opts.jpeg_optimize_coding, None);
+        assert!(opts.background.is_none());
+        assert!(!opts.deterministic);
+        assert!(opts.min_psnr.is_none());
+        assert!(!opts.quality_guard_strict);
+        assert!(opts.encoder_opts.is_empty());
+        assert_eq!(opts.avif_tiles, None);
+        assert!(!opts.auto_lossless);
+        assert!(opts.trim.is_none());
+        assert!(opts.watermark.is_none());
+        assert!(opts.png_filter.is_none());
+        assert!(opts.compare_original.is_none());
+        assert!(!opts.strip_exif_thumbnail);
+        assert_eq!(opts.quantize_colors, None);
+        assert_eq!(opts.png_lossy_quality, None);
+        assert!(opts.dither);
+        assert!(!opts.assume_srgb);
+        assert_eq!(opts.io_retries, 0);
+        assert_eq!(opts.resize_algorithm, ResizeAlgorithm::Resize);
+        assert_eq!(opts.output_bit_depth, None);
+        assert_eq!(opts.temp_dir, None);
+        assert!(!opts.xmp_sidecar);
+        assert!(!opts.apng);
+        assert!(opts.max_dimension.is_none());
+        assert!(opts.avif_preset.is_none());
+        assert!(!opts.mark_output);
+        assert!(opts.max_aspect_ratio.is_none());
+        assert!(!opts.two_pass);
+        assert!(opts.crop.is_none());
+        assert!(opts.adaptive_quality.is_none());
+    }
+
+    #[test]
+    fn format_size_display() {
+        assert_eq!(format_size(500), "500 B");
+        assert_eq!(format_size(1_500), "2 KB");
+        assert_eq!(format_size(2_400_000), "2.4 MB");
+    }
+
+    #[test]
+    fn format_size_binary_display() {
+        assert_eq!(format_size_binary(1_000), "1000 B");
+        assert_eq!(format_size_binary(1_536), "2 KiB");
+        assert_eq!(format_size_binary(2_516_582), "2.4 MiB");
+        assert_eq!(SizeUnits::Binary.format(2_048), "2 KiB");
+        assert_eq!(SizeUnits::Decimal.format(2_048), "2 KB");
+    }
+
+    #[test]
+    fn embed_webp_metadata_builds_extended_container() {
+        let image = DynamicImage::new_rgb8(3, 2);
+        let webp = compress_webp(&image, &CompressOptions::default()).unwrap();
+        let metadata = SourceMetadata {
+            exif: Some(b"II*\0exif".to_vec()),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            ..SourceMetadata::default()
+        };
+
+        let out = embed_webp_metadata(webp, &metadata, 3, 2).unwrap();
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(out[4..8].try_into().unwrap()) as usize,
+            out.len() - 8
+        );
+        assert_eq!(&out[12..16], b"VP8X");
+        assert_eq!(out[20] & 0x0c, 0x0c);
+        assert_eq!(&out[24..27], &[2, 0, 0]);
+        assert_eq!(&out[27..30], &[1, 0, 0]);
+        assert!(out.windows(4).any(|w| w == b"EXIF"));
+        assert!(out.windows(4).any(|w| w == b"XMP "));
+
+        let decoded = image::load_from_memory_with_format(&out, ImageFormat::WebP).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (3, 2));
+    }
+
+    #[test]
+    fn estimate_is_exact_for_small_images() {
+        let image = DynamicImage::new_rgb8(64, 48);
+        let options = CompressOptions::default();
+        let actual = compress_jpeg(&image, &options, &SourceMetadata::default()).unwrap();
+        let estimate = estimate_encoded_size(&image, OutputFormat::Jpeg, &options).unwrap();
+        assert_eq!(estimate, actual.len() as u64);
+    }
+
+    #[test]
+    fn top_level_directory_buckets() {
+        assert_eq!(top_level_directory(Path::new("a.png")), ".");
+        assert_eq!(top_level_directory(Path::new("photos/a.png")), "photos");
+        assert_eq!(
+            top_level_directory(Path::new("photos/2024/a.png")),
+            "photos"
+        );
+    }
+
+    #[test]
+    fn truncated_input_is_reported() {
+        let image = DynamicImage::new_rgb8(32, 32);
+        let png = compress_png(&[], Some(&image), &CompressOptions::default()).unwrap();
+
+        let err = decode_and_resize(
+            &png[..png.len() / 2],
+            &CompressOptions::default(),
+            &mut StageTimings::default(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("truncated or corrupt"), "{err}");
+    }
+
+    #[test]
+    fn watermark_blends_into_anchored_corner() {
+        let base =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(10, 10, image::Rgb([255; 3])));
+        let watermark = WatermarkOptions {
+            overlay: Arc::new(RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255]))),
+            position: WatermarkPosition::BottomRight,
+            opacity: 0.5,
+            margin: 1,
+        };
+
+        let stamped = apply_watermark(base, &watermark);
+        assert!(!stamped.color().has_alpha());
+        let stamped = stamped.to_rgb8();
+        assert_eq!(stamped.get_pixel(7, 7).0, [255, 127, 127]);
+        assert_eq!(stamped.get_pixel(9, 9).0, [255, 255, 255]);
+        assert_eq!(stamped.get_pixel(0, 0).0, [255, 255, 255]);
+
+        // A 16-bit source keeps its depth and values between 8-bit steps
+        let deep = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(
+            10,
+            10,
+            image::Rgb([1000u16; 3]),
+        ));
+        let stamped = apply_watermark(deep, &watermark);
+        assert_eq!(stamped.color(), image::ColorType::Rgb16);
+        assert_eq!(stamped.to_rgb16().get_pixel(0, 0).0, [1000; 3]);
+    }
+
+    #[test]
+    fn trim_removes_uniform_borders() {
+        let framed = image::RgbImage::from_fn(20, 10, |x, y| {
+            if (5..15).contains(&x) && (2..7).contains(&y) {
+                image::Rgb([(x * 10) as u8, 0, 0])
+            } else {
+                image::Rgb([250, 250, 250])
+            }
+        });
+        let image = DynamicImage::ImageRgb8(framed);
+
+        let auto = TrimOptions {
+            color: None,
+            tolerance: 8,
+        };
+        assert_eq!(trim_borders(image.clone(), auto).dimensions(), (10, 5));
+
+        let white = TrimOptions {
+            color: Some([255, 255, 255]),
+            tolerance: 2,
+        };
+        assert_eq!(trim_borders(image, white).dimensions(), (20, 10));
+    }
+
+    #[test]
+    fn graphic_detection_separates_flat_and_noisy_images() {
+        let flat = image::RgbImage::from_fn(64, 64, |x, _| {
+            if x < 32 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        });
+        assert!(looks_graphic(&DynamicImage::ImageRgb8(flat)));
+
+        // Smooth gradient with a distinct color in every pixel
+        let photo = image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        });
+        assert!(!looks_graphic(&DynamicImage::ImageRgb8(photo)));
+    }
+
+    #[test]
+    fn psnr_drops_as_images_diverge() {
+        let base = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([100; 3])));
+        let near = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([101; 3])));
+        let far = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([160; 3])));
+
+        assert!(psnr_rgb(&base, &base).is_infinite());
+        assert!((psnr_rgb(&base, &near) - 48.13).abs() < 0.01);
+        assert!(psnr_rgb(&base, &far) < 20.0);
+    }
+
+    #[test]
+    fn compare_original_keeps_worse_output() {
+        let gradient = RgbaImage::from_fn(32, 32, |x, y| {
+            image::Rgba([(x * 8) as u8, (y * 8) as u8, 90, 255])
+        });
+        let mut bmp = Vec::new();
+        DynamicImage::ImageRgba8(gradient)
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        let mut options = CompressOptions {
+            quality: Some(5),
+            compare_original: Some(0.999),
+            ..CompressOptions::default()
+        };
 
-    let img = imgref::Img::new(pixels, width as usize, height as usize);
+        let (_, stats) = compress_in_memory(&bmp, OutputFormat::Jpeg, &options, "test").unwrap();
+        assert!(stats.ssim.unwrap() < 0.999);
+        assert_eq!(stats.kept_original, Some(KeptOriginal::BelowSsimFloor));
+
+        options.quality = Some(90);
+        options.compare_original = Some(0.9);
+        let (_, stats) = compress_in_memory(&bmp, OutputFormat::Jpeg, &options, "test").unwrap();
+        assert_eq!(stats.kept_original, None);
+
+        let (_, stats) = compress_in_memory(&bmp, OutputFormat::Png, &options, "test").unwrap();
+        assert_eq!(stats.ssim, Some(1.0));
+        assert!(compress_in_memory(&bmp, OutputFormat::Avif, &options, "test").is_err());
+    }
+
+    #[test]
+    fn flatten_onto_gradient_blends_by_alpha() {
+        let mut rgba = RgbaImage::new(1, 3);
+        rgba.put_pixel(0, 0, image::Rgba([9, 9, 9, 0]));
+        rgba.put_pixel(0, 1, image::Rgba([200, 0, 0, 255]));
+        rgba.put_pixel(0, 2, image::Rgba([9, 9, 9, 0]));
+        let background = Background::VerticalGradient {
+            top: [0, 0, 0],
+            bottom: [255, 255, 255],
+        };
+
+        let flat = flatten_onto(&DynamicImage::ImageRgba8(rgba), background).to_rgb8();
+        assert_eq!(flat.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(flat.get_pixel(0, 1).0, [200, 0, 0]);
+        assert_eq!(flat.get_pixel(0, 2).0, [255, 255, 255]);
+    }
+
+    #[test]
+    fn quality_map_prefers_relative_path_over_file_name() {
+        let map = parse_quality_map(
+            "# hero shots\n\"hero.jpg\" = 95\n\"blog/hero.jpg\" = 70\nthumb = 40\n",
+        )
+        .unwrap();
+        assert_eq!(mapped_quality(&map, Path::new("hero.jpg")), Some(95));
+        assert_eq!(
+            mapped_quality(&map, Path::new("gallery/hero.jpg")),
+            Some(95)
+        );
+        assert_eq!(mapped_quality(&map, Path::new("blog/hero.jpg")), Some(70));
+        assert_eq!(mapped_quality(&map, Path::new("other.jpg")), None);
+
+        assert!(parse_quality_map("\"a.jpg\" = 0").is_err());
+        assert!(parse_quality_map("\"a.jpg\" = \"high\"").is_err());
+    }
+
+    #[test]
+    fn batch_rules_route_by_first_matching_prefix() {
+        let rules = parse_batch_rules(
+            "[[rule]]\nprefix = \"photos/\"\nto = \"JPG\"\nquality = 80\n\n\
+             [[rule]]\nprefix = \"icons\"\nto = \"png\"\nkeep_metadata = true\n\n\
+             [[rule]]\nprefix = \"\"\nquality = 60\n",
+        )
+        .unwrap();
+        let batch = BatchOptions {
+            rules,
+            ..BatchOptions::default()
+        };
+        let options = CompressOptions::default();
+
+        let photo = Path::new("photos/a.bmp");
+        assert_eq!(batch_target_extension(photo, photo, &batch, "webp"), "jpg");
+        assert_eq!(
+            batch_file_options(&options, &batch, photo).quality,
+            Some(80)
+        );
+        let icon = Path::new("icons/x/logo.bmp");
+        assert_eq!(batch_target_extension(icon, icon, &batch, "webp"), "png");
+        assert!(!batch_file_options(&options, &batch, icon).strip_metadata);
+        // Prefixes match whole components only; the catch-all keeps --to
+        let other = Path::new("photos2/b.bmp");
+        assert_eq!(batch_target_extension(other, other, &batch, "webp"), "webp");
+        assert_eq!(
+            batch_file_options(&options, &batch, other).quality,
+            Some(60)
+        );
+
+        assert!(parse_batch_rules("[[rule]]\nquality = 80\n").is_err());
+        assert!(parse_batch_rules("[[rule]]\nprefix = \"a\"\nto = \"gif\"\n").is_err());
+        assert!(parse_batch_rules("[[rule]]\nprefix = \"a\"\nqualty = 80\n").is_err());
+    }
+
+    #[test]
+    fn exact_resize_dimensions_are_verified_from_headers() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            33,
+            17,
+            image::Rgb([40, 90, 160]),
+        ));
+        let options = CompressOptions {
+            avif_speed: Some(10),
+            ..CompressOptions::default()
+        };
+        let exact = ResizeOptions::new(33, 17, ResizeMode::Exact).unwrap();
+        let other = ResizeOptions::new(34, 17, ResizeMode::Exact).unwrap();
+
+        for format in OutputFormat::ALL {
+            let encoded =
+                encode_image(&image, format, &options, &SourceMetadata::default()).unwrap();
+            assert_eq!(
+                encoded_dimensions(&encoded, format).unwrap(),
+                (33, 17),
+                "{format}"
+            );
+            assert!(verify_exact_dimensions(&encoded, format, exact).is_ok());
+            assert!(verify_exact_dimensions(&encoded, format, other).is_err());
+        }
+    }
+
+    #[test]
+    fn opaque_alpha_is_detected() {
+        let mut rgba = RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255]));
+        assert!(is_opaque(&DynamicImage::ImageRgba8(rgba.clone())));
+        assert!(is_opaque(&DynamicImage::ImageRgb8(image::RgbImage::new(
+            4, 4
+        ))));
+        rgba.put_pixel(3, 3, image::Rgba([10, 20, 30, 254]));
+        assert!(!is_opaque(&DynamicImage::ImageRgba8(rgba)));
+    }
+
+    #[test]
+    fn since_accepts_rfc3339_and_durations() {
+        let at = |seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+        assert_eq!(parse_since("1970-01-02").unwrap(), at(86_400));
+        assert_eq!(
+            parse_since("2024-05-01T08:00:00Z").unwrap(),
+            at(1_714_550_400)
+        );
+        assert_eq!(
+            parse_since("2024-05-01T10:00:00.250+02:00").unwrap(),
+            at(1_714_550_400)
+        );
+        assert_eq!(
+            parse_since("2000-03-01T00:00:00Z").unwrap(),
+            at(951_868_800)
+        );
+
+        let day_ago = parse_since("24h").unwrap();
+        let elapsed = SystemTime::now().duration_since(day_ago).unwrap();
+        assert!(elapsed >= Duration::from_secs(86_400) && elapsed < Duration::from_secs(86_460));
+
+        for bad in [
+            "",
+            "yesterday",
+            "7x",
+            "2024-13-01",
+            "2024-05-01T08:00",
+            "2024-05-01T08:00:00+0200",
+        ] {
+            assert!(parse_since(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn in_memory_stats_describe_returned_bytes() {
+        let mut bmp = Vec::new();
+        DynamicImage::new_rgb8(40, 30)
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        let options = CompressOptions::default();
+
+        let (bytes, stats) = compress_bytes_with_stats(&bmp, OutputFormat::Jpeg, &options).unwrap();
+        assert_eq!(stats.original_bytes, bmp.len() as u64);
+        assert_eq!(stats.compressed_bytes, bytes.len() as u64);
+        assert_eq!((stats.width, stats.height), (40, 30));
+        assert!(stats.savings_percent > 0.0);
+        assert!(compress_bytes_with_stats(&[], OutputFormat::Jpeg, &options).is_err());
+    }
+
+    #[test]
+    fn empty_encoder_output_is_an_error() {
+        let err = non_empty_output(Vec::new(), "WebP").unwrap_err();
+        assert_eq!(err.to_string(), "WebP encoder returned no data");
+        assert_eq!(non_empty_output(vec![1], "AVIF").unwrap(), [1]);
+    }
+
+    #[test]
+    fn raw_rgba8_buffers_are_checked_and_compressed() {
+        let options = CompressOptions::default();
+        let output = Path::new("never-written.png");
+        let short = vec![0u8; 4 * 4 * 4 - 1];
+        let err = compress_raw_rgba8(&short, 4, 4, output, OutputFormat::Png, &options)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("expected 64"), "{err}");
+        assert!(compress_raw_rgba8(&[], 0, 4, output, OutputFormat::Png, &options).is_err());
+        let comparing = CompressOptions {
+            compare_original: Some(0.9),
+            ..CompressOptions::default()
+        };
+        let pixels = vec![0u8; 4 * 4 * 4];
+        let err = compress_raw_rgba8(&pixels, 4, 4, output, OutputFormat::Png, &comparing)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("compare_original"), "{err}");
+        assert!(!output.exists());
+
+        let pixels = RgbaImage::from_pixel(20, 10, image::Rgba([10, 200, 30, 255]));
+        let (bytes, stats) = compress_source(
+            SourceImage::Decoded(DynamicImage::ImageRgba8(pixels)),
+            OutputFormat::Png,
+            &options,
+            "raw",
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(stats.original_bytes, 20 * 10 * 4);
+        assert_eq!(image::load_from_memory(&bytes).unwrap().width(), 20);
+    }
+
+    #[test]
+    fn quantized_png_is_indexed_with_limited_colors() {
+        // Noisy truecolor content that filters can't predict
+        let noise = image::RgbImage::from_fn(64, 64, |x, y| {
+            let mut hash = (y * 64 + x + 1).wrapping_mul(2_654_435_761);
+            hash ^= hash >> 15;
+            hash = hash.wrapping_mul(hash | 1);
+            image::Rgb([(hash >> 8) as u8, (hash >> 16) as u8, (hash >> 24) as u8])
+        });
+        let mut bmp = Vec::new();
+        DynamicImage::ImageRgb8(noise)
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        let full = compress_bytes(&bmp, OutputFormat::Png, &CompressOptions::default()).unwrap();
+        let options = CompressOptions {
+            quantize_colors: Some(16),
+            compare_original: Some(0.0),
+            ..CompressOptions::default()
+        };
+        let (png, stats) = compress_bytes_with_stats(&bmp, OutputFormat::Png, &options).unwrap();
+        assert!(png.len() < full.len());
+        assert!(stats.ssim.unwrap() < 1.0);
+
+        // IHDR color type 3 = indexed
+        assert_eq!(png[25], 3);
+        let decoded = image::load_from_memory(&png).unwrap().to_rgba8();
+        let colors: std::collections::HashSet<_> = decoded.pixels().collect();
+        assert!(colors.len() <= 16);
+    }
+
+    #[test]
+    fn lossy_png_picks_fewest_colors_within_quality() {
+        // 16 flat blocks, each with 4 slight shades
+        let blocks = image::RgbImage::from_fn(64, 64, |x, y| {
+            let block = (x / 16 + y / 16 * 4) as u8;
+            let shade = (x % 4) as u8 * 3;
+            image::Rgb([block * 15 + shade, 255 - block * 15, block * 16 + shade])
+        });
+        let image = DynamicImage::ImageRgb8(blocks);
+
+        let coarse = quantize_to_quality(&image, (0, 50), false).unwrap();
+        let fine = quantize_to_quality(&image, (0, 95), false).unwrap();
+        let count = |image: &DynamicImage| {
+            let rgba = image.to_rgba8();
+            rgba.pixels()
+                .collect::<std::collections::HashSet<_>>()
+                .len()
+        };
+        assert!(count(&coarse) < count(&fine));
+        assert!(count(&fine) <= 256);
+        assert!(mse_rgba(&image.to_rgba8(), &fine.to_rgba8()) <= quality_to_mse(95));
+
+        // 4096 distinct colors can't be exact with a palette
+        let gradient = image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        });
+        let gradient = DynamicImage::ImageRgb8(gradient);
+        assert!(quantize_to_quality(&gradient, (100, 100), true).is_none());
+
+        // Dithering mixes palette colors, it doesn't add any
+        let dithered = quantize_colors(&gradient, 8, true);
+        let flat = quantize_colors(&gradient, 8, false);
+        assert!(count(&dithered) <= 8);
+        assert_ne!(dithered, flat);
+        assert!(quality_to_mse(60) > quality_to_mse(80));
+    }
+
+    #[test]
+    fn name_template_fills_path_and_clock_tokens() {
+        // 2024-02-29T13:05:09Z
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_211_909);
+        let template = NameTemplate::new("export/{date}/{dir}/{stem}_{time}.webp", now).unwrap();
+        assert_eq!(
+            template.render(Path::new("trips/rome/a.jpg")),
+            Path::new("export/2024-02-29/trips/rome/a_130509")
+        );
+        // Top-level files leave no empty directory behind
+        assert_eq!(
+            template.render(Path::new("a.jpg")),
+            Path::new("export/2024-02-29/a_130509")
+        );
+
+        let stamped = NameTemplate::new("{{{timestamp}}}-{stem}", now).unwrap();
+        assert_eq!(
+            stamped.render(Path::new("b.png")),
+            Path::new("{1709211909}-b")
+        );
+
+        assert!(NameTemplate::new("{date}.webp", now).is_err());
+        assert!(NameTemplate::new("{stem}-{size}", now).is_err());
+        assert!(NameTemplate::new("../{stem}", now).is_err());
+        assert!(NameTemplate::new("{stem", now).is_err());
+        assert_eq!(
+            civil_from_days(days_from_civil(1999, 12, 31)),
+            (1999, 12, 31)
+        );
+    }
+
+    #[test]
+    fn optimized_sources_in_the_target_format_are_detected() {
+        let photo = image::RgbImage::from_fn(96, 96, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, ((x * y) % 256) as u8])
+        });
+        let mut bmp = Vec::new();
+        DynamicImage::ImageRgb8(photo)
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        let low = CompressOptions {
+            quality: Some(40),
+            ..CompressOptions::default()
+        };
+        let jpeg = compress_bytes(&bmp, OutputFormat::Jpeg, &low).unwrap();
+
+        let default = CompressOptions::default();
+        assert!(already_optimized(&jpeg, OutputFormat::Jpeg, &default));
+        // A different output format always needs the encode
+        assert!(!already_optimized(&jpeg, OutputFormat::WebP, &default));
+        assert!(!already_optimized(&bmp, OutputFormat::Jpeg, &default));
+    }
+
+    #[test]
+    fn non_images_keep_their_own_extension_when_copied() {
+        let batch = BatchOptions::default();
+        let target = Path::new("out/css/site.webp");
+        assert_eq!(
+            verbatim_target(target, Path::new("in/css/site.css"), &batch),
+            Path::new("out/css/site.css")
+        );
+        assert_eq!(
+            verbatim_target(
+                Path::new("out/LICENSE.webp"),
+                Path::new("in/LICENSE"),
+                &batch
+            ),
+            Path::new("out/LICENSE")
+        );
+        assert!(!is_non_image(Path::new("no/such/file.css")));
+    }
+
+    #[test]
+    fn preserved_input_extension_is_kept_before_the_new_one() {
+        let mut batch = BatchOptions {
+            preserve_input_extension: true,
+            ..BatchOptions::default()
+        };
+        let target = |relative: &str| {
+            let relative = Path::new(relative);
+            batch_relative_target(relative, relative, "webp", &batch)
+        };
+        assert_eq!(target("img/photo.jpg"), Path::new("img/photo.jpg.webp"));
+        assert_eq!(target("a.tar.gz"), Path::new("a.tar.gz.webp"));
+        assert_eq!(target("README"), Path::new("README.webp"));
+        assert_eq!(
+            verbatim_target(
+                Path::new("out/site.css.webp"),
+                Path::new("site.css"),
+                &batch
+            ),
+            Path::new("out/site.css")
+        );
+
+        batch.name_template =
+            Some(NameTemplate::new("flat/{stem}", SystemTime::UNIX_EPOCH).unwrap());
+        let relative = Path::new("img/a.tar.gz");
+        assert_eq!(
+            batch_relative_target(relative, relative, "webp", &batch),
+            Path::new("flat/a.tar.gz.webp")
+        );
+        let relative = Path::new("img/at 10.15.32 AM");
+        assert_eq!(
+            batch_relative_target(relative, relative, "webp", &batch),
+            Path::new("flat/at 10.15.32 AM.webp")
+        );
+    }
+
+    #[test]
+    fn input_files_are_sorted_and_limited_by_path() {
+        let dir = std::env::temp_dir().join(format!("icr-order-{}", std::process::id()));
+        for name in ["c.png", "a/z.png", "b.png", "a/b.png"] {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let relative = |batch: &BatchOptions| -> Vec<String> {
+            batch_input_files(&dir, batch)
+                .unwrap()
+                .iter()
+                .map(|path| path.strip_prefix(&dir).unwrap().display().to_string())
+                .collect()
+        };
+
+        let mut batch = BatchOptions {
+            recursive: true,
+            ..BatchOptions::default()
+        };
+        assert_eq!(relative(&batch), ["a/b.png", "a/z.png", "b.png", "c.png"]);
+        batch.limit = Some(3);
+        assert_eq!(relative(&batch), ["a/b.png", "a/z.png", "b.png"]);
+        batch.recursive = false;
+        assert_eq!(relative(&batch), ["b.png", "c.png"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn large_inputs_are_memory_mapped() {
+        let path = std::env::temp_dir().join(format!("icr-mmap-{}.bin", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        file.set_len(MMAP_THRESHOLD).unwrap();
+        drop(file);
+
+        let bytes = open_input(&path, 0).unwrap();
+        assert!(matches!(bytes, InputBytes::Mapped(_)));
+        assert_eq!(bytes.len() as u64, MMAP_THRESHOLD);
+        drop(bytes);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn untagged_sources_can_be_tagged_as_srgb() {
+        let profile = srgb_icc_profile(false);
+        assert_eq!(
+            u32::from_be_bytes(profile[..4].try_into().unwrap()),
+            profile.len() as u32
+        );
+        assert_eq!(&profile[36..40], b"acsp");
+
+        let mut bmp = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            16,
+            16,
+            image::Rgb([200, 80, 20]),
+        ))
+        .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+        .unwrap();
+        let options = CompressOptions::builder()
+            .assume_srgb(true)
+            .build()
+            .unwrap();
+        for format in [OutputFormat::Jpeg, OutputFormat::WebP] {
+            let output = compress_bytes(&bmp, format, &options).unwrap();
+            assert_eq!(read_source_metadata(&output).icc.as_ref(), Some(&profile));
+        }
+
+        let png = compress_bytes(&bmp, OutputFormat::Png, &options).unwrap();
+        assert_eq!(png.windows(4).filter(|w| w == b"sRGB").count(), 1);
+        let retagged = tag_png_srgb(
+            png.clone(),
+            &SourceMetadata {
+                srgb: true,
+                ..SourceMetadata::default()
+            },
+        );
+        assert_eq!(retagged, png);
+        assert!(image::load_from_memory(&png).is_ok());
+
+        let untagged =
+            compress_bytes(&bmp, OutputFormat::Png, &CompressOptions::default()).unwrap();
+        assert!(!untagged.windows(4).any(|w| w == b"sRGB"));
+    }
+
+    #[test]
+    fn probe_reads_headers_without_decoding() {
+        let path = std::env::temp_dir().join(format!("icr-probe-{}.png", std::process::id()));
+        DynamicImage::new_rgba8(30, 20).save(&path).unwrap();
+
+        let info = probe_image(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(info.format, "png");
+        assert_eq!((info.width, info.height), (30, 20));
+        assert_eq!(info.color, "Rgba8");
+        assert!(info.has_alpha);
+        assert_eq!(info.exif_bytes + info.xmp_bytes + info.icc_bytes, 0);
+        assert!(probe_image(&path).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_survive_batch_path_rewrites() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Latin-1 "café", not valid UTF-8
+        let name = OsStr::from_bytes(b"caf\xe9");
+        let relative = Path::new("deep")
+            .join(name)
+            .join(name)
+            .with_extension("jpg");
+
+        let folded = limit_structure_depth(&relative, Some(0));
+        let expected = [b"deep_".as_slice(), b"caf\xe9_caf\xe9.jpg"].concat();
+        assert_eq!(folded.as_os_str().as_bytes(), expected);
+
+        let template = NameTemplate::new("{dir}/x-{stem}", SystemTime::UNIX_EPOCH).unwrap();
+        let rendered = template.render(&relative);
+        let mut stem = OsString::from("x-");
+        stem.push(name);
+        assert_eq!(rendered, Path::new("deep").join(name).join(stem));
+
+        let temp = temp_path_for(&Path::new("out").join(name), None).unwrap();
+        assert!(
+            temp.file_name()
+                .unwrap()
+                .as_bytes()
+                .starts_with(b".caf\xe9.")
+        );
+    }
+
+    #[test]
+    fn only_sources_newer_than_their_output_are_rebuilt() {
+        let dir = std::env::temp_dir().join(format!("icr-newer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (source, target) = (dir.join("a.png"), dir.join("a.webp"));
+        fs::write(&source, b"").unwrap();
+        fs::write(&target, b"").unwrap();
+        let set_modified = |path: &Path, secs: u64| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        set_modified(&source, 1_000);
+        set_modified(&target, 2_000);
+        assert!(!source_is_newer(&source, &target));
+        set_modified(&source, 3_000);
+        assert!(source_is_newer(&source, &target));
+        assert!(source_is_newer(&source, &dir.join("missing.webp")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn custom_encoders_replace_the_built_in_one() {
+        /// Stand-in that tags the built-in PNG so the test can tell it ran
+        struct Tagged;
+        impl ImageEncoder for Tagged {
+            fn encode(&self, image: &DynamicImage, options: &CompressOptions) -> Result<Vec<u8>> {
+                let mut png = OutputFormat::Png.encode(image, options)?;
+                png.extend_from_slice(b"tagged");
+                Ok(png)
+            }
+        }
+
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let options = CompressOptions::default();
+        let source = SourceImage::Encoded(&png);
+        let (bytes, stats) = compress_source(
+            source,
+            OutputFormat::Png,
+            &options,
+            "t",
+            Some(&Tagged),
+            None,
+        )
+        .unwrap();
+        assert!(bytes.ends_with(b"tagged"));
+        assert_eq!((stats.width, stats.height), (8, 8));
+
+        let (bytes, _) = compress_bytes_with_stats(&png, OutputFormat::Png, &options).unwrap();
+        assert!(!bytes.ends_with(b"tagged"));
+    }
+
+    #[test]
+    fn manifest_lists_written_outputs_by_sha256() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let dir = std::env::temp_dir().join(format!("icr-manifest-{}", std::process::id()));
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(input.join("sub")).unwrap();
+        DynamicImage::new_rgb8(8, 8)
+            .save(input.join("sub").join("a.bmp"))
+            .unwrap();
+        let batch = BatchOptions {
+            recursive: true,
+            manifest: Some(dir.join("manifest.json")),
+            ..BatchOptions::default()
+        };
+        compress_directory_with_progress(
+            &input,
+            &output,
+            "png",
+            &CompressOptions::default(),
+            &batch,
+            |_| {},
+        )
+        .unwrap();
+
+        let manifest: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+        let written = fs::read(output.join("sub").join("a.png")).unwrap();
+        assert_eq!(manifest["sub/a.png"]["sha256"], sha256_hex(&written));
+        assert_eq!(manifest["sub/a.png"]["bytes"], written.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn estimate_batch_projects_without_writing() {
+        let dir = std::env::temp_dir().join(format!("icr-estimate-{}", std::process::id()));
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(&input).unwrap();
+        DynamicImage::new_rgb8(32, 32)
+            .save(input.join("a.bmp"))
+            .unwrap();
+
+        let report = estimate_batch(
+            &input,
+            &output,
+            "webp",
+            &CompressOptions::default(),
+            &BatchOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.compressed, 1);
+        assert_eq!(
+            report.total_original_bytes,
+            fs::metadata(input.join("a.bmp")).unwrap().len()
+        );
+        assert!(report.total_compressed_bytes > 0);
+        assert!(report.by_format.contains_key("webp"));
+        assert!(!output.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_writes_can_stage_in_another_directory() {
+        let dir = std::env::temp_dir().join(format!("icr-tempdir-{}", std::process::id()));
+        let (staging, output) = (dir.join("staging"), dir.join("out.png"));
+        fs::create_dir_all(&staging).unwrap();
+        assert_eq!(
+            temp_path_for(&output, Some(&staging)).unwrap(),
+            staging.join(format!(".out.png.{}.tmp", std::process::id()))
+        );
+
+        let options = CompressOptions {
+            temp_dir: Some(staging.clone()),
+            ..CompressOptions::default()
+        };
+        write_output(&output, b"data", &options).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"data");
+        assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn savings_histogram_buckets_by_twenty_percent() {
+        let mut report = BatchReport::default();
+        for savings_percent in [-5.0, 0.0, 19.9, 20.0, 55.0, 80.0, 99.0] {
+            let stats = CompressionStats {
+                savings_percent,
+                ..CompressionStats::default()
+            };
+            report.record(&stats, "png".into(), ".".into());
+        }
+        assert_eq!(report.savings_histogram, [3, 1, 1, 0, 2]);
+    }
+
+    #[test]
+    fn unique_colors_stop_counting_past_the_limit() {
+        let rgba = RgbaImage::from_fn(16, 16, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        assert_eq!(unique_colors_up_to(&rgba, 256), Some(256));
+        assert_eq!(unique_colors_up_to(&rgba, 255), None);
+        assert_eq!(unique_colors_up_to(&RgbaImage::new(4, 4), 1), Some(1));
+    }
+
+    #[test]
+    fn identical_outputs_are_not_rewritten() {
+        let dir = std::env::temp_dir().join(format!("icr-identical-{}", std::process::id()));
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(&input).unwrap();
+        DynamicImage::new_rgb8(8, 8)
+            .save(input.join("a.bmp"))
+            .unwrap();
+        let options = CompressOptions {
+            overwrite: true,
+            ..CompressOptions::default()
+        };
+        let batch = BatchOptions {
+            skip_identical: true,
+            ..BatchOptions::default()
+        };
+        let run = || {
+            compress_directory_with_progress(&input, &output, "png", &options, &batch, |_| {})
+                .unwrap()
+        };
+
+        assert_eq!(run().compressed, 1);
+        let target = output.join("a.png");
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&target)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let report = run();
+        assert_eq!((report.compressed, report.skipped_identical), (0, 1));
+        assert_eq!(fs::metadata(&target).unwrap().modified().unwrap(), old);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_transient_io_errors_are_retried() {
+        use std::io::{Error, ErrorKind};
+
+        let mut calls = 0;
+        let result = retry_io(2, || {
+            calls += 1;
+            if calls < 3 {
+                Err(Error::from(ErrorKind::Interrupted))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: std::io::Result<()> = retry_io(2, || {
+            calls += 1;
+            Err(Error::from(ErrorKind::NotFound))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn deep_sources_are_dithered_to_8_bit() {
+        // 128.5 in 8-bit terms: rounding gives a flat 128 (or 129), dithering
+        // mixes both so the average survives
+        let gray = image::ImageBuffer::from_pixel(16, 16, image::Luma([33_025u16]));
+        let image = DynamicImage::ImageLuma16(gray);
+        let dithered = dithered_to_8_bit(&image).to_rgba8();
+        assert!(
+            dithered
+                .pixels()
+                .all(|p| p[0] == p[1] && p[1] == p[2] && p[3] == 255)
+        );
+        let values: Vec<u8> = dithered.pixels().map(|p| p[0]).collect();
+        assert!(values.contains(&128) && values.contains(&129));
+        let mean = values.iter().map(|&v| f64::from(v)).sum::<f64>() / values.len() as f64;
+        assert!((mean - 33_025.0 / 257.0).abs() < 0.05, "mean {mean}");
+
+        let eight_bit = DynamicImage::new_rgb8(4, 4);
+        assert!(matches!(
+            dithered_to_8_bit(&eight_bit),
+            std::borrow::Cow::Borrowed(_)
+        ));
+
+        let options = CompressOptions {
+            output_bit_depth: Some(10),
+            ..CompressOptions::default()
+        };
+        assert!(compress_webp(&image, &options).is_err());
+    }
+
+    #[test]
+    fn bilevel_color_images_encode_as_gray() {
+        // A black-and-white page stored as RGB
+        let page = image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([if (x / 4 + y / 3) % 2 == 0 { 0 } else { 255 }; 3])
+        });
+        let page = DynamicImage::ImageRgb8(page);
+        let gray = as_grayscale(&page).unwrap();
+        assert_eq!(gray.color(), image::ColorType::L8);
+        assert_eq!(gray.to_rgb8(), page.to_rgb8());
+        assert!(as_grayscale(&DynamicImage::new_luma8(4, 4)).is_none());
+        let mut tinted = page.to_rgb8();
+        tinted.put_pixel(0, 0, image::Rgb([10, 0, 0]));
+        assert!(as_grayscale(&DynamicImage::ImageRgb8(tinted)).is_none());
+
+        let options = CompressOptions::default();
+        let none = SourceMetadata::default();
+        let png = encode_image(&page, OutputFormat::Png, &options, &none).unwrap();
+        // IHDR: bit depth 1, color type 0 (grayscale)
+        assert_eq!((png[24], png[25]), (1, 0));
+        let jpeg = encode_image(&page, OutputFormat::Jpeg, &options, &none).unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+    }
+
+    #[test]
+    fn throughput_is_per_elapsed_second() {
+        let report = BatchReport {
+            compressed: 6,
+            total_original_bytes: 12_000_000,
+            elapsed: Duration::from_secs(3),
+            ..BatchReport::default()
+        };
+        assert_eq!(report.files_per_sec(), 2.0);
+        assert_eq!(report.mb_per_sec(), 4.0);
+        assert_eq!(BatchReport::default().files_per_sec(), 0.0);
+    }
+
+    #[test]
+    fn builder_sets_fields_and_validates() {
+        let options = CompressOptions::builder()
+            .quality(70)
+            .progressive(true)
+            .resize(ResizeOptions::new(800, 600, ResizeMode::Fit).unwrap())
+            .png_filter(PngFilter::Paeth)
+            .encoder_opt("method", "6")
+            .build()
+            .unwrap();
+        assert_eq!(options.quality, Some(70));
+        assert!(options.progressive);
+        assert_eq!(options.resize.unwrap().width, 800);
+        assert_eq!(options.png_filter, Some(PngFilter::Paeth));
+        assert_eq!(options.encoder_opts["method"], "6");
+        assert!(options.strip_metadata);
+
+        assert!(CompressOptions::builder().quality(0).build().is_err());
+        assert!(CompressOptions::builder().avif_speed(11).build().is_err());
+        assert!(
+            CompressOptions::builder()
+                .output_bit_depth(12)
+                .build()
+                .is_err()
+        );
+        assert!(
+            CompressOptions::builder()
+                .quality_guard_strict(true)
+                .build()
+                .is_err()
+        );
+        assert!(
+            CompressOptions::builder()
+                .compare_original(1.5)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn ignored_options_depend_on_format() {
+        let options = CompressOptions {
+            quality: Some(50),
+            lossless: true,
+            progressive: true,
+            avif_speed: Some(6),
+            png_level: Some(4),
+            ..CompressOptions::default()
+        };
+        assert_eq!(
+            ignored_options(&options, OutputFormat::WebP),
+            ["quality", "progressive", "png_level", "avif_speed"]
+        );
+        assert_eq!(
+            ignored_options(&options, OutputFormat::Jpeg),
+            ["lossless", "png_level", "avif_speed"]
+        );
+        assert_eq!(
+            ignored_options(&options, OutputFormat::Png),
+            ["quality", "lossless", "progressive", "avif_speed"]
+        );
+        assert!(ignored_options(&CompressOptions::default(), OutputFormat::Avif).is_empty());
+    }
+
+    #[test]
+    fn exif_thumbnail_and_maker_note_are_stripped() {
+        // Little-endian TIFF: IFD0 {Make, ExifIFD} -> IFD1 {thumbnail offset,
+        // length}; EXIF IFD {ExposureTime, MakerNote}
+        let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+        let entry = |tag: u16, kind: u16, count: u32, value: u32| {
+            [
+                &tag.to_le_bytes()[..],
+                &kind.to_le_bytes(),
+                &count.to_le_bytes(),
+                &value.to_le_bytes(),
+            ]
+            .concat()
+        };
+        // IFD0 at 8: 2 entries, next IFD at 38
+        tiff.extend(2u16.to_le_bytes());
+        tiff.extend(entry(0x010F, 2, 6, 80));
+        tiff.extend(entry(EXIF_IFD_POINTER, 4, 1, 56));
+        tiff.extend(38u32.to_le_bytes());
+        // IFD1 at 38: thumbnail at 120, 64 bytes
+        tiff.extend(1u16.to_le_bytes());
+        tiff.extend(entry(0x0202, 4, 1, 64));
+        tiff.extend(0u32.to_le_bytes());
+        // EXIF IFD at 56: ExposureTime at 86, MakerNote (16 bytes) at 94
+        tiff.resize(56, 0);
+        tiff.extend(2u16.to_le_bytes());
+        tiff.extend(entry(0x829A, 5, 1, 86));
+        tiff.extend(entry(EXIF_MAKER_NOTE, 7, 16, 94));
+        tiff.extend(0u32.to_le_bytes());
+        tiff.resize(80, 0);
+        tiff.extend(b"Canon\0");
+        tiff.extend(1u32.to_le_bytes());
+        tiff.extend(250u32.to_le_bytes());
+        tiff.extend([0xAA; 16]);
+        tiff.resize(120, 0);
+        tiff.extend([0xFF; 64]);
+
+        let stripped = strip_exif_thumbnail(&tiff).unwrap();
+        assert!(stripped.len() < 80);
+        let reader = TiffReader {
+            bytes: &stripped,
+            big_endian: false,
+        };
+        let ifd0 = reader.ifd(reader.u32(4).unwrap() as usize, 0).unwrap();
+        let next_ifd = reader.u32(8 + 2 + ifd0.len() * 12).unwrap();
+        assert_eq!(next_ifd, 0);
+        assert_eq!(ifd0[0].value, b"Canon\0");
+        let exif = ifd0[1].sub_ifd.as_ref().unwrap();
+        assert_eq!(exif.len(), 1);
+        assert_eq!(exif[0].value, [1, 0, 0, 0, 250, 0, 0, 0]);
+        assert!(!stripped.windows(4).any(|w| w == [0xAA; 4]));
+
+        assert!(strip_exif_thumbnail(b"not a tiff").is_none());
+    }
+
+    #[test]
+    fn structure_depth_flattens_deeper_directories() {
+        let path = Path::new("a/b/c/x.png");
+        assert_eq!(limit_structure_depth(path, None), path);
+        assert_eq!(limit_structure_depth(path, Some(3)), path);
+        assert_eq!(
+            limit_structure_depth(path, Some(1)),
+            Path::new("a/b_c_x.png")
+        );
+        assert_eq!(
+            limit_structure_depth(path, Some(0)),
+            Path::new("a_b_c_x.png")
+        );
+        assert_eq!(
+            limit_structure_depth(Path::new("x.png"), Some(0)),
+            Path::new("x.png")
+        );
+    }
+
+    #[test]
+    fn fit_single_axis_follows_aspect_ratio() {
+        let image = DynamicImage::new_rgb8(400, 300);
+        let width = ResizeOptions::new(100, 0, ResizeMode::FitWidth).unwrap();
+        assert_eq!(
+            resize_image(image.clone(), width, ResizeAlgorithm::Resize, false, None).dimensions(),
+            (100, 75)
+        );
+        let height = ResizeOptions::new(0, 600, ResizeMode::FitHeight).unwrap();
+        assert_eq!(
+            resize_image(image, height, ResizeAlgorithm::Resize, false, None).dimensions(),
+            (800, 600)
+        );
+    }
 
-    let quality = if options.lossless {
-        100.0
-    } else {
-        options.quality.unwrap_or(80) as f32
-    };
-    let speed = options.avif_speed.unwrap_or(4);
+    #[test]
+    fn fast_downscale_keeps_target_dimensions() {
+        let image = DynamicImage::new_rgb8(1000, 600);
+        for mode in [ResizeMode::Fit, ResizeMode::Exact] {
+            let resize = ResizeOptions::new(100, 100, mode).unwrap();
+            let slow = resize_image(image.clone(), resize, ResizeAlgorithm::Resize, false, None);
+            let fast = resize_image(image.clone(), resize, ResizeAlgorithm::Resize, true, None);
+            assert_eq!(slow.dimensions(), fast.dimensions());
+        }
+        let fit = resize_image(
+            image,
+            ResizeOptions::new(100, 100, ResizeMode::Fit).unwrap(),
+            ResizeAlgorithm::Resize,
+            true,
+            None,
+        );
+        assert_eq!(fit.dimensions(), (100, 60));
+    }
 
-    let encoder = ravif::Encoder::new()
-        .with_quality(quality)
-        .with_speed(speed)
-        .with_alpha_quality(quality);
+    #[test]
+    fn thumbnail_algorithm_matches_resize_dimensions() {
+        let image = DynamicImage::new_rgb8(200, 120);
+        for (width, height, mode) in [
+            (50, 50, ResizeMode::Fit),
+            (50, 50, ResizeMode::Exact),
+            (0, 180, ResizeMode::FitHeight),
+        ] {
+            let resize = ResizeOptions::new(width, height, mode).unwrap();
+            let lanczos = resize_image(image.clone(), resize, ResizeAlgorithm::Resize, false, None);
+            let thumbnail = resize_image(
+                image.clone(),
+                resize,
+                ResizeAlgorithm::Thumbnail,
+                false,
+                None,
+            );
+            assert_eq!(lanczos.dimensions(), thumbnail.dimensions());
+        }
+    }
 
-    let result = encoder
-        .encode_rgba(img.as_ref())
-        .context("AVIF encoding failed")?;
+    #[test]
+    fn parse_resume_state_lines() {
+        assert_eq!(
+            parse_resume_line(r#"{"source":"a/b.png","hash":"00ff"}"#),
+            Some(("a/b.png".to_string(), "00ff".to_string()))
+        );
+        assert_eq!(parse_resume_line(r#"{"source":"a/b.png","ha"#), None);
+        assert_eq!(parse_resume_line(r#"{"source":"a/b.png"}"#), None);
+    }
 
-    Ok(result.avif_file)
-}
+    #[test]
+    fn special_character_names_round_trip_through_a_batch() {
+        let dir = std::env::temp_dir().join(format!("icr-names-{}", std::process::id()));
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(input.join("café #2")).unwrap();
+        let names = [
+            "my photo.bmp",
+            "café #2/100% real+fake.bmp",
+            "a%20b#frag.bmp",
+            "Screenshot at 10.15.32 AM",
+        ];
+        for name in names {
+            DynamicImage::new_rgb8(4, 4)
+                .save_with_format(input.join(name), ImageFormat::Bmp)
+                .unwrap();
+        }
 
-// ---------------------------------------------------------------------------
-// Helpers
-// ---------------------------------------------------------------------------
+        let report =
+            compress_directory(&input, &output, "png", &CompressOptions::default(), true).unwrap();
+        assert_eq!(report.compressed, names.len());
+        for expected in [
+            "my photo.png",
+            "café #2/100% real+fake.png",
+            "a%20b#frag.png",
+            "Screenshot at 10.15.32 AM.png",
+        ] {
+            assert!(output.join(expected).is_file(), "missing {expected}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-fn decode_and_resize(bytes: &[u8], options: &CompressOptions) -> Result<DynamicImage> {
-    let mut image = if let Ok(format) = image::guess_format(bytes) {
-        image::load_from_memory_with_format(bytes, format).context("failed to decode image")?
-    } else {
-        image::load_from_memory(bytes).context("failed to decode image")?
-    };
+    #[test]
+    fn xmp_sidecar_is_embedded_in_the_output() {
+        let dir = std::env::temp_dir().join(format!("icr-xmp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("photo.bmp");
+        DynamicImage::new_rgb8(8, 8).save(&input).unwrap();
+        let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+        fs::write(dir.join("photo.bmp.xmp"), xmp).unwrap();
+        let options = CompressOptions {
+            xmp_sidecar: true,
+            ..CompressOptions::default()
+        };
 
-    if let Some(resize) = options.resize {
-        image = resize_image(image, resize);
+        for extension in ["jpg", "webp"] {
+            let output = dir.join("out").with_extension(extension);
+            compress_image_file(&input, &output, &options).unwrap();
+            let embedded = read_source_metadata(&fs::read(&output).unwrap()).xmp;
+            assert_eq!(embedded.as_deref(), Some(&xmp[..]), "{extension}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    Ok(image)
-}
+    #[test]
+    fn pad_mode_letterboxes_to_the_exact_size() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            200,
+            100,
+            image::Rgb([255, 0, 0]),
+        ));
+        let pad = ResizeOptions::new(50, 50, ResizeMode::Pad).unwrap();
 
-fn resize_image(image: DynamicImage, resize: ResizeOptions) -> DynamicImage {
-    match resize.mode {
-        ResizeMode::Fit => image.resize(resize.width, resize.height, FilterType::Lanczos3),
-        ResizeMode::Exact => image.resize_exact(resize.width, resize.height, FilterType::Lanczos3),
+        let transparent = resize_image(image.clone(), pad, ResizeAlgorithm::Resize, false, None);
+        assert_eq!(transparent.dimensions(), (50, 50));
+        // 200x100 fits as 50x25, centered with 12 rows above
+        assert_eq!(transparent.get_pixel(25, 0).0, [0, 0, 0, 0]);
+        assert_eq!(transparent.get_pixel(25, 25).0, [255, 0, 0, 255]);
+
+        let white = Some(Background::Solid([255, 255, 255]));
+        let filled = resize_image(image, pad, ResizeAlgorithm::Resize, false, white);
+        assert_eq!(filled.get_pixel(25, 49).0, [255, 255, 255, 255]);
+        assert_eq!(filled.get_pixel(25, 25).0, [255, 0, 0, 255]);
     }
-}
 
-fn validate_input_and_output(input: &Path, output: &Path, options: &CompressOptions) -> Result<()> {
-    if !input.is_file() {
-        bail!("input file not found: {}", input.display());
+    #[test]
+    fn stats_record_whether_source_metadata_was_stripped() {
+        let mut bmp = Vec::new();
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        let webp = compress_bytes(&bmp, OutputFormat::WebP, &CompressOptions::default()).unwrap();
+        let metadata = SourceMetadata {
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            ..SourceMetadata::default()
+        };
+        let tagged = embed_webp_metadata(webp, &metadata, 8, 8).unwrap();
+
+        let stripped_from = |source: &[u8], format, strip_metadata| {
+            let options = CompressOptions {
+                strip_metadata,
+                ..CompressOptions::default()
+            };
+            compress_bytes_with_stats(source, format, &options)
+                .unwrap()
+                .1
+                .metadata_stripped
+        };
+        let stripped = |format, strip_metadata| stripped_from(&tagged, format, strip_metadata);
+        assert!(stripped(OutputFormat::Jpeg, true));
+        assert!(!stripped(OutputFormat::Jpeg, false));
+        // PNG re-encodes can't carry XMP, even when asked to keep it
+        assert!(stripped(OutputFormat::Png, false));
+        // Nothing was dropped from a source without metadata
+        assert!(!stripped_from(&bmp, OutputFormat::Jpeg, true));
     }
 
-    if output.exists() && !options.overwrite {
-        bail!(
-            "output file exists (use --overwrite to replace): {}",
-            output.display()
+    #[test]
+    fn animated_gif_becomes_an_apng_with_its_delays() {
+        use image::AnimationDecoder;
+
+        let mut gif = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            encoder
+                .set_repeat(image::codecs::gif::Repeat::Finite(2))
+                .unwrap();
+            for (shade, millis) in [(0, 100), (255, 250)] {
+                let frame = RgbaImage::from_pixel(6, 4, image::Rgba([shade, 0, 0, 255]));
+                let delay = image::Delay::from_numer_denom_ms(millis, 1);
+                encoder
+                    .encode_frame(image::Frame::from_parts(frame, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+        let options = CompressOptions {
+            apng: true,
+            ..CompressOptions::default()
+        };
+
+        let apng = compress_bytes(&gif, OutputFormat::Png, &options).unwrap();
+        let decoder = image::codecs::png::PngDecoder::new(Cursor::new(&apng)).unwrap();
+        assert!(decoder.is_apng().unwrap());
+        let frames = decoder
+            .apng()
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        let delays: Vec<_> = frames
+            .iter()
+            .map(|frame| frame.delay().numer_denom_ms())
+            .collect();
+        assert_eq!(delays, [(100, 1), (250, 1)]);
+        assert_eq!(frames[1].buffer().get_pixel(0, 0).0, [255, 0, 0, 255]);
+        // Two repeats after the first play
+        let control = png::Decoder::new(Cursor::new(&apng))
+            .read_info()
+            .unwrap()
+            .info()
+            .animation_control
+            .unwrap();
+        assert_eq!(control.num_plays, 3);
+        assert_eq!(
+            apng_ignored_options(&CompressOptions {
+                png_level: Some(4),
+                quantize_colors: Some(64),
+                ..options
+            }),
+            ["png_level", "quantize_colors"]
         );
+
+        // Without the option only the first frame is kept
+        let still = compress_bytes(&gif, OutputFormat::Png, &CompressOptions::default()).unwrap();
+        let decoder = image::codecs::png::PngDecoder::new(Cursor::new(&still)).unwrap();
+        assert!(!decoder.is_apng().unwrap());
     }
 
-    if let Some(parent) = output.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    #[test]
+    fn max_dimension_caps_the_longest_side_only() {
+        let options = CompressOptions {
+            max_dimension: Some(100),
+            ..CompressOptions::default()
+        };
+        let wide = prepare_image(DynamicImage::new_rgb8(400, 300), &options);
+        assert_eq!(wide.dimensions(), (100, 75));
+        let tall = prepare_image(DynamicImage::new_rgb8(30, 200), &options);
+        assert_eq!(tall.dimensions(), (15, 100));
+        let small = prepare_image(DynamicImage::new_rgb8(80, 60), &options);
+        assert_eq!(small.dimensions(), (80, 60));
     }
 
-    Ok(())
-}
+    #[test]
+    fn avif_preset_fills_in_speed_and_quality() {
+        let mut options = CompressOptions {
+            avif_preset: Some(AvifPreset::Max),
+            ..CompressOptions::default()
+        };
+        assert_eq!(avif_speed_and_quality(&options), (2, 82.0));
+        options.quality = Some(60);
+        assert_eq!(avif_speed_and_quality(&options), (2, 60.0));
+        options.avif_preset = None;
+        options.avif_speed = Some(9);
+        assert_eq!(avif_speed_and_quality(&options), (9, 60.0));
+        // Balanced is what no preset encodes with
+        let balanced = CompressOptions {
+            avif_preset: Some(AvifPreset::Balanced),
+            ..CompressOptions::default()
+        };
+        assert_eq!(
+            avif_speed_and_quality(&balanced),
+            avif_speed_and_quality(&CompressOptions::default())
+        );
+    }
 
-fn collect_input_files(input_dir: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
-    let mut files = Vec::new();
+    #[test]
+    fn marked_outputs_are_skipped_on_a_rerun_at_the_same_settings() {
+        let dir = std::env::temp_dir().join(format!("icr-marked-{}", std::process::id()));
+        let (input, first, second) = (dir.join("in"), dir.join("first"), dir.join("second"));
+        fs::create_dir_all(&input).unwrap();
+        DynamicImage::new_rgb8(8, 8)
+            .save(input.join("a.bmp"))
+            .unwrap();
+        let mut options = CompressOptions {
+            mark_output: true,
+            ..CompressOptions::default()
+        };
+        let batch = BatchOptions {
+            skip_marked: true,
+            ..BatchOptions::default()
+        };
+        let run = |from: &Path, to: &Path, options: &CompressOptions| {
+            compress_directory_with_progress(from, to, "jpg", options, &batch, |_| {}).unwrap()
+        };
 
-    if recursive {
-        for entry in WalkDir::new(input_dir)
-            .into_iter()
-            .filter_map(|entry| entry.ok())
-        {
-            if entry.file_type().is_file() {
-                files.push(entry.into_path());
-            }
-        }
-    } else {
-        for entry in fs::read_dir(input_dir)
-            .with_context(|| format!("failed to read directory: {}", input_dir.display()))?
-        {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                files.push(path);
-            }
-        }
+        assert_eq!(run(&input, &first, &options).compressed, 1);
+        assert!(image::open(first.join("a.jpg")).is_ok());
+        let report = run(&first, &second, &options);
+        assert_eq!(
+            (report.compressed, report.skipped_already_optimized),
+            (0, 1)
+        );
+
+        options.quality = Some(60);
+        let report = run(&first, &second, &options);
+        assert_eq!(
+            (report.compressed, report.skipped_already_optimized),
+            (1, 0)
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    Ok(files)
-}
+    #[test]
+    fn extreme_aspect_ratios_stay_within_bounds() {
+        let strip = DynamicImage::new_rgb8(10000, 1);
+        for (width, height, mode) in [
+            (100, 100, ResizeMode::Fit),
+            (100, 0, ResizeMode::FitWidth),
+            (0, 100, ResizeMode::FitHeight),
+        ] {
+            let resize = ResizeOptions::new(width, height, mode).unwrap();
+            let resized = resize_image(
+                strip.clone(),
+                resize,
+                ResizeAlgorithm::Thumbnail,
+                false,
+                None,
+            );
+            let (width, height) = resized.dimensions();
+            assert!(width >= 1 && height >= 1, "{mode:?}");
+            assert!(width <= MAX_FIT_SIDE, "{mode:?}: {width}x{height}");
+        }
 
-fn normalize_extension(extension: &str) -> Result<String> {
-    let extension = extension.trim().trim_start_matches('.');
-    if extension.is_empty() {
-        bail!("format/extension cannot be empty");
+        let cropped = limit_aspect_ratio(strip.clone(), 50.0, AspectRatioAction::Crop);
+        assert_eq!(cropped.dimensions(), (50, 1));
+        let tall = limit_aspect_ratio(
+            DynamicImage::new_rgb8(4, 1000),
+            50.0,
+            AspectRatioAction::Crop,
+        );
+        assert_eq!(tall.dimensions(), (4, 200));
+        let warned = limit_aspect_ratio(strip, 50.0, AspectRatioAction::Warn);
+        assert_eq!(warned.dimensions(), (10000, 1));
+
+        let wide = DynamicImage::new_rgb8(WEBP_MAX_SIDE + 1, 1);
+        let err = compress_webp(&wide, &CompressOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("at most 16383 pixels"), "{err}");
     }
-    Ok(extension.to_ascii_lowercase())
-}
 
-pub fn format_size(bytes: u64) -> String {
-    if bytes >= 1_000_000 {
-        format!("{:.1} MB", bytes as f64 / 1_000_000.0)
-    } else if bytes >= 1_000 {
-        format!("{:.0} KB", bytes as f64 / 1_000.0)
-    } else {
-        format!("{} B", bytes)
+    #[test]
+    fn two_pass_webp_decodes_and_is_webp_only() {
+        let noise = RgbaImage::from_fn(64, 64, |x, y| {
+            let v = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)) as u8;
+            image::Rgba([v, v.rotate_left(3), v.rotate_left(5), 255])
+        });
+        let options = CompressOptions {
+            two_pass: true,
+            ..CompressOptions::default()
+        };
+        let webp = compress_webp(&DynamicImage::ImageRgba8(noise), &options).unwrap();
+        let decoded = image::load_from_memory_with_format(&webp, ImageFormat::WebP).unwrap();
+        assert_eq!(decoded.dimensions(), (64, 64));
+        assert_eq!(ignored_options(&options, OutputFormat::Avif), ["two_pass"]);
+        assert!(ignored_options(&options, OutputFormat::WebP).is_empty());
     }
-}
 
-// ---------------------------------------------------------------------------
-// Tests
-// ---------------------------------------------------------------------------
+    #[test]
+    fn large_jpeg_reductions_decode_at_a_dct_scale() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1600, 1200, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }));
+        let jpeg = compress_jpeg(
+            &image,
+            &CompressOptions::default(),
+            &SourceMetadata::default(),
+        )
+        .unwrap();
+        let options = CompressOptions::builder()
+            .resize(ResizeOptions::new(200, 200, ResizeMode::Fit).unwrap())
+            .build()
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // 1/4 leaves 400x300, twice the 200x150 target; 1/8 would not
+        let scaled = decode_jpeg_scaled(&jpeg, &options).unwrap();
+        assert_eq!(scaled.dimensions(), (400, 300));
+        assert_eq!(
+            decode_and_resize(&jpeg, &options, &mut StageTimings::default())
+                .unwrap()
+                .dimensions(),
+            (200, 150)
+        );
+
+        // Small reductions, trim and truncated files take the full decode
+        let small = CompressOptions::builder()
+            .resize(ResizeOptions::new(1000, 1000, ResizeMode::Fit).unwrap())
+            .build()
+            .unwrap();
+        assert!(decode_jpeg_scaled(&jpeg, &small).is_none());
+        let mut trimmed = options.clone();
+        trimmed.trim = Some(TrimOptions {
+            color: None,
+            tolerance: 0,
+        });
+        assert!(decode_jpeg_scaled(&jpeg, &trimmed).is_none());
+        assert!(decode_jpeg_scaled(&jpeg[..jpeg.len() / 2], &options).is_none());
+    }
 
     #[test]
-    fn parse_supported_output_format_extensions() {
+    fn crop_runs_before_resize_and_must_fit_the_source() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(400, 300, |x, _| {
+            image::Rgb(if x < 200 { [255, 0, 0] } else { [0, 0, 255] })
+        }));
+        let options = CompressOptions::builder()
+            .crop(Rect {
+                x: 200,
+                y: 100,
+                width: 200,
+                height: 100,
+            })
+            .resize(ResizeOptions::new(50, 50, ResizeMode::Fit).unwrap())
+            .build()
+            .unwrap();
+        let cropped = SourceImage::Decoded(image.clone())
+            .into_prepared(&options, &mut StageTimings::default())
+            .unwrap();
+        assert_eq!(cropped.dimensions(), (50, 25));
+        assert_eq!(cropped.to_rgb8().get_pixel(0, 0).0, [0, 0, 255]);
+
+        let mut outside = options.clone();
+        outside.crop = Some(Rect {
+            x: 300,
+            y: 0,
+            width: 200,
+            height: 100,
+        });
+        let err = SourceImage::Decoded(image)
+            .into_prepared(&outside, &mut StageTimings::default())
+            .unwrap_err();
         assert_eq!(
-            OutputFormat::from_extension("jpg").unwrap(),
-            OutputFormat::Jpeg
+            err.to_string(),
+            "crop 300,0,200x100 lies outside the 400x300 image"
         );
+
+        let empty = CompressOptions::builder()
+            .crop(Rect {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 10,
+            })
+            .build();
+        assert!(empty.is_err());
+    }
+
+    #[test]
+    fn stage_timings_cover_decode_resize_and_encode() {
+        let mut bmp = Vec::new();
+        DynamicImage::new_rgb8(256, 256)
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        let options = CompressOptions::builder()
+            .resize(ResizeOptions::new(64, 64, ResizeMode::Fit).unwrap())
+            .build()
+            .unwrap();
+        let (_, stats) = compress_bytes_with_stats(&bmp, OutputFormat::Jpeg, &options).unwrap();
+        assert!(stats.timings.decode > Duration::ZERO);
+        assert!(stats.timings.resize > Duration::ZERO);
+        assert!(stats.timings.encode > Duration::ZERO);
+
+        let timings = StageTimings {
+            decode: Duration::from_millis(120),
+            resize: Duration::from_micros(40_700),
+            encode: Duration::from_millis(900),
+        };
         assert_eq!(
-            OutputFormat::from_extension("jpeg").unwrap(),
-            OutputFormat::Jpeg
+            timings.to_string(),
+            "decode 120ms, resize 40ms, encode 900ms"
         );
+    }
+
+    #[test]
+    fn per_format_subdir_prefixes_the_output_format() {
+        let mut batch = BatchOptions {
+            per_format_subdir: true,
+            rules: parse_batch_rules("[[rule]]\nprefix = \"logos\"\nto = \"png\"\n").unwrap(),
+            ..BatchOptions::default()
+        };
+        let target = |batch: &BatchOptions, relative: &str, to: &str| {
+            let relative = Path::new(relative);
+            batch_relative_target(relative, relative, to, batch)
+        };
         assert_eq!(
-            OutputFormat::from_extension("png").unwrap(),
-            OutputFormat::Png
+            target(&batch, "img/a.bmp", "webp"),
+            Path::new("webp/img/a.webp")
         );
         assert_eq!(
-            OutputFormat::from_extension("webp").unwrap(),
-            OutputFormat::WebP
+            target(&batch, "logos/b.bmp", "webp"),
+            Path::new("png/logos/b.png")
         );
+        assert_eq!(target(&batch, "c.bmp", "jpg"), Path::new("jpeg/c.jpg"));
+
+        batch.preserve_input_extension = true;
         assert_eq!(
-            OutputFormat::from_extension("avif").unwrap(),
-            OutputFormat::Avif
+            target(&batch, "img/a.bmp", "avif"),
+            Path::new("avif/img/a.bmp.avif")
         );
     }
 
     #[test]
-    fn reject_unknown_output_extension() {
-        assert!(OutputFormat::from_extension("bmp").is_err());
-        assert!(OutputFormat::from_extension("gif").is_err());
-        assert!(OutputFormat::from_extension("tiff").is_err());
+    fn options_schema_lists_every_field() {
+        // The destructuring pattern has no `..`, so a new field fails to
+        // compile until it is listed here
+        macro_rules! field_names {
+            ($($field:ident),* $(,)?) => {{
+                let CompressOptions { $($field: _),* } = CompressOptions::default();
+                vec![$(stringify!($field)),*]
+            }};
+        }
+        let mut fields = field_names!(
+            overwrite,
+            quality,
+            lossless,
+            progressive,
+            strip_metadata,
+            resize,
+            png_level,
+            avif_speed,
+            avif_premultiplied_alpha,
+            webp_exact,
+            webp_sharp_yuv,
+            webp_alpha_filter,
+            fast_downscale,
+            atomic_write,
+            png_optimize_alpha,
+            jpeg_trellis,
+            jpeg_optimize_coding,
+            background,
+            deterministic,
+            min_psnr,
+            quality_guard_strict,
+            encoder_opts,
+            avif_tiles,
+            auto_lossless,
+            trim,
+            watermark,
+            png_filter,
+            compare_original,
+            strip_exif_thumbnail,
+            quantize_colors,
+            png_lossy_quality,
+            dither,
+            assume_srgb,
+            io_retries,
+            resize_algorithm,
+            output_bit_depth,
+            temp_dir,
+            xmp_sidecar,
+            apng,
+            max_dimension,
+            avif_preset,
+            mark_output,
+            max_aspect_ratio,
+            aspect_ratio_action,
+            two_pass,
+            crop,
+            adaptive_quality,
+        );
+
+        let schema = compress_options_schema();
+        let properties = schema["properties"].as_object().unwrap();
+        let mut documented: Vec<_> = properties.keys().cloned().collect();
+        fields.sort();
+        documented.sort();
+        assert_eq!(fields, documented);
+        assert_eq!(properties["quality"]["maximum"], 100);
+        assert_eq!(properties["avif_speed"]["default"], DEFAULT_AVIF_SPEED);
     }
 
     #[test]
-    fn normalize_extension_values() {
-        assert_eq!(normalize_extension(" JPG ").unwrap(), "jpg");
-        assert_eq!(normalize_extension(".WebP").unwrap(), "webp");
-        assert_eq!(normalize_extension("AVIF").unwrap(), "avif");
+    fn auto_algorithm_area_averages_only_large_reductions() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(300, 300, |x, y| {
+            image::Rgb(if (x / 3 + y / 3) % 2 == 0 {
+                [0; 3]
+            } else {
+                [255; 3]
+            })
+        }));
+        let scaled = |size: u32, algorithm| {
+            let resize = ResizeOptions::new(size, size, ResizeMode::Exact).unwrap();
+            resize_image(image.clone(), resize, algorithm, false, None).to_rgb8()
+        };
+
+        // 3x and more: area averaging, as `thumbnail`
+        assert_eq!(
+            scaled(100, ResizeAlgorithm::Auto),
+            scaled(100, ResizeAlgorithm::Thumbnail)
+        );
+        assert_ne!(
+            scaled(100, ResizeAlgorithm::Auto),
+            scaled(100, ResizeAlgorithm::Resize)
+        );
+        // Milder reductions and enlargements keep Lanczos
+        for size in [150, 600] {
+            assert_eq!(
+                scaled(size, ResizeAlgorithm::Auto),
+                scaled(size, ResizeAlgorithm::Resize)
+            );
+        }
     }
 
     #[test]
-    fn reject_empty_extension() {
-        assert!(normalize_extension("").is_err());
-        assert!(normalize_extension("  ").is_err());
-        assert!(normalize_extension(".").is_err());
+    fn adaptive_quality_follows_image_detail() {
+        let flat = DynamicImage::new_rgb8(64, 64);
+        // Deterministic pseudo-random noise stands in for a busy photo
+        let busy = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            let value = (x.wrapping_mul(2_654_435_761) ^ y.wrapping_mul(40_503)) >> 7;
+            image::Rgb([value as u8; 3])
+        }));
+        assert_eq!(image_complexity(&flat), 0.0);
+        assert!(image_complexity(&busy) > 0.9);
+        assert_eq!(quality_for_complexity(0.0, 60, 90), 90);
+        assert_eq!(quality_for_complexity(0.5, 60, 90), 75);
+        assert_eq!(quality_for_complexity(1.0, 60, 90), 60);
+        assert_eq!(quality_for_complexity(1.0, 90, 60), 60);
+
+        let encode = |image: &DynamicImage, options: &CompressOptions| {
+            let mut bmp = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+                .unwrap();
+            compress_bytes_with_stats(&bmp, OutputFormat::Jpeg, options)
+                .unwrap()
+                .1
+        };
+        let adaptive = CompressOptions::builder()
+            .adaptive_quality(60, 90)
+            .build()
+            .unwrap();
+        assert_eq!(encode(&flat, &adaptive).adaptive_quality, Some(90));
+        assert!(encode(&busy, &adaptive).adaptive_quality < Some(65));
+
+        // An explicit quality, e.g. from a rule, wins
+        let explicit = CompressOptions {
+            quality: Some(80),
+            ..adaptive.clone()
+        };
+        assert_eq!(encode(&busy, &explicit).adaptive_quality, None);
+        assert!(ignored_options(&adaptive, OutputFormat::Png).contains(&"adaptive_quality"));
+        assert!(
+            CompressOptions::builder()
+                .adaptive_quality(90, 60)
+                .build()
+                .is_err()
+        );
     }
 
     #[test]
-    fn validate_resize_bounds() {
-        assert!(ResizeOptions::new(0, 200, ResizeMode::Fit).is_err());
-        assert!(ResizeOptions::new(200, 0, ResizeMode::Fit).is_err());
-        assert!(ResizeOptions::new(200, 100, ResizeMode::Fit).is_ok());
+    fn name_template_keeps_dots_in_the_stem() {
+        let batch = BatchOptions {
+            name_template: Some(NameTemplate::new("{stem}.jpg", SystemTime::UNIX_EPOCH).unwrap()),
+            ..BatchOptions::default()
+        };
+        let target = |relative: &str| {
+            let relative = Path::new(relative);
+            batch_relative_target(relative, relative, "webp", &batch)
+        };
+        assert_eq!(target("shot.1.jpg"), Path::new("shot.1.webp"));
+        assert_eq!(target("shot.2.jpg"), Path::new("shot.2.webp"));
+        assert_eq!(target("a.tar.gz"), Path::new("a.tar.webp"));
+        assert_eq!(target("README"), Path::new("README.webp"));
     }
 
     #[test]
-    fn default_compress_options() {
-        let opts = CompressOptions::default();
-        assert!(!opts.overwrite);
-        assert!(opts.quality.is_none());
-        assert!(!opts.lossless);
-        assert!(!opts.progressive);
-        assert!(opts.strip_metadata);
-        assert!(opts.resize.is_none());
-        assert!(opts.png_level.is_none());
-        assert!(opts.avif_speed.is_none());
+    fn progressive_jpeg_survives_the_fastest_profile() {
+        // The start-of-frame marker: SOF0 is baseline, SOF2 progressive
+        fn frame_marker(jpeg: &[u8]) -> u8 {
+            let mut at = 2;
+            while jpeg[at] == 0xFF && !(0xC0..=0xC2).contains(&jpeg[at + 1]) {
+                at += 2 + usize::from(u16::from_be_bytes([jpeg[at + 2], jpeg[at + 3]]));
+            }
+            jpeg[at + 1]
+        }
+
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, 90])
+        }));
+        let encode = |progressive| {
+            let options = CompressOptions {
+                progressive,
+                jpeg_trellis: Some(false),
+                ..CompressOptions::default()
+            };
+            compress_jpeg(&image, &options, &SourceMetadata::default()).unwrap()
+        };
+        assert_eq!(frame_marker(&encode(true)), 0xC2);
+        assert_eq!(frame_marker(&encode(false)), 0xC0);
     }
 
     #[test]
-    fn format_size_display() {
-        assert_eq!(format_size(500), "500 B");
-        assert_eq!(format_size(1_500), "2 KB");
-        assert_eq!(format_size(2_400_000), "2.4 MB");
+    fn max_dimension_below_an_exact_resize_is_rejected() {
+        let exact = ResizeOptions::new(200, 100, ResizeMode::Exact).unwrap();
+        assert!(
+            CompressOptions::builder()
+                .resize(exact)
+                .max_dimension(150)
+                .build()
+                .is_err()
+        );
+
+        let mut bmp = Vec::new();
+        DynamicImage::new_rgb8(40, 40)
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        let mut options = CompressOptions {
+            resize: Some(ResizeOptions::new(200, 100, ResizeMode::Pad).unwrap()),
+            max_dimension: Some(150),
+            ..CompressOptions::default()
+        };
+        let err = compress_bytes(&bmp, OutputFormat::Png, &options).unwrap_err();
+        assert!(err.to_string().contains("max_dimension"), "{err}");
+        // A cap the resize already fits in is fine
+        options.max_dimension = Some(200);
+        let (_, stats) = compress_bytes_with_stats(&bmp, OutputFormat::Png, &options).unwrap();
+        assert_eq!((stats.width, stats.height), (200, 100));
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip_output_stops_at_the_total_budget() {
+        let dir = std::env::temp_dir().join(format!("icr-zip-budget-{}", std::process::id()));
+        let input = dir.join("in");
+        fs::create_dir_all(&input).unwrap();
+        for name in ["a.png", "b.png"] {
+            DynamicImage::new_rgb8(8, 8).save(input.join(name)).unwrap();
+        }
+        let archive = dir.join("out.zip");
+        let batch = BatchOptions {
+            total_budget: Some(1),
+            ..BatchOptions::default()
+        };
+
+        let report =
+            compress_directory_to_zip(&input, &archive, "png", &CompressOptions::default(), &batch)
+                .unwrap();
+        assert_eq!((report.compressed, report.not_processed), (1, 1));
+        let zip = zip::ZipArchive::new(fs::File::open(&archive).unwrap()).unwrap();
+        assert_eq!(zip.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn avif_tiles_set_the_encoder_threads() {
+        let mut options = CompressOptions {
+            avif_tiles: Some(AvifTiles::Grid { rows: 2, cols: 3 }),
+            deterministic: true,
+            ..CompressOptions::default()
+        };
+        // A fixed tile count doesn't depend on the machine, so it wins over the pin
+        assert_eq!(avif_threads(&options, 4000, 3000), Some(6));
+        options.avif_tiles = Some(AvifTiles::Auto);
+        assert_eq!(avif_threads(&options, 4000, 3000), Some(6));
+        assert_eq!(avif_threads(&options, 64, 64), Some(1));
+        options.avif_tiles = None;
+        assert_eq!(avif_threads(&options, 4000, 3000), Some(1));
+        options.deterministic = false;
+        assert_eq!(avif_threads(&options, 4000, 3000), None);
+
+        let too_many = AvifTiles::Grid { rows: 8, cols: 9 };
+        assert!(
+            CompressOptions::builder()
+                .avif_tiles(too_many)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip_archive_entries_stay_inside_the_output_dir() {
+        let dir = std::env::temp_dir().join(format!("icr-zip-in-{}", std::process::id()));
+        let output_dir = dir.join("out");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let entry_options = zip::write::SimpleFileOptions::default();
+        for (name, bytes) in [
+            ("photos/a.png", png.as_slice()),
+            ("notes.txt", b"not an image".as_slice()),
+            ("../evil.png", png.as_slice()),
+        ] {
+            zip.start_file(name, entry_options).unwrap();
+            zip.write_all(bytes).unwrap();
+        }
+        let archive = dir.join("in.zip");
+        fs::write(&archive, zip.finish().unwrap().into_inner()).unwrap();
+
+        let report = compress_zip_archive(
+            &archive,
+            &output_dir,
+            "png",
+            &CompressOptions::default(),
+            SizeUnits::Decimal,
+        )
+        .unwrap();
+        assert_eq!(
+            (report.compressed, report.skipped, report.failed),
+            (1, 1, 1)
+        );
+        assert!(output_dir.join("photos/a.png").is_file());
+        assert!(!output_dir.join("notes.png").exists());
+        assert!(!dir.join("evil.png").exists());
+        let mut written: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        written.sort();
+        assert_eq!(written, ["in.zip", "out"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn directory_is_written_into_a_stored_zip() {
+        let dir = std::env::temp_dir().join(format!("icr-zip-out-{}", std::process::id()));
+        let input = dir.join("in");
+        fs::create_dir_all(input.join("sub")).unwrap();
+        for name in ["b.png", "sub/a.png"] {
+            DynamicImage::new_rgb8(8, 8).save(input.join(name)).unwrap();
+        }
+        let archive = dir.join("out.zip");
+        let options = CompressOptions {
+            atomic_write: true,
+            ..CompressOptions::default()
+        };
+        let batch = BatchOptions {
+            recursive: true,
+            ..BatchOptions::default()
+        };
+
+        let report = compress_directory_to_zip(&input, &archive, "png", &options, &batch).unwrap();
+        assert_eq!(report.compressed, 2);
+        let mut zip = zip::ZipArchive::new(fs::File::open(&archive).unwrap()).unwrap();
+        let mut names: Vec<_> = zip.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, ["b.png", "sub/a.png"]);
+        for index in 0..zip.len() {
+            let entry = zip.by_index(index).unwrap();
+            assert_eq!(entry.compression(), zip::CompressionMethod::Stored);
+        }
+        // The atomic temp file was renamed into place, not left beside it
+        let mut written: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        written.sort();
+        assert_eq!(written, ["in", "out.zip"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn premultiplied_avif_rejects_8_bit_output() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            16,
+            16,
+            image::Rgba([200, 40, 40, 128]),
+        ));
+        let mut options = CompressOptions {
+            avif_premultiplied_alpha: true,
+            avif_speed: Some(10),
+            output_bit_depth: Some(8),
+            ..CompressOptions::default()
+        };
+        let err = compress_avif(&image, &options).unwrap_err();
+        assert!(err.to_string().contains("10 bits"), "{err}");
+        options.output_bit_depth = None;
+        assert!(compress_avif(&image, &options).is_ok());
+    }
+
+    #[test]
+    fn resume_redoes_interrupted_and_changed_files() {
+        let dir = std::env::temp_dir().join(format!("icr-resume-{}", std::process::id()));
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        fs::create_dir_all(&input).unwrap();
+        for name in ["a.png", "b.png"] {
+            DynamicImage::new_rgb8(8, 8).save(input.join(name)).unwrap();
+        }
+        let batch = BatchOptions {
+            resume: true,
+            ..BatchOptions::default()
+        };
+        let run = || {
+            let report = compress_directory_with_options(
+                &input,
+                &output,
+                "png",
+                &CompressOptions::default(),
+                &batch,
+            )
+            .unwrap();
+            (report.compressed, report.skipped)
+        };
+        assert_eq!(run(), (2, 0));
+
+        // Interrupted while writing b: only a made it into the state file
+        let state_path = output.join(RESUME_STATE_FILE);
+        let state = fs::read_to_string(&state_path).unwrap();
+        let first = state.lines().next().unwrap();
+        assert!(first.contains(&sha256_hex(&fs::read(input.join("a.png")).unwrap())));
+        fs::write(&state_path, format!("{first}\n")).unwrap();
+        fs::write(output.join("b.png"), b"torn").unwrap();
+        assert_eq!(run(), (1, 1));
+        assert!(image::open(output.join("b.png")).is_ok());
+
+        // A changed source no longer matches its recorded hash
+        DynamicImage::new_rgb8(9, 9)
+            .save(input.join("a.png"))
+            .unwrap();
+        assert_eq!(run(), (1, 1));
+        assert_eq!(image::open(output.join("a.png")).unwrap().width(), 9);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,10 +1,21 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand, ValueEnum};
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use image_compressor_rs::{
-    BatchReport, CompressOptions, ResizeMode, ResizeOptions, compress_directory,
-    compress_image_file, format_size,
+    AspectRatioAction, AvifPreset, AvifTiles, Background, BatchEvent, BatchOptions, BatchReport,
+    BatchTotals, CompressOptions, CompressionStats, ImageInfo, KeptOriginal, NameTemplate,
+    OutputFormat, PlannedFile, PngFilter, Rect, ResizeAlgorithm, ResizeMode, ResizeOptions,
+    SAVINGS_BUCKETS, SizeUnits, TrimOptions, UNIQUE_COLORS_LIMIT, WatermarkOptions,
+    WatermarkPosition, WebPAlphaFilter, compress_bytes, compress_directory_with_progress,
+    compress_image_file, compress_options_schema, decode_to_rgba8, estimate_batch, ignored_options,
+    parse_batch_rules, parse_quality_map, parse_since, plan_batch, probe_image, probe_image_colors,
+    supported_formats,
 };
-use std::path::PathBuf;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 #[derive(Parser)]
 #[command(
@@ -15,6 +26,9 @@ use std::path::PathBuf;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Units for displayed sizes
+    #[arg(long, global = true, env = "IMGC_UNITS", value_enum, default_value_t = UnitsArg::Decimal)]
+    units: UnitsArg,
 }
 
 #[derive(Subcommand)]
@@ -25,72 +39,422 @@ enum Commands {
         input: PathBuf,
         /// Output image path (format determined by extension)
         output: PathBuf,
-        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
-        quality: Option<u8>,
-        /// Lossless mode (WebP, AVIF)
+        /// Debug: write the decoded (and resized) pixels as raw RGBA8 with an
+        /// 8-byte header (width, height as little-endian u32)
+        #[arg(long, hide = true, value_name = "PATH")]
+        dump_rgba: Option<PathBuf>,
+        /// Print only this template instead of the summary line, e.g. "{saved_pct}"
+        /// (tokens: input, output, width, height, original_bytes, compressed_bytes,
+        /// saved_bytes, saved_pct, metadata_bytes_removed, metadata_stripped, psnr,
+        /// ssim, kept_original)
+        #[arg(long, value_name = "TEMPLATE", value_parser = parse_format_string)]
+        format_string: Option<String>,
+        /// Encode at qualities 60/75/85/95, show the sizes and ask which to write
+        /// (only when stdin is a terminal; otherwise --quality is used)
         #[arg(long, default_value_t = false)]
-        lossless: bool,
-        /// Progressive JPEG
-        #[arg(long, default_value_t = false)]
-        progressive: bool,
-        /// Preserve EXIF/metadata (default: strip)
-        #[arg(long, default_value_t = false)]
-        keep_metadata: bool,
-        /// Resize dimensions (WIDTHxHEIGHT)
-        #[arg(long, value_parser = parse_resize)]
-        resize: Option<ResizeInput>,
-        /// Resize strategy
-        #[arg(long, value_enum, default_value_t = ResizeModeArg::Fit)]
-        resize_mode: ResizeModeArg,
-        /// Overwrite existing output files
-        #[arg(long, default_value_t = false)]
-        overwrite: bool,
-        /// PNG optimization level (1-6)
-        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=6))]
-        png_level: Option<u8>,
-        /// AVIF encoding speed (1=slow/best, 10=fast)
-        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
-        avif_speed: Option<u8>,
+        interactive: bool,
+        /// Print how long decode, resize and encode took (stderr)
+        #[arg(long, short, default_value_t = false)]
+        verbose: bool,
+        #[command(flatten)]
+        encode: EncodeArgs,
     },
     /// Compress all images in a directory
     Batch {
         /// Input directory
         input_dir: PathBuf,
         /// Output directory
-        output_dir: PathBuf,
+        #[cfg_attr(feature = "zip", arg(required_unless_present = "output_zip"))]
+        #[cfg_attr(not(feature = "zip"), arg(required = true))]
+        output_dir: Option<PathBuf>,
+        /// Write all results into this ZIP archive instead of an output directory
+        #[cfg(feature = "zip")]
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["output_dir", "resume", "dry_run", "manifest", "estimate", "events"])]
+        output_zip: Option<PathBuf>,
         /// Target format (jpg, png, webp, avif)
-        #[arg(long, value_name = "FORMAT")]
-        to: String,
+        #[arg(
+            long,
+            env = "IMGC_FORMAT",
+            value_name = "FORMAT",
+            required_unless_present_any = ["follow_input_format", "rules"]
+        )]
+        to: Option<String>,
+        /// Keep each file's own format (JPEG, PNG, WebP, AVIF) and only optimize;
+        /// other formats are converted to --to, or PNG if --to is not given
+        #[arg(long, default_value_t = false)]
+        follow_input_format: bool,
         /// Process subdirectories
         #[arg(long, default_value_t = false)]
         recursive: bool,
-        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
-        quality: Option<u8>,
-        /// Lossless mode (WebP, AVIF)
+        /// Resume an interrupted run, skipping files already completed and unchanged
+        #[arg(long, default_value_t = false)]
+        resume: bool,
+        /// TOML file of per-file quality overrides ("name.jpg" = 95); other files use --quality
+        #[arg(long, value_name = "PATH")]
+        quality_map: Option<PathBuf>,
+        /// TOML file of per-subfolder settings ([[rule]] with prefix, to, quality, ...)
+        #[arg(long, value_name = "PATH")]
+        rules: Option<PathBuf>,
+        /// Only process files modified since this time: RFC 3339
+        /// (2024-05-01T08:00:00Z) or a duration ago (90m, 24h, 7d, 2w)
+        #[arg(long, value_name = "TIME", value_parser = parse_since)]
+        since: Option<SystemTime>,
+        /// Stop once this much output has been written (e.g. 500MB, 2GiB)
+        #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+        total_budget: Option<u64>,
+        /// Emit NDJSON progress events (file_start, file_done, ...) on stderr
+        #[arg(long, default_value_t = false)]
+        events: bool,
+        /// List what would be compressed or skipped without writing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Print a bar chart of per-file savings (in 20% buckets) at the end
         #[arg(long, default_value_t = false)]
-        lossless: bool,
-        /// Progressive JPEG
+        histogram: bool,
+        /// After each file, print how long decode, resize and encode took (stderr)
+        #[arg(long, short, default_value_t = false)]
+        verbose: bool,
+        /// Project the total savings from fast downscaled proxy encodes,
+        /// without writing anything
+        #[arg(long, default_value_t = false, conflicts_with_all = ["dry_run", "manifest"])]
+        estimate: bool,
+        /// Mirror only the first N directory levels; deeper ones are folded into
+        /// the file name with `_`
+        #[arg(long, value_name = "N")]
+        keep_structure_depth: Option<usize>,
+        /// Process only the first N files (in path order), e.g. for a quick
+        /// trial run on a large folder
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+        /// Skip sources already in the target format and no larger than the
+        /// estimated re-encode (nothing is written for them)
         #[arg(long, default_value_t = false)]
-        progressive: bool,
-        /// Preserve EXIF/metadata (default: strip)
+        skip_optimized: bool,
+        /// Replace an existing output only if its source is newer (make-style
+        /// incremental rebuild)
         #[arg(long, default_value_t = false)]
-        keep_metadata: bool,
-        /// Resize dimensions (WIDTHxHEIGHT)
-        #[arg(long, value_parser = parse_resize)]
-        resize: Option<ResizeInput>,
-        /// Resize strategy
-        #[arg(long, value_enum, default_value_t = ResizeModeArg::Fit)]
-        resize_mode: ResizeModeArg,
-        /// Overwrite existing output files
+        overwrite_newer_only: bool,
+        /// Don't replace an existing output whose new encode is byte-identical,
+        /// so its mtime stays put for caches and CDNs
+        #[arg(long, default_value_t = false)]
+        skip_identical: bool,
+        /// Mark JPEG/PNG outputs as written by this tool (format and quality),
+        /// and skip sources already carrying the mark for this run's settings,
+        /// e.g. when re-optimizing a folder in place
+        #[arg(long, default_value_t = false)]
+        mark_optimized: bool,
+        /// With --mark-optimized, re-encode marked sources anyway
+        #[arg(long, default_value_t = false, requires = "mark_optimized")]
+        force: bool,
+        /// Write a JSON manifest of every output this run writes, with its
+        /// SHA-256 and size
+        #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+        manifest: Option<PathBuf>,
+        /// Copy files that aren't images (CSS, JSON, fonts, ...) into the
+        /// output tree as they are instead of failing them
+        #[arg(long, default_value_t = false)]
+        copy_non_images: bool,
+        /// Output path template, e.g. "export/{date}/{stem}"; tokens {dir},
+        /// {stem}, {date}, {time}, {timestamp} (UTC, fixed at run start)
+        #[arg(long, value_name = "TEMPLATE")]
+        name_template: Option<String>,
+        /// Keep the source extension in output names: photo.jpg -> photo.jpg.webp
+        #[arg(long, default_value_t = false)]
+        preserve_input_extension_in_name: bool,
+        /// Write each output under a folder named for its format: out/webp/...,
+        /// out/avif/... (useful with --follow-input-format or rules)
+        #[arg(long, default_value_t = false)]
+        per_format_subdir: bool,
+        #[command(flatten)]
+        encode: EncodeArgs,
+    },
+    /// Compress the images inside a ZIP archive without unzipping it first
+    #[cfg(feature = "zip")]
+    BatchZip {
+        /// Input ZIP archive
+        archive: PathBuf,
+        /// Output directory (the archive's internal paths are kept)
+        output_dir: PathBuf,
+        /// Target format (jpg, png, webp, avif)
+        #[arg(long, env = "IMGC_FORMAT", value_name = "FORMAT")]
+        to: String,
+        #[command(flatten)]
+        encode: EncodeArgs,
+    },
+    /// Show an image's format, dimensions, color type and metadata sizes
+    Info {
+        input: PathBuf,
+        /// Also count distinct colors (decodes the image; stops past 100,000)
         #[arg(long, default_value_t = false)]
-        overwrite: bool,
-        /// PNG optimization level (1-6)
-        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=6))]
-        png_level: Option<u8>,
-        /// AVIF encoding speed (1=slow/best, 10=fast)
-        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
-        avif_speed: Option<u8>,
+        color_count: bool,
     },
+    /// List the input and output formats this build supports
+    Capabilities,
+    /// Print a JSON Schema of the compression options, for tooling
+    #[command(hide = true, alias = "json-schema")]
+    Schema,
+}
+
+/// Encoder and output flags shared by `compress` and `batch`
+#[derive(Args)]
+struct EncodeArgs {
+    #[arg(long, env = "IMGC_QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+    /// Pick each image's quality from its detail, between --quality-min and
+    /// --quality-max: flat graphics get the max, busy photos nearer the min
+    #[arg(
+        long,
+        env = "IMGC_ADAPTIVE_QUALITY",
+        default_value_t = false,
+        conflicts_with = "quality"
+    )]
+    adaptive_quality: bool,
+    /// Lowest quality --adaptive-quality picks
+    #[arg(long, env = "IMGC_QUALITY_MIN", value_name = "1-100", default_value_t = 60, value_parser = clap::value_parser!(u8).range(1..=100), requires = "adaptive_quality")]
+    quality_min: u8,
+    /// Highest quality --adaptive-quality picks
+    #[arg(long, env = "IMGC_QUALITY_MAX", value_name = "1-100", default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100), requires = "adaptive_quality")]
+    quality_max: u8,
+    /// Lossless mode (WebP, AVIF)
+    #[arg(long, env = "IMGC_LOSSLESS", default_value_t = false)]
+    lossless: bool,
+    /// WebP: lossless for graphics (few colors, flat areas), lossy for photos
+    #[arg(
+        long,
+        env = "IMGC_AUTO_LOSSLESS",
+        default_value_t = false,
+        conflicts_with = "lossless"
+    )]
+    auto_lossless: bool,
+    /// Progressive JPEG
+    #[arg(long, env = "IMGC_PROGRESSIVE", default_value_t = false)]
+    progressive: bool,
+    /// Preserve EXIF/metadata (default: strip)
+    #[arg(long, env = "IMGC_KEEP_METADATA", default_value_t = false)]
+    keep_metadata: bool,
+    /// With --keep-metadata, drop the EXIF thumbnail and MakerNote
+    #[arg(
+        long,
+        env = "IMGC_STRIP_EXIF_THUMBNAIL",
+        default_value_t = false,
+        requires = "keep_metadata"
+    )]
+    strip_exif_thumbnail: bool,
+    /// Embed the XMP sidecar next to each input (photo.jpg.xmp, else
+    /// photo.xmp) in JPEG and WebP output
+    #[arg(long, env = "IMGC_XMP_SIDECAR", default_value_t = false)]
+    xmp_sidecar: bool,
+    /// Tag outputs of sources without an ICC profile as sRGB instead of
+    /// leaving them untagged
+    #[arg(long, env = "IMGC_ASSUME_SRGB", default_value_t = false)]
+    assume_srgb: bool,
+    /// Resize dimensions (WIDTHxHEIGHT, or WIDTHx / xHEIGHT for one axis)
+    #[arg(long, env = "IMGC_RESIZE", value_parser = parse_resize)]
+    resize: Option<ResizeInput>,
+    /// Scale down so the longest side is at most N pixels (aspect ratio kept;
+    /// smaller images are untouched)
+    #[arg(
+        long,
+        env = "IMGC_MAX_DIMENSION",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "resize"
+    )]
+    max_dimension: Option<u32>,
+    /// Longest-to-shortest side ratio past which --aspect-ratio-action applies
+    #[arg(long, env = "IMGC_MAX_ASPECT_RATIO", value_name = "RATIO", value_parser = parse_aspect_ratio)]
+    max_aspect_ratio: Option<f64>,
+    /// What happens past --max-aspect-ratio: warn, or crop the middle of the long side
+    #[arg(
+        long,
+        env = "IMGC_ASPECT_RATIO_ACTION",
+        value_enum,
+        default_value_t = AspectRatioActionArg::Warn,
+        requires = "max_aspect_ratio"
+    )]
+    aspect_ratio_action: AspectRatioActionArg,
+    /// Region to keep, as X,Y,WIDTHxHEIGHT in source pixels; cut before any resize
+    #[arg(long, env = "IMGC_CROP", value_name = "X,Y,WxH", value_parser = parse_crop)]
+    crop: Option<Rect>,
+    /// Resize strategy
+    #[arg(long, env = "IMGC_RESIZE_MODE", value_enum, default_value_t = ResizeModeArg::Fit)]
+    resize_mode: ResizeModeArg,
+    /// Crop uniform borders (color taken from the corners) before resizing
+    #[arg(long, env = "IMGC_TRIM", default_value_t = false)]
+    trim: bool,
+    /// Border color to trim (#RRGGBB); implies --trim
+    #[arg(long, env = "IMGC_TRIM_COLOR", value_name = "COLOR", value_parser = parse_color)]
+    trim_color: Option<[u8; 3]>,
+    /// Per-channel difference still treated as border when trimming
+    #[arg(
+        long,
+        env = "IMGC_TRIM_TOLERANCE",
+        value_name = "0-255",
+        default_value_t = 10
+    )]
+    trim_tolerance: u8,
+    /// Overlay image (e.g. a logo) stamped onto every output after resizing
+    #[arg(long, env = "IMGC_WATERMARK", value_name = "PATH")]
+    watermark: Option<PathBuf>,
+    /// Where the watermark is anchored
+    #[arg(long, env = "IMGC_WATERMARK_POSITION", value_enum, default_value_t = WatermarkPositionArg::BottomRight)]
+    watermark_position: WatermarkPositionArg,
+    /// Watermark opacity (0.0-1.0)
+    #[arg(
+        long,
+        env = "IMGC_WATERMARK_OPACITY",
+        value_name = "0.0-1.0",
+        default_value_t = 1.0
+    )]
+    watermark_opacity: f32,
+    /// Watermark distance from the anchored edges, in pixels
+    #[arg(
+        long,
+        env = "IMGC_WATERMARK_MARGIN",
+        value_name = "PX",
+        default_value_t = 16
+    )]
+    watermark_margin: u32,
+    /// Box-filter large reductions (>4x) before the final Lanczos pass (faster thumbnails)
+    #[arg(long, env = "IMGC_FAST_DOWNSCALE", default_value_t = false)]
+    fast_downscale: bool,
+    /// Resize algorithm: resize (Lanczos), thumbnail (much faster, lower quality)
+    /// or auto (area averaging for 3x+ reductions, Lanczos otherwise)
+    #[arg(long, env = "IMGC_RESIZE_ALGO", value_enum, default_value_t = ResizeAlgorithmArg::Resize)]
+    resize_algo: ResizeAlgorithmArg,
+    /// Overwrite existing output files
+    #[arg(long, env = "IMGC_OVERWRITE", default_value_t = false)]
+    overwrite: bool,
+    /// Raw encoder option applied best-effort to the output format's encoder
+    /// (repeatable, e.g. --encoder-opt method=6)
+    #[arg(long = "encoder-opt", env = "IMGC_ENCODER_OPT", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    encoder_opts: Vec<(String, String)>,
+    /// Warn when a lossy JPEG/WebP result falls below this PSNR (dB)
+    #[arg(long, env = "IMGC_MIN_PSNR", value_name = "DB")]
+    min_psnr: Option<f64>,
+    /// With --min-psnr, fail files below the threshold instead of writing them
+    #[arg(
+        long,
+        env = "IMGC_STRICT",
+        default_value_t = false,
+        requires = "min_psnr"
+    )]
+    strict: bool,
+    /// Write output only if it is smaller than the input and its SSIM stays at or
+    /// above --min-ssim; otherwise keep the original and write nothing
+    #[arg(long, env = "IMGC_COMPARE_ORIGINAL", default_value_t = false)]
+    compare_original: bool,
+    /// SSIM floor for --compare-original (0-1)
+    #[arg(long, env = "IMGC_MIN_SSIM", value_name = "SSIM", default_value_t = 0.98, value_parser = parse_ssim)]
+    min_ssim: f64,
+    /// Bit-reproducible output across machines (single-threaded AVIF, no PNG tIME)
+    #[arg(long, env = "IMGC_DETERMINISTIC", default_value_t = false)]
+    deterministic: bool,
+    /// Write output directly instead of via a temp file + rename
+    #[arg(long, env = "IMGC_NO_ATOMIC", default_value_t = false)]
+    no_atomic: bool,
+    /// Write atomic-write temp files here instead of next to the output
+    #[arg(long, env = "IMGC_TEMP_DIR", value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+    /// Retry reads and writes that fail with a transient IO error (EINTR,
+    /// EAGAIN, timeouts) up to N times, with backoff
+    #[arg(long, env = "IMGC_IO_RETRIES", value_name = "N", default_value_t = 0)]
+    io_retries: u32,
+    /// JPEG trellis quantization (false switches to mozjpeg's fastest profile)
+    #[arg(long, env = "IMGC_JPEG_TRELLIS", value_name = "BOOL")]
+    jpeg_trellis: Option<bool>,
+    /// JPEG optimized Huffman coding
+    #[arg(long, env = "IMGC_JPEG_OPTIMIZE_CODING", value_name = "BOOL")]
+    jpeg_optimize_coding: Option<bool>,
+    /// PNG optimization level (1-6)
+    #[arg(long, env = "IMGC_PNG_LEVEL", value_parser = clap::value_parser!(u8).range(1..=6))]
+    png_level: Option<u8>,
+    /// Quantize PNG output to at most N colors (2-256)
+    #[arg(
+        long = "colors",
+        env = "IMGC_COLORS",
+        visible_alias = "palette-colors",
+        value_name = "N",
+        value_parser = clap::value_parser!(u16).range(2..=256)
+    )]
+    quantize_colors: Option<u16>,
+    /// pngquant-style lossy PNG: fewest colors reaching quality MIN-MAX (0-100)
+    #[arg(
+        long,
+        env = "IMGC_PNG_LOSSY",
+        value_name = "MIN-MAX",
+        value_parser = parse_quality_range,
+        conflicts_with = "quantize_colors"
+    )]
+    png_lossy: Option<(u8, u8)>,
+    /// Dither when --colors or --png-lossy reduces the palette (default)
+    #[arg(
+        long,
+        env = "IMGC_DITHER",
+        default_value_t = false,
+        overrides_with = "no_dither"
+    )]
+    dither: bool,
+    /// Map to the nearest palette color without dithering
+    #[arg(
+        long,
+        env = "IMGC_NO_DITHER",
+        default_value_t = false,
+        overrides_with = "dither"
+    )]
+    no_dither: bool,
+    /// Let oxipng alter the RGB of fully transparent pixels (lossy for hidden color data)
+    #[arg(long, env = "IMGC_PNG_OPTIMIZE_ALPHA", default_value_t = false)]
+    png_optimize_alpha: bool,
+    /// Write animated GIF/APNG sources as APNG (PNG output), keeping frame delays
+    #[arg(long, env = "IMGC_APNG", default_value_t = false)]
+    apng: bool,
+    /// PNG row filter strategy (default: chosen by --png-level)
+    #[arg(long, env = "IMGC_PNG_FILTER", value_enum)]
+    png_filter: Option<PngFilterArg>,
+    /// AVIF encoding speed (1=slow/best, 10=fast)
+    #[arg(long, env = "IMGC_AVIF_SPEED", value_parser = clap::value_parser!(u8).range(1..=10))]
+    avif_speed: Option<u8>,
+    /// AVIF speed and quality together: fast (8/70), balanced (4/80) or max
+    /// (2/82); --avif-speed and --quality override its halves
+    #[arg(long, env = "IMGC_AVIF_PRESET", value_enum)]
+    avif_preset: Option<AvifPresetArg>,
+    /// AVIF tiles as ROWSxCOLS (e.g. 2x2, at most 64) or "auto" (one per
+    /// 2 megapixels). Also limits the encoder to that many threads
+    #[arg(long, env = "IMGC_AVIF_TILES", value_name = "RxC|auto", value_parser = parse_avif_tiles)]
+    avif_tiles: Option<AvifTiles>,
+    /// Store AVIF color premultiplied by alpha (for compositing pipelines)
+    #[arg(long, env = "IMGC_AVIF_PREMULTIPLIED", default_value_t = false)]
+    avif_premultiplied: bool,
+    /// Bits per channel for WebP/AVIF output (8 or 10; WebP is 8-bit only);
+    /// deeper sources are dithered down
+    #[arg(long, env = "IMGC_OUTPUT_BIT_DEPTH", value_name = "BITS", value_parser = parse_bit_depth)]
+    output_bit_depth: Option<u8>,
+    /// Keep RGB values under fully transparent WebP pixels
+    #[arg(long, env = "IMGC_WEBP_EXACT", default_value_t = false)]
+    webp_exact: bool,
+    /// Sharper RGB to YUV conversion for lossy WebP (slower)
+    #[arg(long, env = "IMGC_WEBP_SHARP_YUV", default_value_t = false)]
+    webp_sharp_yuv: bool,
+    /// Two analysis passes for lossy WebP (slower; AVIF has no multi-pass mode)
+    #[arg(long, env = "IMGC_TWO_PASS", default_value_t = false)]
+    two_pass: bool,
+    /// Alpha plane filtering for lossy WebP
+    #[arg(long, env = "IMGC_WEBP_ALPHA_FILTER", value_enum)]
+    webp_alpha_filter: Option<WebPAlphaFilterArg>,
+    /// Flatten transparency onto this color for JPEG output, and fill
+    /// `--resize-mode pad` padding with it (#RRGGBB)
+    #[arg(long, env = "IMGC_BACKGROUND", value_name = "COLOR", value_parser = parse_color)]
+    background: Option<[u8; 3]>,
+    /// Flatten transparency onto a vertical gradient for JPEG output (#TOP,#BOTTOM)
+    #[arg(
+        long,
+        env = "IMGC_BACKGROUND_GRADIENT",
+        value_name = "TOP,BOTTOM",
+        value_parser = parse_gradient,
+        conflicts_with = "background"
+    )]
+    background_gradient: Option<([u8; 3], [u8; 3])>,
 }
 
 fn main() {
@@ -102,32 +466,33 @@ fn main() {
 
 fn run() -> Result<()> {
     let cli = Cli::parse();
+    let units = SizeUnits::from(cli.units);
 
     match cli.command {
         Commands::Compress {
             input,
             output,
-            quality,
-            lossless,
-            progressive,
-            keep_metadata,
-            resize,
-            resize_mode,
-            overwrite,
-            png_level,
-            avif_speed,
+            dump_rgba,
+            format_string,
+            interactive,
+            verbose,
+            encode,
         } => {
-            let options = build_compress_options(
-                overwrite,
-                quality,
-                lossless,
-                progressive,
-                keep_metadata,
-                resize,
-                resize_mode,
-                png_level,
-                avif_speed,
-            )?;
+            let mut options = build_compress_options(&encode)?;
+            warn_ignored_options(&options, Some(&output));
+            if interactive {
+                if io::stdin().is_terminal() {
+                    options.quality = Some(pick_quality_interactively(
+                        &input, &output, &options, units,
+                    )?);
+                } else {
+                    eprintln!("warning: --interactive ignored, stdin is not a terminal");
+                }
+            }
+
+            if let Some(dump_path) = dump_rgba {
+                dump_rgba8(&input, &dump_path, &options)?;
+            }
 
             let stats = compress_image_file(&input, &output, &options).with_context(|| {
                 format!(
@@ -136,62 +501,492 @@ fn run() -> Result<()> {
                     output.display()
                 )
             })?;
+            if verbose {
+                eprintln!("{}", stats.timings);
+            }
 
-            let input_name = input.file_name().and_then(|n| n.to_str()).unwrap_or("?");
-            let output_name = output.file_name().and_then(|n| n.to_str()).unwrap_or("?");
+            if let Some(template) = format_string {
+                println!(
+                    "{}",
+                    render_format_string(&template, |token| {
+                        format_token(token, &input, &output, &stats)
+                    })?
+                );
+                return Ok(());
+            }
+
+            let input_name = input.file_name().unwrap_or_default().to_string_lossy();
+            let output_name = output.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(reason) = stats.kept_original {
+                println!(
+                    "kept original {} ({reason}: {} \u{2192} {}, SSIM {:.4}), nothing written",
+                    input_name,
+                    units.format(stats.original_bytes),
+                    units.format(stats.compressed_bytes),
+                    stats.ssim.unwrap_or(1.0),
+                );
+                return Ok(());
+            }
             println!(
                 "compressed {} \u{2192} {} ({} \u{2192} {}, saved {:.1}%)",
                 input_name,
                 output_name,
-                format_size(stats.original_bytes),
-                format_size(stats.compressed_bytes),
+                units.format(stats.original_bytes),
+                units.format(stats.compressed_bytes),
                 stats.savings_percent,
             );
         }
         Commands::Batch {
             input_dir,
             output_dir,
+            #[cfg(feature = "zip")]
+            output_zip,
             to,
+            follow_input_format,
             recursive,
-            quality,
-            lossless,
-            progressive,
-            keep_metadata,
-            resize,
-            resize_mode,
-            overwrite,
-            png_level,
-            avif_speed,
+            resume,
+            keep_structure_depth,
+            limit,
+            name_template,
+            preserve_input_extension_in_name,
+            per_format_subdir,
+            skip_optimized,
+            overwrite_newer_only,
+            skip_identical,
+            mark_optimized,
+            force,
+            manifest,
+            copy_non_images,
+            dry_run,
+            estimate,
+            histogram,
+            verbose,
+            events,
+            total_budget,
+            quality_map,
+            rules,
+            since,
+            encode,
         } => {
-            let options = build_compress_options(
-                overwrite,
-                quality,
-                lossless,
-                progressive,
-                keep_metadata,
-                resize,
-                resize_mode,
-                png_level,
-                avif_speed,
-            )?;
-
-            let report = compress_directory(&input_dir, &output_dir, &to, &options, recursive)
+            let mut options = build_compress_options(&encode)?;
+            options.mark_output = mark_optimized;
+            // With per-file formats there is no single target to check against
+            if !follow_input_format && rules.is_none() {
+                warn_ignored_options(&options, to.as_deref().map(Path::new));
+            }
+            let to = to.unwrap_or_else(|| "png".to_string());
+
+            let batch = BatchOptions {
+                recursive,
+                resume,
+                size_units: units,
+                verbose,
+                follow_input_format,
+                keep_structure_depth,
+                total_budget,
+                quality_map: match quality_map {
+                    Some(path) => {
+                        let text = fs::read_to_string(&path).with_context(|| {
+                            format!("failed to read quality map: {}", path.display())
+                        })?;
+                        parse_quality_map(&text)
+                            .with_context(|| format!("in quality map {}", path.display()))?
+                    }
+                    None => BTreeMap::new(),
+                },
+                rules: match rules {
+                    Some(path) => {
+                        let text = fs::read_to_string(&path).with_context(|| {
+                            format!("failed to read rules file: {}", path.display())
+                        })?;
+                        parse_batch_rules(&text)
+                            .with_context(|| format!("in rules file {}", path.display()))?
+                    }
+                    None => Vec::new(),
+                },
+                modified_since: since,
+                skip_optimized,
+                overwrite_newer_only,
+                skip_identical,
+                skip_marked: mark_optimized && !force,
+                manifest,
+                copy_non_images,
+                preserve_input_extension: preserve_input_extension_in_name,
+                per_format_subdir,
+                limit,
+                name_template: name_template
+                    .map(|template| NameTemplate::new(&template, SystemTime::now()))
+                    .transpose()
+                    .context("invalid --name-template")?,
+            };
+
+            #[cfg(feature = "zip")]
+            if let Some(archive) = output_zip {
+                let report = image_compressor_rs::compress_directory_to_zip(
+                    &input_dir, &archive, &to, &options, &batch,
+                )
                 .with_context(|| {
                     format!(
                         "failed batch compression from {} to {}",
                         input_dir.display(),
-                        output_dir.display()
+                        archive.display()
                     )
                 })?;
+                print_batch_summary(&report, units);
+                if histogram {
+                    print_savings_histogram(&report);
+                }
+                return Ok(());
+            }
 
-            print_batch_summary(&report);
+            let output_dir = output_dir.context("output directory is required")?;
+            if dry_run {
+                let plan = plan_batch(&input_dir, &output_dir, &to, &options, &batch)?;
+                print_batch_plan(&input_dir, &plan);
+                return Ok(());
+            }
+            if estimate {
+                let report = estimate_batch(&input_dir, &output_dir, &to, &options, &batch)?;
+                print_batch_estimate(&report, units);
+                if histogram {
+                    print_savings_histogram(&report);
+                }
+                return Ok(());
+            }
+
+            let on_event = |event: BatchEvent<'_>| {
+                if events {
+                    emit_event(batch_event_json(&event));
+                }
+            };
+            let report = compress_directory_with_progress(
+                &input_dir,
+                &output_dir,
+                &to,
+                &options,
+                &batch,
+                on_event,
+            )
+            .with_context(|| {
+                format!(
+                    "failed batch compression from {} to {}",
+                    input_dir.display(),
+                    output_dir.display()
+                )
+            })?;
+
+            if events {
+                emit_event(json!({
+                    "event": "batch_done",
+                    "compressed": report.compressed,
+                    "skipped": report.skipped,
+                    "skipped_unchanged": report.skipped_unchanged,
+                    "skipped_optimized": report.skipped_optimized,
+                    "skipped_uptodate": report.skipped_uptodate,
+                    "skipped_identical": report.skipped_identical,
+                    "skipped_already_optimized": report.skipped_already_optimized,
+                    "copied": report.copied,
+                    "failed": report.failed,
+                    "original_bytes": report.total_original_bytes,
+                    "compressed_bytes": report.total_compressed_bytes,
+                    "elapsed_secs": report.elapsed.as_secs_f64(),
+                    "files_per_sec": report.files_per_sec(),
+                    "mb_per_sec": report.mb_per_sec(),
+                }));
+            }
+            print_batch_summary(&report, units);
+            if histogram {
+                print_savings_histogram(&report);
+            }
+        }
+        #[cfg(feature = "zip")]
+        Commands::BatchZip {
+            archive,
+            output_dir,
+            to,
+            encode,
+        } => {
+            let options = build_compress_options(&encode)?;
+            warn_ignored_options(&options, Some(Path::new(&to)));
+
+            let report = image_compressor_rs::compress_zip_archive(
+                &archive,
+                &output_dir,
+                &to,
+                &options,
+                units,
+            )
+            .with_context(|| {
+                format!(
+                    "failed batch compression from {} to {}",
+                    archive.display(),
+                    output_dir.display()
+                )
+            })?;
+
+            print_batch_summary(&report, units);
+        }
+        Commands::Info { input, color_count } => {
+            let info = if color_count {
+                probe_image_colors(&input)
+            } else {
+                probe_image(&input)
+            }
+            .with_context(|| format!("failed to probe {}", input.display()))?;
+            print_image_info(&info, color_count, units);
+        }
+        Commands::Capabilities => print_capabilities(),
+        Commands::Schema => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&compress_options_schema())?
+            );
         }
     }
 
     Ok(())
 }
 
-fn print_batch_summary(report: &BatchReport) {
+fn batch_event_json(event: &BatchEvent<'_>) -> Value {
+    match event {
+        BatchEvent::Started { source, target } => json!({
+            "event": "file_start",
+            "path": source.display().to_string(),
+            "output": target.display().to_string(),
+        }),
+        BatchEvent::Done {
+            source,
+            target,
+            stats,
+        } => json!({
+            "event": "file_done",
+            "path": source.display().to_string(),
+            "output": target.display().to_string(),
+            "stats": {
+                "original_bytes": stats.original_bytes,
+                "compressed_bytes": stats.compressed_bytes,
+                "savings_percent": stats.savings_percent,
+                "metadata_bytes_removed": stats.metadata_bytes_removed,
+                "metadata_stripped": stats.metadata_stripped,
+                "ssim": stats.ssim,
+                "adaptive_quality": stats.adaptive_quality,
+                "kept_original": stats.kept_original.map(|reason| match reason {
+                    KeptOriginal::NotSmaller => "not_smaller",
+                    KeptOriginal::BelowSsimFloor => "below_ssim_floor",
+                }),
+            },
+        }),
+        BatchEvent::Skipped { source, target } => json!({
+            "event": "file_skipped",
+            "path": source.display().to_string(),
+            "output": target.display().to_string(),
+        }),
+        BatchEvent::Copied {
+            source,
+            target,
+            bytes,
+        } => json!({
+            "event": "file_copied",
+            "path": source.display().to_string(),
+            "output": target.display().to_string(),
+            "bytes": bytes,
+        }),
+        BatchEvent::Failed { source, error } => json!({
+            "event": "file_failed",
+            "path": source.display().to_string(),
+            "error": error,
+        }),
+    }
+}
+
+/// Write one NDJSON event line to stderr, stamped like the plugin's log lines
+fn emit_event(mut event: Value) {
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    event["ts"] = json!(ts);
+    eprintln!("{event}");
+}
+
+fn print_batch_plan(input_dir: &Path, plan: &[PlannedFile]) {
+    for entry in plan {
+        let source = entry
+            .source
+            .strip_prefix(input_dir)
+            .unwrap_or(&entry.source);
+        let action = if entry.skip {
+            "would skip"
+        } else if entry.copy {
+            "would copy"
+        } else {
+            "would compress"
+        };
+        let format = entry
+            .detected_format
+            .and_then(|format| format.extensions_str().first())
+            .unwrap_or(&"unrecognized");
+        println!(
+            "{action} {} \u{2192} {} ({format})",
+            source.display(),
+            entry.target.display()
+        );
+    }
+
+    let skipped = plan.iter().filter(|entry| entry.skip).count();
+    let copied = plan
+        .iter()
+        .filter(|entry| entry.copy && !entry.skip)
+        .count();
+    if copied > 0 {
+        println!(
+            "dry run: {} to compress, {copied} to copy, {skipped} to skip",
+            plan.len() - skipped - copied
+        );
+    } else {
+        println!(
+            "dry run: {} to compress, {} to skip",
+            plan.len() - skipped,
+            skipped
+        );
+    }
+}
+
+/// Warn about flags the target format's encoder ignores. `target` is an
+/// output path or a bare format name; unknown formats are left to fail later.
+fn warn_ignored_options(options: &CompressOptions, target: Option<&Path>) {
+    let Some(format) = target
+        .and_then(|target| target.extension().or(Some(target.as_os_str())))
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| OutputFormat::from_extension(ext).ok())
+    else {
+        return;
+    };
+    for name in ignored_options(options, format) {
+        let flag = match name {
+            "avif_premultiplied_alpha" => "avif-premultiplied".to_string(),
+            "quantize_colors" => "colors".to_string(),
+            "png_lossy_quality" => "png-lossy".to_string(),
+            _ => name.replace('_', "-"),
+        };
+        let reason = if name == "quality" && options.lossless {
+            "is ignored with --lossless".to_string()
+        } else {
+            format!("has no effect for {format} output")
+        };
+        eprintln!("warning: --{flag} {reason}");
+    }
+}
+
+/// Qualities offered by `compress --interactive`
+const INTERACTIVE_QUALITIES: [u8; 4] = [60, 75, 85, 95];
+
+/// Encode in memory at each of [`INTERACTIVE_QUALITIES`], list the sizes on
+/// stderr and read the choice from stdin
+fn pick_quality_interactively(
+    input: &Path,
+    output: &Path,
+    options: &CompressOptions,
+    units: SizeUnits,
+) -> Result<u8> {
+    let format = output
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .context("output path must include a file extension")
+        .and_then(OutputFormat::from_extension)?;
+    if format == OutputFormat::Png || options.lossless {
+        bail!("--interactive needs lossy output (JPEG, WebP or AVIF)");
+    }
+    let input_bytes =
+        fs::read(input).with_context(|| format!("failed to read input: {}", input.display()))?;
+
+    eprintln!(
+        "{} ({})",
+        input.display(),
+        units.format(input_bytes.len() as u64)
+    );
+    for (index, quality) in INTERACTIVE_QUALITIES.into_iter().enumerate() {
+        let candidate = CompressOptions {
+            quality: Some(quality),
+            ..options.clone()
+        };
+        let size = compress_bytes(&input_bytes, format, &candidate)?.len() as u64;
+        eprintln!(
+            "  [{}] quality {quality:>3}  {}  (saved {:.1}%)",
+            index + 1,
+            units.format(size),
+            (1.0 - size as f64 / input_bytes.len() as f64) * 100.0,
+        );
+    }
+
+    let mut line = String::new();
+    loop {
+        eprint!(
+            "write which? [1-{}, q to cancel] ",
+            INTERACTIVE_QUALITIES.len()
+        );
+        io::stderr().flush()?;
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            bail!("no quality chosen");
+        }
+        match line.trim() {
+            "q" => bail!("cancelled, nothing written"),
+            choice => {
+                if let Ok(index) = choice.parse::<usize>()
+                    && let Some(&quality) = INTERACTIVE_QUALITIES.get(index.wrapping_sub(1))
+                {
+                    return Ok(quality);
+                }
+            }
+        }
+    }
+}
+
+fn dump_rgba8(input: &Path, dump_path: &Path, options: &CompressOptions) -> Result<()> {
+    let rgba = decode_to_rgba8(input, options)?;
+    let mut bytes = Vec::with_capacity(8 + rgba.as_raw().len());
+    bytes.extend_from_slice(&rgba.width().to_le_bytes());
+    bytes.extend_from_slice(&rgba.height().to_le_bytes());
+    bytes.extend_from_slice(rgba.as_raw());
+
+    fs::write(dump_path, bytes)
+        .with_context(|| format!("failed to write RGBA dump: {}", dump_path.display()))
+}
+
+fn print_image_info(info: &ImageInfo, color_count: bool, units: SizeUnits) {
+    println!("format: {}", info.format);
+    println!("dimensions: {}x{}", info.width, info.height);
+    println!("file size: {}", units.format(info.file_bytes));
+    println!(
+        "color: {}{}",
+        info.color,
+        if info.has_alpha { " (alpha)" } else { "" }
+    );
+    println!(
+        "metadata: EXIF {}, XMP {}, ICC {}",
+        units.format(info.exif_bytes),
+        units.format(info.xmp_bytes),
+        units.format(info.icc_bytes)
+    );
+    if color_count {
+        match info.unique_colors {
+            Some(colors) => println!("unique colors: {colors}"),
+            None => println!("unique colors: many (over {UNIQUE_COLORS_LIMIT})"),
+        }
+    }
+}
+
+fn print_capabilities() {
+    let formats = supported_formats();
+    let output: Vec<String> = formats.output.iter().map(ToString::to_string).collect();
+
+    println!("image-compressor-rs {}", env!("CARGO_PKG_VERSION"));
+    println!("input:  {}", formats.input.join(", "));
+    println!("output: {}", output.join(", "));
+}
+
+fn print_batch_summary(report: &BatchReport, units: SizeUnits) {
     let total_saved = report
         .total_original_bytes
         .saturating_sub(report.total_compressed_bytes);
@@ -206,21 +1001,194 @@ fn print_batch_summary(report: &BatchReport) {
         report.compressed,
         report.failed,
         report.skipped,
-        format_size(total_saved),
+        units.format(total_saved),
+        savings_percent,
+    );
+
+    if report.compressed > 0 {
+        let bytes_per_sec = (report.mb_per_sec() * 1e6).round() as u64;
+        println!(
+            "throughput: {:.1} files/s, {}/s of input ({:.2}s elapsed)",
+            report.files_per_sec(),
+            units.format(bytes_per_sec),
+            report.elapsed.as_secs_f64(),
+        );
+    }
+
+    if report.total_metadata_bytes_removed > 0 {
+        println!(
+            "removed {} of metadata across {} files",
+            units.format(report.total_metadata_bytes_removed),
+            report.files_with_metadata_removed,
+        );
+    }
+
+    if report.skipped_unchanged > 0 {
+        println!(
+            "skipped {} files not modified since --since",
+            report.skipped_unchanged
+        );
+    }
+
+    if report.copied > 0 {
+        println!("copied {} non-image files", report.copied);
+    }
+
+    if report.skipped_optimized > 0 {
+        println!(
+            "skipped {} files already smaller than a re-encode (--skip-optimized)",
+            report.skipped_optimized
+        );
+    }
+
+    if report.skipped_uptodate > 0 {
+        println!(
+            "skipped {} up-to-date outputs (--overwrite-newer-only)",
+            report.skipped_uptodate
+        );
+    }
+
+    if report.skipped_identical > 0 {
+        println!(
+            "left {} outputs untouched: new encode identical (--skip-identical)",
+            report.skipped_identical
+        );
+    }
+
+    if report.skipped_already_optimized > 0 {
+        println!(
+            "skipped {} files marked by an earlier run at these settings (--mark-optimized)",
+            report.skipped_already_optimized
+        );
+    }
+
+    if report.not_processed > 0 {
+        println!(
+            "stopped at --total-budget: {} files not processed",
+            report.not_processed
+        );
+    }
+
+    if report.kept_original > 0 {
+        println!(
+            "kept {} originals: output not smaller or below --min-ssim",
+            report.kept_original
+        );
+    }
+
+    if report.quality_flagged > 0 {
+        println!(
+            "warning: {} files fell below the --min-psnr threshold",
+            report.quality_flagged
+        );
+    }
+
+    print_batch_breakdown("by format", &report.by_format, units);
+    print_batch_breakdown("by directory", &report.by_directory, units);
+}
+
+/// Summary of [`estimate_batch`], labelled so it can't be mistaken for a run
+fn print_batch_estimate(report: &BatchReport, units: SizeUnits) {
+    let saved = report
+        .total_original_bytes
+        .saturating_sub(report.total_compressed_bytes);
+    let savings_percent = if report.total_original_bytes > 0 {
+        saved as f64 / report.total_original_bytes as f64 * 100.0
+    } else {
+        0.0
+    };
+
+    println!(
+        "ESTIMATE (downscaled proxies, nothing written): {} files, {} \u{2192} ~{}, saving ~{} ({:.1}%)",
+        report.compressed,
+        units.format(report.total_original_bytes),
+        units.format(report.total_compressed_bytes),
+        units.format(saved),
         savings_percent,
     );
+    println!(
+        "would skip {}, copy {}; could not estimate {} ({:.2}s elapsed)",
+        report.skipped,
+        report.copied,
+        report.failed,
+        report.elapsed.as_secs_f64(),
+    );
+    if report.not_processed > 0 {
+        println!(
+            "--total-budget would stop the run with {} files not processed",
+            report.not_processed
+        );
+    }
+
+    print_batch_breakdown("estimated by format", &report.by_format, units);
+    print_batch_breakdown("estimated by directory", &report.by_directory, units);
+}
+
+/// Widest bar in [`print_savings_histogram`], in characters
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Text bar chart of `BatchReport::savings_histogram`, bars scaled to the
+/// fullest bucket
+fn print_savings_histogram(report: &BatchReport) {
+    let fullest = report.savings_histogram.iter().copied().max().unwrap_or(0);
+    if fullest == 0 {
+        return;
+    }
+
+    println!("savings per file:");
+    for (bucket, &files) in report.savings_histogram.iter().enumerate() {
+        let low = bucket * 20;
+        let label = match bucket {
+            0 => "<20%".to_string(),
+            _ if bucket == SAVINGS_BUCKETS - 1 => format!("{low}%+"),
+            _ => format!("{low}-{}%", low + 20),
+        };
+        // Any non-empty bucket gets at least one block so it stays visible
+        let bar = (files * HISTOGRAM_WIDTH).div_ceil(fullest);
+        println!(
+            "  {label:>7} {:<HISTOGRAM_WIDTH$} {files}",
+            "\u{2588}".repeat(bar)
+        );
+    }
+}
+
+/// Only printed when there is more than one group to compare
+fn print_batch_breakdown(title: &str, groups: &BTreeMap<String, BatchTotals>, units: SizeUnits) {
+    if groups.len() < 2 {
+        return;
+    }
+
+    let name_width = groups
+        .keys()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    println!("{title}:");
+    for (name, totals) in groups {
+        println!(
+            "  {:<name_width$}  {:>5} files  {} \u{2192} {}  (saved {:.1}%)",
+            name,
+            totals.files,
+            units.format(totals.original_bytes),
+            units.format(totals.compressed_bytes),
+            totals.savings_percent(),
+        );
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 struct ResizeInput {
-    width: u32,
-    height: u32,
+    width: Option<u32>,
+    height: Option<u32>,
 }
 
 #[derive(Clone, Copy, Debug, ValueEnum)]
 enum ResizeModeArg {
     Fit,
     Exact,
+    FitWidth,
+    FitHeight,
+    Pad,
 }
 
 impl From<ResizeModeArg> for ResizeMode {
@@ -228,6 +1196,134 @@ impl From<ResizeModeArg> for ResizeMode {
         match value {
             ResizeModeArg::Fit => ResizeMode::Fit,
             ResizeModeArg::Exact => ResizeMode::Exact,
+            ResizeModeArg::FitWidth => ResizeMode::FitWidth,
+            ResizeModeArg::FitHeight => ResizeMode::FitHeight,
+            ResizeModeArg::Pad => ResizeMode::Pad,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ResizeAlgorithmArg {
+    Resize,
+    Thumbnail,
+    Auto,
+}
+
+impl From<ResizeAlgorithmArg> for ResizeAlgorithm {
+    fn from(value: ResizeAlgorithmArg) -> Self {
+        match value {
+            ResizeAlgorithmArg::Resize => ResizeAlgorithm::Resize,
+            ResizeAlgorithmArg::Thumbnail => ResizeAlgorithm::Thumbnail,
+            ResizeAlgorithmArg::Auto => ResizeAlgorithm::Auto,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum AspectRatioActionArg {
+    Warn,
+    Crop,
+}
+
+impl From<AspectRatioActionArg> for AspectRatioAction {
+    fn from(value: AspectRatioActionArg) -> Self {
+        match value {
+            AspectRatioActionArg::Warn => AspectRatioAction::Warn,
+            AspectRatioActionArg::Crop => AspectRatioAction::Crop,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum AvifPresetArg {
+    Fast,
+    Balanced,
+    Max,
+}
+
+impl From<AvifPresetArg> for AvifPreset {
+    fn from(value: AvifPresetArg) -> Self {
+        match value {
+            AvifPresetArg::Fast => AvifPreset::Fast,
+            AvifPresetArg::Balanced => AvifPreset::Balanced,
+            AvifPresetArg::Max => AvifPreset::Max,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum WebPAlphaFilterArg {
+    None,
+    Fast,
+    Best,
+}
+
+impl From<WebPAlphaFilterArg> for WebPAlphaFilter {
+    fn from(value: WebPAlphaFilterArg) -> Self {
+        match value {
+            WebPAlphaFilterArg::None => WebPAlphaFilter::None,
+            WebPAlphaFilterArg::Fast => WebPAlphaFilter::Fast,
+            WebPAlphaFilterArg::Best => WebPAlphaFilter::Best,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum PngFilterArg {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    TryAll,
+}
+
+impl From<PngFilterArg> for PngFilter {
+    fn from(value: PngFilterArg) -> Self {
+        match value {
+            PngFilterArg::None => PngFilter::None,
+            PngFilterArg::Sub => PngFilter::Sub,
+            PngFilterArg::Up => PngFilter::Up,
+            PngFilterArg::Average => PngFilter::Average,
+            PngFilterArg::Paeth => PngFilter::Paeth,
+            PngFilterArg::TryAll => PngFilter::TryAll,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum WatermarkPositionArg {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl From<WatermarkPositionArg> for WatermarkPosition {
+    fn from(value: WatermarkPositionArg) -> Self {
+        match value {
+            WatermarkPositionArg::TopLeft => WatermarkPosition::TopLeft,
+            WatermarkPositionArg::TopRight => WatermarkPosition::TopRight,
+            WatermarkPositionArg::BottomLeft => WatermarkPosition::BottomLeft,
+            WatermarkPositionArg::BottomRight => WatermarkPosition::BottomRight,
+            WatermarkPositionArg::Center => WatermarkPosition::Center,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum UnitsArg {
+    Decimal,
+    Binary,
+}
+
+impl From<UnitsArg> for SizeUnits {
+    fn from(value: UnitsArg) -> Self {
+        match value {
+            UnitsArg::Decimal => SizeUnits::Decimal,
+            UnitsArg::Binary => SizeUnits::Binary,
         }
     }
 }
@@ -238,44 +1334,348 @@ fn parse_resize(value: &str) -> std::result::Result<ResizeInput, String> {
         .split_once('x')
         .ok_or_else(|| "resize must be in WIDTHxHEIGHT format (example: 1920x1080)".to_string())?;
 
-    let width = width
-        .parse::<u32>()
-        .map_err(|_| "resize width must be an integer".to_string())?;
-    let height = height
-        .parse::<u32>()
-        .map_err(|_| "resize height must be an integer".to_string())?;
+    let width = parse_resize_axis(width, "width")?;
+    let height = parse_resize_axis(height, "height")?;
 
-    if width == 0 || height == 0 {
-        return Err("resize width and height must be greater than zero".to_string());
+    if width.is_none() && height.is_none() {
+        return Err("resize needs a width, a height, or both (example: 1920x1080)".to_string());
     }
 
     Ok(ResizeInput { width, height })
 }
 
-#[allow(clippy::too_many_arguments)]
-fn build_compress_options(
-    overwrite: bool,
-    quality: Option<u8>,
-    lossless: bool,
-    progressive: bool,
-    keep_metadata: bool,
-    resize: Option<ResizeInput>,
-    resize_mode: ResizeModeArg,
-    png_level: Option<u8>,
-    avif_speed: Option<u8>,
-) -> Result<CompressOptions> {
-    let resize = resize
-        .map(|value| ResizeOptions::new(value.width, value.height, resize_mode.into()))
+/// An empty axis means "follow the aspect ratio"
+fn parse_resize_axis(value: &str, name: &str) -> std::result::Result<Option<u32>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let parsed = value
+        .parse::<u32>()
+        .map_err(|_| format!("resize {name} must be an integer"))?;
+    if parsed == 0 {
+        return Err("resize width and height must be greater than zero".to_string());
+    }
+
+    Ok(Some(parsed))
+}
+
+fn parse_crop(value: &str) -> std::result::Result<Rect, String> {
+    let invalid =
+        || format!("invalid crop {value:?} (expected X,Y,WIDTHxHEIGHT, e.g. 100,50,800x600)");
+    let normalized = value.trim().to_ascii_lowercase();
+    let mut parts = normalized.split(',').map(str::trim);
+    let (Some(x), Some(y), Some(size), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    let number = |part: &str| part.trim().parse::<u32>().map_err(|_| invalid());
+    let crop = Rect {
+        x: number(x)?,
+        y: number(y)?,
+        width: number(width)?,
+        height: number(height)?,
+    };
+    if crop.width == 0 || crop.height == 0 {
+        return Err("crop width and height must be greater than zero".to_string());
+    }
+    Ok(crop)
+}
+
+fn parse_color(value: &str) -> std::result::Result<[u8; 3], String> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("color must be in #RRGGBB format, got {value:?}"));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.to_string());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Expands `{token}` placeholders, with `{{` and `}}` for literal braces
+fn render_format_string(
+    template: &str,
+    mut token_value: impl FnMut(&str) -> Option<String>,
+) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            rendered.push_str(&tail[..1]);
+            rest = &tail[2..];
+        } else if let Some(tail) = tail.strip_prefix('{')
+            && let Some(end) = tail.find('}')
+        {
+            let token = &tail[..end];
+            let Some(value) = token_value(token) else {
+                bail!("unknown format token {{{token}}}");
+            };
+            rendered.push_str(&value);
+            rest = &tail[end + 1..];
+        } else {
+            bail!("unbalanced brace in format string {template:?}");
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn format_token(
+    token: &str,
+    input: &Path,
+    output: &Path,
+    stats: &CompressionStats,
+) -> Option<String> {
+    Some(match token {
+        "input" => input.display().to_string(),
+        "output" => output.display().to_string(),
+        "width" => stats.width.to_string(),
+        "height" => stats.height.to_string(),
+        "original_bytes" => stats.original_bytes.to_string(),
+        "compressed_bytes" => stats.compressed_bytes.to_string(),
+        "saved_bytes" => (stats.original_bytes as i64 - stats.compressed_bytes as i64).to_string(),
+        "saved_pct" => format!("{:.1}", stats.savings_percent),
+        "metadata_bytes_removed" => stats.metadata_bytes_removed.to_string(),
+        "metadata_stripped" => stats.metadata_stripped.to_string(),
+        "psnr" => stats
+            .psnr
+            .map(|psnr| format!("{psnr:.2}"))
+            .unwrap_or_default(),
+        "ssim" => stats
+            .ssim
+            .map(|ssim| format!("{ssim:.4}"))
+            .unwrap_or_default(),
+        "kept_original" => stats.kept_original.is_some().to_string(),
+        _ => return None,
+    })
+}
+
+/// Checks a `--format-string` template up front so typos fail before any encoding
+fn parse_format_string(value: &str) -> std::result::Result<String, String> {
+    let probe = CompressionStats::default();
+    render_format_string(value, |token| {
+        format_token(token, Path::new(""), Path::new(""), &probe)
+    })
+    .map_err(|err| err.to_string())?;
+    Ok(value.to_string())
+}
+
+/// `MIN-MAX` qualities (0-100); a single `MAX` means `0-MAX` as in pngquant
+fn parse_quality_range(value: &str) -> std::result::Result<(u8, u8), String> {
+    let (min, max) = value.split_once('-').unwrap_or(("0", value));
+    let parse = |part: &str| part.trim().parse::<u8>().ok().filter(|q| *q <= 100);
+    match (parse(min), parse(max)) {
+        (Some(min), Some(max)) if min <= max => Ok((min, max)),
+        _ => Err(format!(
+            "invalid quality range {value:?} (expected MIN-MAX, 0 <= MIN <= MAX <= 100)"
+        )),
+    }
+}
+
+fn parse_ssim(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ssim) if (0.0..=1.0).contains(&ssim) => Ok(ssim),
+        _ => Err(format!("invalid SSIM {value:?} (expected 0.0 to 1.0)")),
+    }
+}
+
+fn parse_aspect_ratio(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio.is_finite() && ratio >= 1.0 => Ok(ratio),
+        _ => Err(format!(
+            "invalid aspect ratio {value:?} (expected a number of at least 1)"
+        )),
+    }
+}
+
+fn parse_bit_depth(value: &str) -> std::result::Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(bits @ (8 | 10)) => Ok(bits),
+        _ => Err(format!("invalid bit depth {value:?} (expected 8 or 10)")),
+    }
+}
+
+/// Byte count with an optional KB/MB/GB (1000-based) or KiB/MiB/GiB suffix
+fn parse_byte_size(value: &str) -> std::result::Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {value:?} (example: 500MB)"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        other => {
+            return Err(format!(
+                "unknown size unit {other:?} (use KB, MB, GB, KiB, MiB or GiB)"
+            ));
+        }
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+fn parse_key_value(value: &str) -> std::result::Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_ascii_lowercase(), value.trim().to_string()))
+        }
+        _ => Err("encoder option must be in KEY=VALUE format (example: method=6)".to_string()),
+    }
+}
+
+fn parse_avif_tiles(value: &str) -> std::result::Result<AvifTiles, String> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(AvifTiles::Auto);
+    }
+    let grid = value.split_once(['x', 'X']).and_then(|(rows, cols)| {
+        let rows = rows.trim().parse::<u8>().ok()?;
+        let cols = cols.trim().parse::<u8>().ok()?;
+        (rows > 0 && cols > 0 && u16::from(rows) * u16::from(cols) <= 64)
+            .then_some(AvifTiles::Grid { rows, cols })
+    });
+    grid.ok_or_else(|| {
+        format!("invalid AVIF tiles {value:?} (expected ROWSxCOLS with at most 64 tiles, or auto)")
+    })
+}
+
+fn parse_gradient(value: &str) -> std::result::Result<([u8; 3], [u8; 3]), String> {
+    let (top, bottom) = value.split_once(',').ok_or_else(|| {
+        "gradient must be in #TOP,#BOTTOM format (example: #ffffff,#d0d8e0)".to_string()
+    })?;
+    Ok((parse_color(top)?, parse_color(bottom)?))
+}
+
+fn build_compress_options(args: &EncodeArgs) -> Result<CompressOptions> {
+    if args.adaptive_quality && args.quality_min > args.quality_max {
+        bail!(
+            "--quality-min {} is above --quality-max {}",
+            args.quality_min,
+            args.quality_max
+        );
+    }
+    let resize = args
+        .resize
+        .map(|value| {
+            // A single axis with the default mode means "fit that axis"
+            let mode = match (args.resize_mode, value.width, value.height) {
+                (ResizeModeArg::Fit, Some(_), None) => ResizeMode::FitWidth,
+                (ResizeModeArg::Fit, None, Some(_)) => ResizeMode::FitHeight,
+                (mode, _, _) => mode.into(),
+            };
+            ResizeOptions::new(value.width.unwrap_or(0), value.height.unwrap_or(0), mode)
+        })
+        .transpose()?;
+
+    let watermark = args
+        .watermark
+        .as_deref()
+        .map(|path| {
+            WatermarkOptions::load(
+                path,
+                args.watermark_position.into(),
+                args.watermark_opacity,
+                args.watermark_margin,
+            )
+        })
         .transpose()?;
 
     Ok(CompressOptions {
-        overwrite,
-        quality,
-        lossless,
-        progressive,
-        strip_metadata: !keep_metadata,
+        overwrite: args.overwrite,
+        atomic_write: !args.no_atomic,
+        temp_dir: args.temp_dir.clone(),
+        io_retries: args.io_retries,
+        deterministic: args.deterministic,
+        min_psnr: args.min_psnr,
+        quality_guard_strict: args.strict,
+        compare_original: args.compare_original.then_some(args.min_ssim),
+        encoder_opts: args.encoder_opts.iter().cloned().collect(),
+        quality: args.quality,
+        adaptive_quality: args
+            .adaptive_quality
+            .then_some((args.quality_min, args.quality_max)),
+        lossless: args.lossless,
+        auto_lossless: args.auto_lossless,
+        progressive: args.progressive,
+        jpeg_trellis: args.jpeg_trellis,
+        jpeg_optimize_coding: args.jpeg_optimize_coding,
+        strip_metadata: !args.keep_metadata,
+        strip_exif_thumbnail: args.strip_exif_thumbnail,
+        xmp_sidecar: args.xmp_sidecar,
+        assume_srgb: args.assume_srgb,
+        crop: args.crop,
         resize,
-        png_level,
-        avif_speed,
+        max_dimension: args.max_dimension,
+        max_aspect_ratio: args.max_aspect_ratio,
+        aspect_ratio_action: args.aspect_ratio_action.into(),
+        fast_downscale: args.fast_downscale,
+        resize_algorithm: args.resize_algo.into(),
+        watermark,
+        trim: (args.trim || args.trim_color.is_some()).then_some(TrimOptions {
+            color: args.trim_color,
+            tolerance: args.trim_tolerance,
+        }),
+        png_level: args.png_level,
+        png_optimize_alpha: args.png_optimize_alpha,
+        apng: args.apng,
+        // Batch-only: set from --mark-optimized
+        mark_output: false,
+        png_filter: args.png_filter.map(Into::into),
+        quantize_colors: args.quantize_colors,
+        png_lossy_quality: args.png_lossy,
+        dither: !args.no_dither,
+        avif_speed: args.avif_speed,
+        avif_preset: args.avif_preset.map(Into::into),
+        avif_premultiplied_alpha: args.avif_premultiplied,
+        avif_tiles: args.avif_tiles,
+        output_bit_depth: args.output_bit_depth,
+        webp_exact: args.webp_exact,
+        webp_sharp_yuv: args.webp_sharp_yuv,
+        two_pass: args.two_pass,
+        webp_alpha_filter: args.webp_alpha_filter.map(Into::into),
+        background: match (args.background, args.background_gradient) {
+            (Some(color), _) => Some(Background::Solid(color)),
+            (None, Some((top, bottom))) => Some(Background::VerticalGradient { top, bottom }),
+            (None, None) => None,
+        },
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> Result<String> {
+        render_format_string(template, |token| (token == "a").then(|| "1".to_string()))
+    }
+
+    #[test]
+    fn doubled_braces_render_literally() {
+        assert_eq!(render("{{a}} = {a}").unwrap(), "{a} = 1");
+        assert_eq!(render("}}{{").unwrap(), "}{");
+    }
+
+    #[test]
+    fn unknown_format_token_is_an_error() {
+        let err = render("{a} {b}").unwrap_err().to_string();
+        assert!(err.contains("unknown format token {b}"), "{err}");
+    }
+
+    #[test]
+    fn unbalanced_braces_are_an_error() {
+        for template in ["{a", "x {", "a}b"] {
+            let err = render(template).unwrap_err().to_string();
+            assert!(err.contains("unbalanced brace"), "{template}: {err}");
+        }
+    }
+}
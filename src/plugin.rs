@@ -4,12 +4,12 @@
 /// image-compressor-rs library, and writes JSON-RPC responses to stdout.
 /// All diagnostic output goes to stderr.
 use image_compressor_rs::{
-    CompressOptions, ResizeMode, ResizeOptions, compress_directory, compress_image_file,
-    format_size,
+    CompressOptions, CompressionStats, OutputFormat, ResizeMode, ResizeOptions, compress_directory,
+    compress_image_file, compress_options_schema, format_size, probe_image, probe_image_colors,
 };
 use serde_json::{Value, json};
 use std::io::{self, BufRead, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const NAME: &str = "image-compressor";
 const VERSION: &str = "0.1.0";
@@ -86,6 +86,11 @@ fn main() {
 // ---------------------------------------------------------------------------
 
 fn tool_definitions() -> Value {
+    // `CompressOptions` properties from the library's schema, so the tool
+    // schemas can't drift from the options they set
+    let options = compress_options_schema();
+    let option_schema = |name: &str| options["properties"][name].clone();
+
     json!([
         {
             "name": "compress_image",
@@ -102,12 +107,7 @@ fn tool_definitions() -> Value {
                         "type": "string",
                         "description": "Path for the compressed output (format inferred from extension). Defaults to input path with format extension."
                     },
-                    "quality": {
-                        "type": "integer",
-                        "description": "Compression quality 1-100 (default: format-specific — JPEG 85, WebP 85, AVIF 80)",
-                        "minimum": 1,
-                        "maximum": 100
-                    },
+                    "quality": option_schema("quality"),
                     "format": {
                         "type": "string",
                         "enum": ["jpeg", "png", "webp", "avif"],
@@ -123,9 +123,38 @@ fn tool_definitions() -> Value {
                         "description": "Maximum height in pixels (maintains aspect ratio)",
                         "minimum": 1
                     },
-                    "lossless": {
-                        "type": "boolean",
-                        "description": "Use lossless compression (WebP and AVIF only, default: false)"
+                    "lossless": option_schema("lossless")
+                }
+            }
+        },
+        {
+            "name": "compress_images",
+            "description": "Compress many single images in one call; each item is processed like compress_image and gets its own result, so one failure doesn't stop the rest",
+            "inputSchema": {
+                "type": "object",
+                "required": ["items"],
+                "properties": {
+                    "items": {
+                        "type": "array",
+                        "description": "Images to compress, in order",
+                        "items": {
+                            "type": "object",
+                            "required": ["input_path"],
+                            "properties": {
+                                "input_path": {
+                                    "type": "string",
+                                    "description": "Path to the source image file"
+                                },
+                                "output_path": {
+                                    "type": "string",
+                                    "description": "Path for the compressed output (as in compress_image)"
+                                },
+                                "options": {
+                                    "type": "object",
+                                    "description": "Any of compress_image's quality, format, max_width, max_height and lossless"
+                                }
+                            }
+                        }
                     }
                 }
             }
@@ -143,14 +172,13 @@ fn tool_definitions() -> Value {
                     },
                     "output_dir": {
                         "type": "string",
-                        "description": "Path for compressed output (defaults to input_dir + '_compressed')"
+                        "description": "Path for compressed output (defaults to a sibling of input_dir named with a '_compressed' suffix)"
                     },
-                    "quality": {
-                        "type": "integer",
-                        "description": "Compression quality 1-100 (default: 80)",
-                        "minimum": 1,
-                        "maximum": 100
+                    "recursive": {
+                        "type": "boolean",
+                        "description": "Include subdirectories (default: true)"
                     },
+                    "quality": option_schema("quality"),
                     "format": {
                         "type": "string",
                         "enum": ["jpeg", "png", "webp", "avif"],
@@ -158,6 +186,24 @@ fn tool_definitions() -> Value {
                     }
                 }
             }
+        },
+        {
+            "name": "probe_image",
+            "description": "Read an image's format, dimensions, color type and metadata sizes without compressing it",
+            "inputSchema": {
+                "type": "object",
+                "required": ["input_path"],
+                "properties": {
+                    "input_path": {
+                        "type": "string",
+                        "description": "Path to the image file"
+                    },
+                    "count_colors": {
+                        "type": "boolean",
+                        "description": "Also count distinct colors, reported as \"many\" past 100000 (decodes the image; default: false)"
+                    }
+                }
+            }
         }
     ])
 }
@@ -178,39 +224,98 @@ fn handle_tool_call(id: &Value, params: &Value) -> Value {
 
     match tool_name {
         "compress_image" => call_compress_image(id, &args),
+        "compress_images" => call_compress_images(id, &args),
         "compress_directory" => call_compress_directory(id, &args),
+        "probe_image" => call_probe_image(id, &args),
         _ => err(id, -32601, &format!("Unknown tool: {tool_name}")),
     }
 }
 
 fn call_compress_image(id: &Value, args: &Value) -> Value {
+    match compress_one(args) {
+        Ok((input_path, final_output, stats)) => {
+            let text = format!(
+                "Compressed {} -> {} ({} -> {}, saved {:.1}%)",
+                input_path,
+                final_output.display(),
+                format_size(stats.original_bytes),
+                format_size(stats.compressed_bytes),
+                stats.savings_percent,
+            );
+            ok(id, json!({
+                "content": [{ "type": "text", "text": text }]
+            }))
+        }
+        Err((code, message)) => err(id, code, &message),
+    }
+}
+
+/// Run each item through `compress_one` and answer with a JSON array of
+/// per-item results (stats, or the error) in item order
+fn call_compress_images(id: &Value, args: &Value) -> Value {
+    let Some(items) = args.get("items").and_then(Value::as_array) else {
+        return err(id, -32602, "Missing required parameter: items (an array)");
+    };
+
+    log("info", &format!("compress_images: {} items", items.len()));
+
+    let results: Vec<Value> = items
+        .iter()
+        .map(|item| {
+            // An item's options sit beside its paths in compress_image's arguments
+            let mut item_args = item.get("options").cloned().unwrap_or_else(|| json!({}));
+            if !item_args.is_object() {
+                return json!({ "input_path": item.get("input_path"), "error": "options must be an object" });
+            }
+            for key in ["input_path", "output_path"] {
+                if let Some(value) = item.get(key) {
+                    item_args[key] = value.clone();
+                }
+            }
+            match compress_one(&item_args) {
+                Ok((input_path, output, stats)) => json!({
+                    "input_path": input_path,
+                    "output_path": output.to_string_lossy(),
+                    "original_bytes": stats.original_bytes,
+                    "compressed_bytes": stats.compressed_bytes,
+                    "savings_percent": stats.savings_percent
+                }),
+                Err((_, message)) => json!({ "input_path": item.get("input_path"), "error": message }),
+            }
+        })
+        .collect();
+
+    ok(id, json!({
+        "content": [{ "type": "text", "text": Value::from(results).to_string() }]
+    }))
+}
+
+/// One compress_image call: returns the input path, the output written and
+/// its stats, or a JSON-RPC error code (-32602 bad arguments, -32000 failed
+/// compression) with its message
+fn compress_one(args: &Value) -> Result<(&str, PathBuf, CompressionStats), (i32, String)> {
     let Some(input_path) = args.get("input_path").and_then(Value::as_str) else {
-        return err(id, -32602, "Missing required parameter: input_path");
+        return Err((-32602, "Missing required parameter: input_path".to_string()));
     };
 
-    let format_ext = args
-        .get("format")
-        .and_then(Value::as_str)
-        .map(|f| match f {
-            "jpeg" => "jpg",
-            other => other,
-        });
+    let format_ext = match args.get("format").and_then(Value::as_str) {
+        Some(f) => match OutputFormat::from_extension(f) {
+            Ok(format) => Some(format.extension()),
+            Err(e) => return Err((-32602, format!("{e}"))),
+        },
+        None => None,
+    };
 
+    // Paths stay `PathBuf`s so no name is re-encoded on the way to the library
     let output_path = match args.get("output_path").and_then(Value::as_str) {
-        Some(p) => p.to_string(),
-        None => {
-            let ext = format_ext.unwrap_or("webp");
-            let p = Path::new(input_path);
-            p.with_extension(ext).to_string_lossy().into_owned()
-        }
+        Some(p) => PathBuf::from(p),
+        None => Path::new(input_path).with_extension(format_ext.unwrap_or("webp")),
     };
 
     // If format is specified, override the output extension
-    let final_output = if let Some(ext) = format_ext {
-        let p = Path::new(&output_path);
-        p.with_extension(ext).to_string_lossy().into_owned()
-    } else {
-        output_path
+    let final_output = match format_ext {
+        Some(ext) => output_path.with_extension(ext),
+        None => output_path,
     };
 
     let max_width = args.get("max_width").and_then(Value::as_u64).map(|v| v as u32);
@@ -218,8 +323,8 @@ fn call_compress_image(id: &Value, args: &Value) -> Value {
 
     let resize = match (max_width, max_height) {
         (Some(w), Some(h)) => ResizeOptions::new(w, h, ResizeMode::Fit).ok(),
-        (Some(w), None) => ResizeOptions::new(w, u32::MAX, ResizeMode::Fit).ok(),
-        (None, Some(h)) => ResizeOptions::new(u32::MAX, h, ResizeMode::Fit).ok(),
+        (Some(w), None) => ResizeOptions::new(w, 0, ResizeMode::FitWidth).ok(),
+        (None, Some(h)) => ResizeOptions::new(0, h, ResizeMode::FitHeight).ok(),
         _ => None,
     };
 
@@ -231,23 +336,11 @@ fn call_compress_image(id: &Value, args: &Value) -> Value {
         ..CompressOptions::default()
     };
 
-    log("info", &format!("compress_image: {input_path} -> {final_output}"));
+    log("info", &format!("compress_image: {input_path} -> {}", final_output.display()));
 
-    match compress_image_file(Path::new(input_path), Path::new(&final_output), &options) {
-        Ok(stats) => {
-            let text = format!(
-                "Compressed {} -> {} ({} -> {}, saved {:.1}%)",
-                input_path,
-                final_output,
-                format_size(stats.original_bytes),
-                format_size(stats.compressed_bytes),
-                stats.savings_percent,
-            );
-            ok(id, json!({
-                "content": [{ "type": "text", "text": text }]
-            }))
-        }
-        Err(e) => err(id, -32000, &format!("Compression failed: {e:#}")),
+    match compress_image_file(Path::new(input_path), &final_output, &options) {
+        Ok(stats) => Ok((input_path, final_output, stats)),
+        Err(e) => Err((-32000, format!("Compression failed: {e:#}"))),
     }
 }
 
@@ -256,20 +349,24 @@ fn call_compress_directory(id: &Value, args: &Value) -> Value {
         return err(id, -32602, "Missing required parameter: input_dir");
     };
 
-    let format_ext = args
+    let format = args
         .get("format")
         .and_then(Value::as_str)
         .unwrap_or("webp");
-    let format_ext = match format_ext {
-        "jpeg" => "jpg",
-        other => other,
+    let format_ext = match OutputFormat::from_extension(format) {
+        Ok(format) => format.extension(),
+        Err(e) => return err(id, -32602, &format!("{e}")),
     };
 
     let output_dir = match args.get("output_dir").and_then(Value::as_str) {
-        Some(p) => p.to_string(),
-        None => format!("{input_dir}_compressed"),
+        Some(p) => PathBuf::from(p),
+        None => match default_output_dir(input_dir) {
+            Some(p) => p,
+            None => return err(id, -32602, &format!("output_dir is required for input_dir {input_dir}")),
+        },
     };
 
+    let recursive = args.get("recursive").and_then(Value::as_bool).unwrap_or(true);
     let quality = args.get("quality").and_then(Value::as_u64).map(|v| v as u8);
 
     let options = CompressOptions {
@@ -278,14 +375,14 @@ fn call_compress_directory(id: &Value, args: &Value) -> Value {
         ..CompressOptions::default()
     };
 
-    log("info", &format!("compress_directory: {input_dir} -> {output_dir} (format: {format_ext})"));
+    log("info", &format!("compress_directory: {input_dir} -> {} (format: {format_ext})", output_dir.display()));
 
     match compress_directory(
         Path::new(input_dir),
-        Path::new(&output_dir),
+        &output_dir,
         format_ext,
         &options,
-        true,
+        recursive,
     ) {
         Ok(report) => {
             let text = format!(
@@ -304,6 +401,53 @@ fn call_compress_directory(id: &Value, args: &Value) -> Value {
     }
 }
 
+fn call_probe_image(id: &Value, args: &Value) -> Value {
+    let Some(input_path) = args.get("input_path").and_then(Value::as_str) else {
+        return err(id, -32602, "Missing required parameter: input_path");
+    };
+
+    let count_colors = args.get("count_colors").and_then(Value::as_bool).unwrap_or(false);
+
+    log("info", &format!("probe_image: {input_path}"));
+
+    let probed = if count_colors {
+        probe_image_colors(Path::new(input_path))
+    } else {
+        probe_image(Path::new(input_path))
+    };
+    match probed {
+        Ok(info) => {
+            let mut details = json!({
+                "format": info.format,
+                "width": info.width,
+                "height": info.height,
+                "file_bytes": info.file_bytes,
+                "color": info.color,
+                "has_alpha": info.has_alpha,
+                "exif_bytes": info.exif_bytes,
+                "xmp_bytes": info.xmp_bytes,
+                "icc_bytes": info.icc_bytes
+            });
+            if count_colors {
+                details["unique_colors"] = info.unique_colors.map_or(json!("many"), |n| json!(n));
+            }
+            ok(id, json!({
+                "content": [{ "type": "text", "text": details.to_string() }]
+            }))
+        }
+        Err(e) => err(id, -32000, &format!("Probe failed: {e:#}")),
+    }
+}
+
+/// `photos` and `photos/` both become `photos_compressed`; `None` when the
+/// path has no final name to suffix (e.g. `.` or `/`)
+fn default_output_dir(input_dir: &str) -> Option<PathBuf> {
+    let input = Path::new(input_dir);
+    let mut name = input.file_name()?.to_os_string();
+    name.push("_compressed");
+    Some(input.with_file_name(name))
+}
+
 // ---------------------------------------------------------------------------
 // JSON-RPC helpers
 // ---------------------------------------------------------------------------